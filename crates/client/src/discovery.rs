@@ -0,0 +1,117 @@
+//! Finding controllers among the USB serial ports, by the VID/PID the firmware enumerates with
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// USB vendor ID of the controller, see the firmware's `usb::setup`
+pub const VID: u16 = 0x16c0;
+/// USB product ID of the controller
+pub const PID: u16 = 0x27dd;
+
+/// A controller's serial port
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Device {
+    /// The tty to open, e.g. `/dev/ttyACM0`
+    pub path: PathBuf,
+    /// USB serial number, None if the device does not report one
+    pub serial: Option<String>,
+}
+
+/// Every connected controller, optionally only the one with serial number `serial`
+///
+/// # Errors
+/// * If sysfs can not be read
+pub fn find(serial: Option<&str>) -> io::Result<Vec<Device>> {
+    scan(Path::new("/sys/class/tty"), Path::new("/dev"), serial)
+}
+
+/// Look through the ttys under `class` for controllers, their device nodes being under `dev`
+fn scan(class: &Path, dev: &Path, serial: Option<&str>) -> io::Result<Vec<Device>> {
+    let mut devices = Vec::new();
+    for entry in std::fs::read_dir(class)? {
+        let entry = entry?;
+        // USB serial ports link to the interface, whose parent holds the device's descriptors
+        let Ok(interface) = entry.path().join("device").canonicalize() else {
+            continue;
+        };
+        let Some(usb_device) = interface.parent() else {
+            continue;
+        };
+        let attribute = |name: &str| {
+            std::fs::read_to_string(usb_device.join(name))
+                .ok()
+                .map(|value| value.trim().to_owned())
+        };
+        let id = |name: &str| attribute(name).and_then(|id| u16::from_str_radix(&id, 16).ok());
+        if id("idVendor") != Some(VID) || id("idProduct") != Some(PID) {
+            continue;
+        }
+
+        let device = Device {
+            path: dev.join(entry.file_name()),
+            serial: attribute("serial"),
+        };
+        if serial.is_none() || device.serial.as_deref() == serial {
+            devices.push(device);
+        }
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    /// A sysfs-like tree with a USB device `name` whose interface has tty `tty`
+    fn usb_tty(root: &Path, name: &str, tty: &str, ids: (&str, &str), serial: &str) {
+        let device = root.join("devices").join(name);
+        fs::create_dir_all(device.join(format!("{name}:1.0"))).unwrap();
+        fs::write(device.join("idVendor"), format!("{}\n", ids.0)).unwrap();
+        fs::write(device.join("idProduct"), format!("{}\n", ids.1)).unwrap();
+        fs::write(device.join("serial"), format!("{serial}\n")).unwrap();
+
+        let class = root.join("class").join(tty);
+        fs::create_dir_all(&class).unwrap();
+        symlink(device.join(format!("{name}:1.0")), class.join("device")).unwrap();
+    }
+
+    #[test]
+    fn finds_controllers_by_id_and_serial() {
+        let root = std::env::temp_dir().join(format!("client-discovery-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        usb_tty(&root, "1-1", "ttyACM1", ("16c0", "27dd"), "FOO");
+        usb_tty(&root, "1-2", "ttyACM0", ("16c0", "27dd"), "BAR");
+        usb_tty(&root, "1-3", "ttyACM2", ("2e8a", "000a"), "FOO");
+        // Not a USB port at all
+        fs::create_dir_all(root.join("class").join("ttyS0")).unwrap();
+
+        let class = root.join("class");
+        let dev = Path::new("/dev");
+        assert_eq!(
+            scan(&class, dev, None).unwrap(),
+            [
+                Device {
+                    path: "/dev/ttyACM0".into(),
+                    serial: Some("BAR".into())
+                },
+                Device {
+                    path: "/dev/ttyACM1".into(),
+                    serial: Some("FOO".into())
+                },
+            ]
+        );
+        assert_eq!(
+            scan(&class, dev, Some("FOO")).unwrap(),
+            [Device {
+                path: "/dev/ttyACM1".into(),
+                serial: Some("FOO".into())
+            }]
+        );
+        assert!(scan(&class, dev, Some("BAZ")).unwrap().is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! Host side of the controller's serial protocol, for Linux tools
+//!
+//! `discovery` finds controllers by their USB IDs, `Client` opens one and exchanges `controller_protocol` messages
+//! with it. Any serial port speaking the protocol can be opened, including the emulator's pseudo-terminal.
+
+pub mod discovery;
+pub mod text;
+
+use controller_protocol::{
+    Command, Config, Curve, FeedResult, FrameAccumulator, Reply, Request, Response, SensorReading,
+    Status, MAX_FRAME, PROTOCOL_VERSION,
+};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub use discovery::Device;
+
+/// How long the controller gets to answer a request
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The serial port failed, usually because the controller was unplugged
+    Io(io::Error),
+    /// No answer within the timeout
+    Timeout,
+    /// The controller refused the request
+    Controller(controller_protocol::Error),
+    /// An answer that does not fit the request, or could not be decoded
+    Unexpected,
+    /// The request does not fit in a frame
+    Encode,
+    /// No controller is connected, or none with the requested serial number
+    NotFound,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "serial port: {e}"),
+            Self::Timeout => write!(f, "the controller did not answer"),
+            Self::Controller(controller_protocol::Error::VersionMismatch { supported }) => write!(
+                f,
+                "the controller speaks protocol version {supported}, this tool {PROTOCOL_VERSION}"
+            ),
+            Self::Controller(e) => write!(f, "the controller refused the request: {e:?}"),
+            Self::Unexpected => write!(f, "the controller sent an unexpected answer"),
+            Self::Encode => write!(f, "request too large"),
+            Self::NotFound => write!(f, "no controller found"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<nix::Error> for Error {
+    fn from(e: nix::Error) -> Self {
+        Self::Io(e.into())
+    }
+}
+
+/// An open connection to a controller that has completed the `Hello` handshake
+pub struct Client {
+    port: File,
+    path: PathBuf,
+    frames: FrameAccumulator,
+    /// Received bytes not yet fed to `frames`
+    pending: Vec<u8>,
+    next_id: u16,
+    timeout: Duration,
+    sensors: u8,
+    outputs: u8,
+}
+
+impl Client {
+    /// Open the controller with serial number `serial`, or the first one found if None
+    ///
+    /// # Errors
+    /// * `Error::NotFound` if there is no such controller
+    /// * As `open`
+    pub fn find(serial: Option<&str>) -> Result<Self, Error> {
+        let device = discovery::find(serial)?
+            .into_iter()
+            .next()
+            .ok_or(Error::NotFound)?;
+        Self::open(&device.path)
+    }
+
+    /// Open the serial port at `path` and check the controller on it speaks our protocol version
+    ///
+    /// # Errors
+    /// * If the port can not be opened or set up
+    /// * If the handshake fails, `Error::Controller` with `VersionMismatch` if the versions differ
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let port = OpenOptions::new().read(true).write(true).open(path)?;
+        // Frames are binary, the tty must not translate or echo anything
+        let mut termios = tcgetattr(&port)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(&port, SetArg::TCSANOW, &termios)?;
+
+        let mut client = Self {
+            port,
+            path: path.to_owned(),
+            frames: FrameAccumulator::new(),
+            pending: Vec::new(),
+            next_id: 1,
+            timeout: DEFAULT_TIMEOUT,
+            sensors: 0,
+            outputs: 0,
+        };
+        match client.request(Command::Hello {
+            version: PROTOCOL_VERSION,
+        })? {
+            Reply::Hello {
+                sensors, outputs, ..
+            } => {
+                client.sensors = sensors;
+                client.outputs = outputs;
+                Ok(client)
+            }
+            _ => Err(Error::Unexpected),
+        }
+    }
+
+    /// The serial port this client was opened on
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of sensors the controller reads
+    pub const fn sensors(&self) -> u8 {
+        self.sensors
+    }
+
+    /// Number of fan outputs the controller drives
+    pub const fn outputs(&self) -> u8 {
+        self.outputs
+    }
+
+    /// Change how long the controller gets to answer each request
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Send `command` without waiting for an answer, returning the request's id
+    ///
+    /// # Errors
+    /// * If the request can not be written
+    pub fn send(&mut self, command: Command) -> Result<u16, Error> {
+        let id = self.next_id;
+        // 0 is the id of answers to malformed requests, never used for a request
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+
+        let mut buf = [0u8; MAX_FRAME];
+        let frame = controller_protocol::encode(&Request { id, command }, &mut buf)
+            .map_err(|_| Error::Encode)?;
+        self.port.write_all(frame)?;
+        Ok(id)
+    }
+
+    /// Send `command` and wait for the controller's answer
+    ///
+    /// Answers to earlier requests that timed out are skipped.
+    ///
+    /// # Errors
+    /// * `Error::Controller` if the controller refused the request
+    /// * `Error::Timeout` if it did not answer in time
+    /// * `Error::Io` if the port failed
+    pub fn request(&mut self, command: Command) -> Result<Reply, Error> {
+        let id = self.send(command)?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let response = self.receive(deadline)?;
+            if response.id == id {
+                return response.result.map_err(Error::Controller);
+            }
+            if response.id == 0 {
+                // The controller could not even decode our request
+                return Err(Error::Controller(
+                    response.result.err().ok_or(Error::Unexpected)?,
+                ));
+            }
+        }
+    }
+
+    /// Wait until `deadline` for the next whole response
+    fn receive(&mut self, deadline: Instant) -> Result<Response, Error> {
+        loop {
+            while !self.pending.is_empty() {
+                let pending = std::mem::take(&mut self.pending);
+                match self.frames.feed::<Response>(&pending) {
+                    FeedResult::Consumed => {}
+                    FeedResult::OverFull(rest) | FeedResult::DeserError(rest) => {
+                        self.pending = rest.to_vec();
+                    }
+                    FeedResult::Success { data, remaining } => {
+                        self.pending = remaining.to_vec();
+                        return Ok(data);
+                    }
+                }
+            }
+
+            let timeout = deadline
+                .checked_duration_since(Instant::now())
+                .ok_or(Error::Timeout)?;
+            let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+            let mut fds = [PollFd::new(self.port.as_fd(), PollFlags::POLLIN)];
+            if poll(&mut fds, timeout)? == 0 {
+                return Err(Error::Timeout);
+            }
+            let mut buf = [0u8; 256];
+            match self.port.read(&mut buf)? {
+                0 => {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the controller went away",
+                    )))
+                }
+                count => self.pending.extend_from_slice(&buf[..count]),
+            }
+        }
+    }
+
+    /// Latest reading of every sensor
+    ///
+    /// # Errors
+    /// * As `request`
+    pub fn read_sensors(&mut self) -> Result<Vec<SensorReading>, Error> {
+        match self.request(Command::ReadSensors)? {
+            Reply::Sensors(readings) => Ok(readings.into_iter().collect()),
+            _ => Err(Error::Unexpected),
+        }
+    }
+
+    /// Duties, RPMs and fault state
+    ///
+    /// # Errors
+    /// * As `request`
+    pub fn status(&mut self) -> Result<Status, Error> {
+        match self.request(Command::GetStatus)? {
+            Reply::Status(status) => Ok(status),
+            _ => Err(Error::Unexpected),
+        }
+    }
+
+    /// The fan curve `output` runs on
+    ///
+    /// # Errors
+    /// * As `request`
+    pub fn curve(&mut self, output: u8) -> Result<Curve, Error> {
+        match self.request(Command::GetCurve { output })? {
+            Reply::Curve(curve) => Ok(curve),
+            _ => Err(Error::Unexpected),
+        }
+    }
+
+    /// Run `output` on `curve` until reset, see `save_config` to keep it
+    ///
+    /// # Errors
+    /// * As `request`
+    pub fn set_curve(&mut self, output: u8, curve: Curve) -> Result<(), Error> {
+        self.ack(Command::SetCurve { output, curve })
+    }
+
+    /// Settings the controller is running with
+    ///
+    /// # Errors
+    /// * As `request`
+    pub fn config(&mut self) -> Result<Config, Error> {
+        match self.request(Command::GetConfig)? {
+            Reply::Config(config) => Ok(config),
+            _ => Err(Error::Unexpected),
+        }
+    }
+
+    /// Store settings in flash, applied from the next reset
+    ///
+    /// # Errors
+    /// * As `request`
+    pub fn set_config(&mut self, config: Config) -> Result<(), Error> {
+        self.ack(Command::SetConfig { config })
+    }
+
+    /// Store the running settings in flash
+    ///
+    /// # Errors
+    /// * As `request`
+    pub fn save_config(&mut self) -> Result<(), Error> {
+        self.ack(Command::SaveConfig)
+    }
+
+    /// Reset the controller into the RP2040 USB bootloader, the connection is gone afterwards
+    ///
+    /// # Errors
+    /// * If the request can not be written
+    pub fn reboot_to_bootloader(mut self) -> Result<(), Error> {
+        self.send(Command::RebootToBootloader)?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    fn ack(&mut self, command: Command) -> Result<(), Error> {
+        match self.request(command)? {
+            Reply::Ack => Ok(()),
+            _ => Err(Error::Unexpected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_protocol::{OutputStatus, MAX_CHANNELS};
+    use nix::pty::openpty;
+
+    /// Answer requests on `port` as a controller with one sensor and output, sending an answer to the previous
+    /// request before each
+    fn controller(mut port: File, requests: usize) -> File {
+        let mut frames = FrameAccumulator::new();
+        let mut buf = [0u8; MAX_FRAME];
+        let mut byte = [0u8];
+        let mut answered = 0;
+        while answered < requests {
+            port.read_exact(&mut byte).unwrap();
+            let FeedResult::Success { data, .. } = frames.feed::<Request>(&byte) else {
+                continue;
+            };
+            let Request { id, command } = data;
+            let result = match command {
+                Command::Hello { .. } => Ok(Reply::Hello {
+                    version: PROTOCOL_VERSION,
+                    sensors: 1,
+                    outputs: 1,
+                }),
+                Command::GetStatus => Ok(Reply::Status(Status {
+                    fault: None,
+                    outputs: heapless::Vec::<_, MAX_CHANNELS>::from_slice(&[OutputStatus {
+                        duty_permille: 500,
+                        rpm: Some(1200),
+                    }])
+                    .unwrap(),
+                    overruns: 0,
+                    save_failures: 0,
+                })),
+                _ => Err(controller_protocol::Error::UnknownOutput),
+            };
+            let stale = Response {
+                id: id - 1,
+                result: Ok(Reply::Ack),
+            };
+            if stale.id != 0 {
+                port.write_all(controller_protocol::encode(&stale, &mut buf).unwrap())
+                    .unwrap();
+            }
+            let response = Response { id, result };
+            port.write_all(controller_protocol::encode(&response, &mut buf).unwrap())
+                .unwrap();
+            answered += 1;
+        }
+        port
+    }
+
+    #[test]
+    fn matches_answers_to_requests() {
+        let pty = openpty(None, None).unwrap();
+        let path = nix::unistd::ttyname(&pty.slave).unwrap();
+        let mut termios = tcgetattr(&pty.slave).unwrap();
+        cfmakeraw(&mut termios);
+        tcsetattr(&pty.slave, SetArg::TCSANOW, &termios).unwrap();
+        let master = File::from(pty.master);
+        let controller = std::thread::spawn(move || controller(master, 3));
+
+        let mut client = Client::open(&path).unwrap();
+        assert_eq!((client.sensors(), client.outputs()), (1, 1));
+        let status = client.status().unwrap();
+        assert_eq!(status.outputs[0].rpm, Some(1200));
+        assert!(matches!(
+            client.curve(3),
+            Err(Error::Controller(controller_protocol::Error::UnknownOutput))
+        ));
+        // Still connected, but no longer answering
+        let _master = controller.join().unwrap();
+        client.set_timeout(Duration::from_millis(50));
+        assert!(matches!(client.status(), Err(Error::Timeout)));
+    }
+}
@@ -0,0 +1,21 @@
+//! Names of protocol values as host tools show them
+
+use controller_protocol::{Fault, SensorStatus};
+
+/// Name of a sensor's health
+pub const fn status_name(status: SensorStatus) -> &'static str {
+    match status {
+        SensorStatus::Ok => "ok",
+        SensorStatus::Open => "open",
+        SensorStatus::Short => "short",
+        SensorStatus::OutOfRange => "out-of-range",
+        SensorStatus::Stuck => "stuck",
+    }
+}
+
+/// Description of a latched fault, numbering outputs from 1
+pub fn fault_description(fault: Fault) -> String {
+    match fault {
+        Fault::Stall { output } => format!("stall on output {}", u16::from(output) + 1),
+    }
+}
@@ -1,6 +1,8 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 8K
+    /* Two sectors for persistent settings, see src/config.rs */
+    CONFIG : ORIGIN = 0x101FE000, LENGTH = 8K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
@@ -5,35 +5,73 @@ use pimoroni_tiny2040::hal::{
 
 use crate::util::ThermistorPin;
 
-pub struct Token<'a> {
-    pub adc_fifo: AdcFifo<'a, u16>,
-    _sensor_pin: AdcPin<ThermistorPin>,
-}
+/// External ADC inputs, GPIO26-29
+pub const MAX_CHANNELS: usize = 4;
+
+/// Resolution of the readings
+pub const BITS: u8 = controller_core::sensors::ADC_BITS;
+/// Full scale of the readings, in counts
+pub const FULL_SCALE: u32 = 1 << BITS;
 
-static mut STATIC_ADC: Option<Adc> = None;
+pub struct Token {
+    pub adc_fifo: AdcFifo<'static, u16>,
+    _sensor_pins: heapless::Vec<AdcPin<ThermistorPin>, MAX_CHANNELS>,
+}
 
-impl Token<'_> {
+impl Token {
     /// Consume ADC and provide constructed HAL structure (adc will be paused)
-    pub fn new(adc: ADC, resets: &mut RESETS, sensor_pin: ThermistorPin) -> Option<Self> {
-        // 1024 sps by USB clock trusting the documented factors
-        let adc = Adc::new(adc, resets);
-        let s_adc = unsafe {
-            STATIC_ADC = Some(adc);
-            STATIC_ADC.as_mut().unwrap_unchecked()
-        };
+    ///
+    /// With more than one pin the FIFO samples them round-robin, always in ascending channel order starting from the
+    /// lowest channel, whatever order they are given in here
+    pub fn new(
+        adc: ADC,
+        resets: &mut RESETS,
+        sensor_pins: impl IntoIterator<Item = ThermistorPin>,
+    ) -> Option<Self> {
+        // 1024 sps by USB clock trusting the documented factors, shared between all channels
+        let s_adc = cortex_m::singleton!(: Adc = Adc::new(adc, resets))?;
+
+        let mut pins: heapless::Vec<AdcPin<ThermistorPin>, MAX_CHANNELS> = heapless::Vec::new();
+        for pin in sensor_pins {
+            pins.push(AdcPin::new(pin).ok()?).ok()?;
+        }
+        pins.sort_unstable_by_key(AdcPin::channel);
 
-        let mut sensor_pin = AdcPin::new(sensor_pin).unwrap();
+        let builder = s_adc.build_fifo().set_channel(pins.first_mut()?);
+        let builder = match pins.as_slice() {
+            [_] => builder,
+            [a, b] => builder.round_robin((a, b)),
+            [a, b, c] => builder.round_robin((a, b, c)),
+            [a, b, c, d] => builder.round_robin((a, b, c, d)),
+            _ => return None,
+        };
 
-        let fifo = s_adc
-            .build_fifo()
-            .set_channel(&mut sensor_pin)
-            .clock_divider(46874, 0)
-            .enable_dma()
-            .start_paused();
+        let fifo = builder.clock_divider(46874, 0).enable_dma().start_paused();
 
         Some(Token {
             adc_fifo: fifo,
-            _sensor_pin: sensor_pin,
+            _sensor_pins: pins,
         })
     }
 }
+
+/// Restart round-robin sampling from `channel` with an empty FIFO, for when samples were dropped and the order of
+/// those in the FIFO can no longer be trusted
+///
+/// `start` runs while sampling is stopped, to get whatever reads the FIFO going again
+pub(crate) fn realign(channel: u8, start: impl FnOnce()) {
+    // The FIFO is otherwise only touched by DMA, which has stopped when this is needed
+    let adc = unsafe { &*ADC::ptr() };
+
+    adc.cs().modify(|_, w| w.start_many().clear_bit());
+    while adc.cs().read().ready().bit_is_clear() {}
+    while adc.fcs().read().level().bits() > 0 {
+        adc.fifo().read();
+    }
+    adc.fcs().modify(|_, w| w.over().clear_bit_by_one());
+    adc.cs().modify(|_, w| unsafe { w.ainsel().bits(channel) });
+
+    start();
+
+    adc.cs().modify(|_, w| w.start_many().set_bit());
+}
@@ -0,0 +1,50 @@
+//! The board's side of host requests, see `controller_core::commands` for their handling
+
+use crate::bsp::hal;
+use controller_core::Board;
+use controller_protocol::{Config, Error};
+use core::sync::atomic::{AtomicU32, Ordering};
+use rtic_sync::channel::Sender;
+
+/// Settings waiting for the `storage` task to write them to flash
+pub(crate) const SAVE_CAPACITY: usize = 1;
+
+/// Queued settings the `storage` task failed to write, only ever written from `record_save_failure`
+static SAVE_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Count a failed write of settings already acknowledged to the host, call from the `storage` task only
+pub(crate) fn record_save_failure() {
+    // No RMW atomics on the M0+, but this is the only writer
+    SAVE_FAILURES.store(
+        SAVE_FAILURES.load(Ordering::Relaxed).wrapping_add(1),
+        Ordering::Relaxed,
+    );
+}
+
+/// Settings in flash, written by the `storage` task, and the ROM's USB bootloader
+pub(crate) struct Controller {
+    save_tx: Sender<'static, Config, SAVE_CAPACITY>,
+}
+
+impl Controller {
+    pub(crate) const fn new(save_tx: Sender<'static, Config, SAVE_CAPACITY>) -> Self {
+        Self { save_tx }
+    }
+}
+
+impl Board for Controller {
+    /// Queued for the `storage` task, erasing flash takes far too long for the USB interrupt
+    fn save_config(&mut self, config: Config) -> Result<(), Error> {
+        // Full only if the host asks again before the previous save has started
+        self.save_tx.try_send(config).map_err(|_e| Error::Storage)
+    }
+
+    fn reboot_to_bootloader(&mut self) {
+        // reset into BL mode
+        hal::rom_data::reset_to_usb_boot(0, 0);
+    }
+
+    fn save_failures(&self) -> u32 {
+        SAVE_FAILURES.load(Ordering::Relaxed)
+    }
+}
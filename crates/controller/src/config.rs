@@ -0,0 +1,85 @@
+//! Persistent settings, stored in the two sectors at the top of flash reserved by `memory.x`
+//!
+//! What is stored and how it is turned into the running configuration is in `controller_core::config`, this is only
+//! the flash underneath it.
+
+use controller_core::config::SLOT_SIZE;
+use controller_lib::{nvstore::Slot, NvStorage};
+use controller_protocol::Config;
+
+const XIP_BASE: u32 = 0x1000_0000;
+/// One slot per sector
+const SECTOR_SIZE: usize = SLOT_SIZE;
+/// Flash offset of the `CONFIG` region in `memory.x`
+const CONFIG_OFFSET: u32 = 0x1F_E000;
+
+/// Errors writing settings to flash
+#[derive(Debug)]
+pub(crate) enum StorageError {
+    /// The settings do not fit in a sector
+    TooLarge,
+}
+
+/// The `CONFIG` region of flash, one slot per sector, and the RAM copy of a sector that records are built in
+pub(crate) struct Flash {
+    buffer: &'static mut [u8; SECTOR_SIZE],
+}
+
+impl Flash {
+    /// The one handle on the region, None if it has already been taken
+    pub(crate) fn take() -> Option<Self> {
+        let buffer = cortex_m::singleton!(: [u8; SECTOR_SIZE] = [0xff; SECTOR_SIZE])?;
+        Some(Self { buffer })
+    }
+}
+
+impl NvStorage for Flash {
+    type Error = StorageError;
+
+    fn read(&self, slot: Slot) -> &[u8] {
+        sector(slot)
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        self.buffer
+    }
+
+    /// Interrupts are disabled for the erase and program, tens of milliseconds, so this is only called from the
+    /// lowest priority task
+    fn write(&mut self, slot: Slot, len: usize) -> Result<(), StorageError> {
+        // The whole sector is rewritten, unused bytes left as erased
+        self.buffer
+            .get_mut(len..)
+            .ok_or(StorageError::TooLarge)?
+            .fill(0xff);
+
+        cortex_m::interrupt::free(|_cs| unsafe {
+            // Code runs from flash, so nothing else may touch it until XIP is restored using the boot2 copy
+            rp2040_flash::flash::flash_range_erase_and_program(
+                offset(slot),
+                &self.buffer[..],
+                true,
+            );
+        });
+
+        Ok(())
+    }
+}
+
+/// Settings to boot with, the newest valid stored record or the defaults
+pub(crate) fn load(flash: &Flash) -> Config {
+    controller_core::config::load(flash)
+}
+
+fn offset(slot: Slot) -> u32 {
+    match slot {
+        Slot::A => CONFIG_OFFSET,
+        #[allow(clippy::cast_possible_truncation)]
+        Slot::B => CONFIG_OFFSET + SECTOR_SIZE as u32,
+    }
+}
+
+fn sector(slot: Slot) -> &'static [u8] {
+    // Flash is memory mapped through XIP, and the region is reserved in memory.x so nothing else lives there
+    unsafe { core::slice::from_raw_parts((XIP_BASE + offset(slot)) as *const u8, SECTOR_SIZE) }
+}
@@ -10,38 +10,229 @@ pub(crate) use pimoroni_tiny2040 as bsp;
 
 // Imports
 mod adc;
+mod commands;
+mod config;
 mod control_loop;
 mod dma;
+mod sampling;
+mod tach;
 mod usb;
 mod util;
 
-// Use statements for main
-use bsp::entry;
+/// The firmware's tasks, highest priority first:
+///
+/// *  `tach` - counts fan tach edges, never to be held up by anything else
+/// *  `sampling` - turns each completed ADC buffer into readings, it has to hand the buffer back before the other
+///    one fills
+/// *  `control` - drives the fans from each batch of readings
+/// *  `usb`, `telemetry`, `health` and `storage` - answer the host, publish the control loop's state to it, watch over
+///    the whole thing and write settings to flash
+///
+/// Readings and the control loop's state are passed along as messages, so each task only waits on the one before
+/// it. New subsystems are new tasks receiving from or sending to these.
+#[rtic::app(device = crate::bsp::hal::pac, peripherals = true, dispatchers = [SW0_IRQ, SW1_IRQ])]
+mod app {
+    use crate::bsp::hal::Watchdog;
+    use crate::{
+        adc,
+        commands::{self, Controller, SAVE_CAPACITY},
+        config::{self, Flash},
+        control_loop::{self, ControlLoop, Snapshot},
+        dma,
+        sampling::{Sampler, Samples},
+        tach::{TachCounter, TachPins},
+        usb::Usb,
+        util::{self, ControllerStatusPin},
+    };
+    use controller_core::sensors::SENSORS;
+    use controller_protocol::Config;
+    use embedded_hal::digital::{OutputPin, StatefulOutputPin};
+    use rtic_monotonics::systick::prelude::*;
+    use rtic_sync::channel::{Receiver, Sender};
 
-#[entry]
-fn main() -> ! {
-    let mut peripherals = util::ControllerPeripherals::take(false).unwrap();
-    let red = peripherals.red.take().unwrap();
+    systick_monotonic!(Mono, 1_000);
 
-    let mut dma = dma::Token::new(peripherals.dma.take().unwrap(), &mut peripherals.resets);
+    /// Batches of readings in flight from `sampling` to `control`, more than one only if control falls behind
+    const SAMPLES_CAPACITY: usize = 2;
+    /// Snapshots in flight from `control` to `telemetry`
+    const SNAPSHOT_CAPACITY: usize = 2;
+    /// How often `health` runs, the heartbeat blinks at half this
+    const HEALTH_PERIOD_MS: u32 = 250;
 
-    let mut adc = adc::Token::new(
-        peripherals.adc.take().unwrap(),
-        &mut peripherals.resets,
-        peripherals.thermistor_pin.take().unwrap(),
-    )
-    .unwrap();
+    #[shared]
+    struct Shared {
+        /// Updated on every batch of readings, its curves can be replaced from the host
+        control: ControlLoop,
+        /// Latest state of the control loop, only ever touched at the lowest priority
+        snapshot: Snapshot,
+    }
+
+    #[local]
+    struct Local {
+        sampler: Sampler,
+        samples_tx: Sender<'static, Samples, SAMPLES_CAPACITY>,
+        samples_rx: Receiver<'static, Samples, SAMPLES_CAPACITY>,
+        snapshot_tx: Sender<'static, Snapshot, SNAPSHOT_CAPACITY>,
+        snapshot_rx: Receiver<'static, Snapshot, SNAPSHOT_CAPACITY>,
+        usb: Usb,
+        flash: Flash,
+        save_rx: Receiver<'static, Config, SAVE_CAPACITY>,
+        tach_pins: TachPins,
+        watchdog: Watchdog,
+        status_led: ControllerStatusPin,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        let mut peripherals = util::ControllerPeripherals::new(cx.device, false).unwrap();
+        Mono::start(cx.core.SYST, util::REF_CLK_HZ);
+        let flash = Flash::take().unwrap();
+        let config = config::load(&flash);
+
+        let mut dma = dma::Token::new(peripherals.dma.take().unwrap(), &mut peripherals.resets);
+
+        let mut adc = adc::Token::new(
+            peripherals.adc.take().unwrap(),
+            &mut peripherals.resets,
+            SENSORS.iter().map(|sensor| {
+                peripherals.thermistor_pins[usize::from(sensor.channel)]
+                    .take()
+                    .unwrap()
+            }),
+        )
+        .unwrap();
+
+        // Fans run flat out until the first batch of readings arrives
+        let fans = control_loop::OUTPUTS.iter().map(|output| {
+            (
+                peripherals.fans[output.header].take().unwrap(),
+                TachCounter(output.header),
+            )
+        });
+        let control =
+            ControlLoop::new(fans, util::SystemClock(peripherals.timer), &config).unwrap();
+        let snapshot = control.snapshot();
+
+        let sampler = Sampler::start(&mut adc, &mut dma, &config).unwrap();
+
+        let tach_pins = crate::tach::setup(
+            control_loop::OUTPUTS
+                .iter()
+                .filter(|output| output.pulses_per_rev > 0)
+                .map(|output| {
+                    (
+                        output.header,
+                        peripherals.tach_pins[output.header].take().unwrap(),
+                    )
+                }),
+        );
+        let (save_tx, save_rx) = rtic_sync::make_channel!(Config, SAVE_CAPACITY);
+        let usb = crate::usb::setup(&mut peripherals, config, Controller::new(save_tx));
+
+        let (samples_tx, samples_rx) = rtic_sync::make_channel!(Samples, SAMPLES_CAPACITY);
+        let (snapshot_tx, snapshot_rx) = rtic_sync::make_channel!(Snapshot, SNAPSHOT_CAPACITY);
+        control::spawn().ok().unwrap();
+        telemetry::spawn().ok().unwrap();
+        health::spawn().ok().unwrap();
+        storage::spawn().ok().unwrap();
+
+        // Interrupts are enabled as soon as this returns
+        peripherals.blue.set_high().unwrap();
+
+        (
+            Shared { control, snapshot },
+            Local {
+                sampler,
+                samples_tx,
+                samples_rx,
+                snapshot_tx,
+                snapshot_rx,
+                usb,
+                flash,
+                save_rx,
+                tach_pins,
+                watchdog: peripherals.watchdog,
+                status_led: peripherals.red.take().unwrap(),
+            },
+        )
+    }
+
+    #[task(binds = IO_IRQ_BANK0, priority = 4, local = [tach_pins])]
+    fn tach(cx: tach::Context) {
+        crate::tach::count(cx.local.tach_pins);
+    }
+
+    #[task(binds = DMA_IRQ_0, priority = 3, local = [sampler, samples_tx])]
+    fn sampling(cx: sampling::Context) {
+        if let Some(samples) = cx.local.sampler.complete() {
+            // Control is at most a batch behind, if it ever is further the newest batch is the one to lose as the
+            // next one is only a buffer away
+            let _ = cx.local.samples_tx.try_send(samples);
+        }
+    }
+
+    #[task(priority = 2, shared = [control], local = [samples_rx, snapshot_tx])]
+    async fn control(mut cx: control::Context) {
+        while let Ok(mut samples) = cx.local.samples_rx.recv().await {
+            let snapshot = cx
+                .shared
+                .control
+                .lock(|control| control.update(&mut samples.channels, samples.overruns));
+            let _ = cx.local.snapshot_tx.try_send(snapshot);
+        }
+    }
+
+    /// Keeps the latest state of the control loop for the host, so control never waits on USB to read it
+    #[task(priority = 1, shared = [snapshot], local = [snapshot_rx])]
+    async fn telemetry(mut cx: telemetry::Context) {
+        while let Ok(snapshot) = cx.local.snapshot_rx.recv().await {
+            cx.shared.snapshot.lock(|latest| *latest = snapshot);
+        }
+    }
 
-    // Initialize objects with the peripherals created before
-    let controller = control_loop::Token::new(&mut adc, &mut dma, red).unwrap();
-    usb::setup(&mut peripherals, controller);
+    #[task(binds = USBCTRL_IRQ, priority = 1, shared = [snapshot, control], local = [usb])]
+    fn usb(mut cx: usb::Context) {
+        let snapshot = cx.shared.snapshot.lock(|snapshot| *snapshot);
+        cx.local.usb.poll(&snapshot, &mut cx.shared.control);
+    }
+
+    /// Feeds the watchdog and shows the state of the controller on the status LED: solid on a fault or while any
+    /// sensor is unhealthy, a heartbeat while the control loop is updating, and frozen if it stops
+    #[task(priority = 1, shared = [snapshot], local = [watchdog, status_led])]
+    async fn health(mut cx: health::Context) {
+        let status = cx.local.status_led;
+        let mut last_sequence = None;
+        loop {
+            cx.local.watchdog.feed();
 
-    peripherals.unmask_interrupts();
+            let snapshot = cx.shared.snapshot.lock(|snapshot| *snapshot);
+            let unhealthy = snapshot
+                .status
+                .sensors
+                .iter()
+                .flatten()
+                .any(|status| *status != controller_lib::SensorStatus::Ok);
+            // The LED is active low, driving the pin low turns it on
+            if snapshot.status.fault.is_some() || unhealthy {
+                status.set_low().unwrap();
+            } else if last_sequence != Some(snapshot.sequence) {
+                status.toggle().unwrap();
+            }
+            last_sequence = Some(snapshot.sequence);
 
-    loop {
-        peripherals.watchdog.feed();
+            Mono::delay(HEALTH_PERIOD_MS.millis()).await;
+        }
+    }
 
-        // event loop
-        cortex_m::asm::wfi();
+    /// Writes settings the host asked to store. Interrupts are off while a sector is erased and programmed, so this
+    /// runs after the USB interrupt has answered rather than inside it.
+    #[task(priority = 1, local = [flash, save_rx])]
+    async fn storage(cx: storage::Context) {
+        while let Ok(config) = cx.local.save_rx.recv().await {
+            // The host has had its answer already, failures are counted in the status instead
+            if controller_core::config::save(cx.local.flash, &config).is_err() {
+                commands::record_save_failure();
+            }
+        }
     }
 }
@@ -0,0 +1,163 @@
+//! Thermistor sampling, from the ADC's round-robin over `SENSORS` through DMA to a raw reading of each sensor
+//!
+//! ADC conversion is done entirely in hw. DMA fills one buffer while the other is processed, which only has to be
+//! queued again before the filling one is full. Conversion of the readings happens in the control loop, see
+//! `controller_lib::SensorChain`. The sensors themselves are listed in `controller_core::sensors`.
+
+use crate::{
+    adc,
+    bsp::hal::{
+        adc::DmaReadTarget,
+        dma::{
+            double_buffer::{Config, Transfer, WriteNext},
+            Channel, SingleChannel, CH0, CH1,
+        },
+        pac,
+    },
+    dma,
+};
+use controller_core::{
+    config,
+    sensors::{MAX_SAMPLES_PER_SENSOR, SENSORS, SENSOR_COUNT},
+};
+use controller_lib::dsp::{Decimator, RP2040_DNL_CODES};
+use controller_lib::{RawReading, TemperatureSource};
+
+// Singletons
+type DmaBuf = [u16; MAX_SAMPLES_PER_SENSOR * adc::MAX_CHANNELS];
+/// DMA fills one buffer while the other is processed, see `Sampler::complete`
+type SampleTransfer = Transfer<
+    Channel<CH0>,
+    Channel<CH1>,
+    DmaReadTarget<u16>,
+    &'static mut [u16],
+    WriteNext<&'static mut [u16]>,
+>;
+
+/// One sensor's share of a completed buffer
+#[derive(Copy, Clone)]
+pub struct AdcChannel(RawReading);
+
+impl TemperatureSource for AdcChannel {
+    fn read(&mut self) -> RawReading {
+        self.0
+    }
+}
+
+/// Readings of every sensor from one completed buffer
+#[derive(Copy, Clone)]
+pub struct Samples {
+    /// Indexed as `SENSORS`
+    pub channels: [AdcChannel; SENSOR_COUNT],
+    /// Times both buffers filled before one was handed back and sampling had to be restarted
+    pub overruns: u32,
+}
+
+/// The running DMA transfer, and the decimation of its buffers into readings
+pub(crate) struct Sampler {
+    /// Only None while a completed buffer is being processed
+    transfer: Option<SampleTransfer>,
+    /// DMA channel number of the buffer queued behind the one filling, the channel that last completed
+    queued_channel: u8,
+    decimator: Decimator,
+    overruns: u32,
+}
+
+/// Whether both DMA channels have stopped, chained channels only start if queued before the active one finished
+fn dma_stalled() -> bool {
+    // Read only, the channels themselves are owned by the transfer
+    let dma = unsafe { &*pac::DMA::ptr() };
+    (0..2).all(|ch| dma.ch(ch).ch_ctrl_trig().read().busy().bit_is_clear())
+}
+
+/// One sensor's samples out of an interleaved buffer
+fn samples(buf: &[u16], index: usize) -> impl Iterator<Item = u16> + '_ {
+    buf.iter().skip(index).step_by(SENSORS.len()).copied()
+}
+
+impl Sampler {
+    /// Start sampling into a pair of singleton buffers, completing each raises `DMA_IRQ_0`
+    ///
+    /// The ADC must be running round-robin over exactly the channels in `SENSORS`. `config` must pass
+    /// `config::is_valid`.
+    pub(crate) fn start(
+        adc: &mut adc::Token,
+        dma: &mut dma::Token,
+        config: &controller_protocol::Config,
+    ) -> Option<Self> {
+        if !config::is_valid(config) {
+            return None;
+        }
+        // Samples are demultiplexed by position in the buffer, which only works if this matches the ADC's order
+        if SENSORS.windows(2).any(|w| w[0].channel >= w[1].channel) {
+            return None;
+        }
+        let mut ch0 = dma.take_ch0()?;
+        let mut ch1 = dma.take_ch1()?;
+        ch0.enable_irq0();
+        ch1.enable_irq0();
+
+        // DMA transfers, a whole number of round-robin cycles so every buffer starts on the first sensor. Channel 0
+        // fills the first buffer, then hands over to channel 1 for the second.
+        let len = usize::from(config.samples_per_sensor) * SENSORS.len();
+        let [first, second] = cortex_m::singleton!(: [DmaBuf; 2] = [[0; MAX_SAMPLES_PER_SENSOR * adc::MAX_CHANNELS]; 2])?;
+        let trans = Config::new(
+            (ch0, ch1),
+            adc.adc_fifo.dma_read_target(),
+            &mut first[..len],
+        )
+        .start()
+        .write_next(&mut second[..len]);
+
+        adc.adc_fifo.resume();
+
+        Some(Self {
+            transfer: Some(trans),
+            queued_channel: 1,
+            decimator: Decimator::new(config.oversample_bits).discarding(&RP2040_DNL_CODES),
+            overruns: 0,
+        })
+    }
+
+    /// Decimate a completed buffer and queue it up again, call on `DMA_IRQ_0`
+    ///
+    /// None if the interrupt was not for a completed buffer
+    pub(crate) fn complete(&mut self) -> Option<Samples> {
+        let mut trans = self.transfer.take()?;
+        if !trans.check_irq0() {
+            self.transfer = Some(trans);
+            return None;
+        }
+        let (wr, trans) = trans.wait();
+
+        let full_scale = adc::FULL_SCALE << self.decimator.extra_bits();
+        let channels = core::array::from_fn(|index| {
+            let (sum, samples) = self.decimator.accumulate(samples(wr, index));
+            AdcChannel(RawReading {
+                sum,
+                samples,
+                full_scale,
+            })
+        });
+
+        // Requeue the completed buffer behind the one now filling
+        self.transfer = Some(trans.write_next(wr));
+        self.queued_channel ^= 1;
+        let queued = self.queued_channel;
+
+        // Both buffers filled before this one was queued, so samples were dropped and the chain never started it
+        if dma_stalled() {
+            adc::realign(SENSORS[0].channel, || unsafe {
+                (*pac::DMA::ptr())
+                    .multi_chan_trigger()
+                    .write(|w| w.bits(1 << queued));
+            });
+            self.overruns = self.overruns.wrapping_add(1);
+        }
+
+        Some(Samples {
+            channels,
+            overruns: self.overruns,
+        })
+    }
+}
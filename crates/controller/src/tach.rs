@@ -0,0 +1,58 @@
+//! Fan tach inputs, counted with a GPIO interrupt on every falling edge
+//!
+//! Conversion of the counts to RPM happens in the control loop, see `controller_lib::Tachometer`
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use controller_lib::TachInput;
+
+use crate::{
+    bsp::hal::gpio::Interrupt as GpioInterrupt,
+    util::{TachPin, FAN_HEADERS},
+};
+
+/// Tach inputs by fan header, None for headers not counted
+pub(crate) type TachPins = [Option<TachPin>; FAN_HEADERS];
+
+/// Free running pulse count of each fan header, only ever written from `count`
+static PULSES: [AtomicU32; FAN_HEADERS] = [const { AtomicU32::new(0) }; FAN_HEADERS];
+
+/// Enable the edge interrupts on the tach inputs of the given fan headers, see `count`
+pub(crate) fn setup(pins: impl IntoIterator<Item = (usize, TachPin)>) -> TachPins {
+    let mut tach_pins = [const { None }; FAN_HEADERS];
+    for (header, pin) in pins {
+        pin.set_interrupt_enabled(GpioInterrupt::EdgeLow, true);
+        tach_pins[header] = Some(pin);
+    }
+    tach_pins
+}
+
+/// Pulses counted so far on a fan header, wraps
+pub(crate) fn pulses(header: usize) -> u32 {
+    PULSES[header].load(Ordering::Relaxed)
+}
+
+/// The pulse count of a fan header
+pub struct TachCounter(pub usize);
+
+impl TachInput for TachCounter {
+    fn pulses(&mut self) -> u32 {
+        pulses(self.0)
+    }
+}
+
+/// Count the edges pending on `pins`, call on `IO_IRQ_BANK0`
+pub(crate) fn count(pins: &mut TachPins) {
+    for (pin, count) in pins.iter_mut().zip(PULSES.iter()) {
+        if let Some(pin) = pin {
+            if pin.interrupt_status(GpioInterrupt::EdgeLow) {
+                pin.clear_interrupt(GpioInterrupt::EdgeLow);
+                // No RMW atomics on the M0+, but this is the only writer
+                count.store(
+                    count.load(Ordering::Relaxed).wrapping_add(1),
+                    Ordering::Relaxed,
+                );
+            }
+        }
+    }
+}
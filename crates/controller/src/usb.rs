@@ -1,35 +1,47 @@
-use crate::{control_loop, util::ControllerPeripherals};
+//! The host connection, a CDC serial port carrying `controller_protocol` frames
 
-use bsp::hal;
-use controller_lib::Degrees;
-use core::{
-    fmt::Write,
-    sync::atomic::{AtomicBool, Ordering},
+use crate::{
+    commands::Controller,
+    control_loop::{ControlLoop, Snapshot},
+    util::ControllerPeripherals,
 };
-use hal::pac::interrupt;
-use hal::timer::{Alarm, Alarm2};
+
+use bsp::hal;
+use controller_core::Session;
+use controller_protocol::{Config, MAX_FRAME};
 use hal::usb::UsbBus;
 use pimoroni_tiny2040 as bsp;
+use rtic::Mutex;
 
 // USB Device support
 use usb_device::{class_prelude::*, prelude::*};
 use usbd_serial::SerialPort;
 
-static mut USB_BUS: Option<UsbBusAllocator<UsbBus>> = None;
-
-static mut ALARM2: Option<Alarm2> = None;
-
-// USB Singletons
-static mut USB_DEVICE: Option<UsbDevice<UsbBus>> = None;
-static mut USB_SERIAL: Option<SerialPort<UsbBus>> = None;
-static mut ACTIVE_LOOP: Option<control_loop::Token> = None;
-
-// Poll every 10ms
-const USB_PERIOD: fugit::MicrosDurationU32 = fugit::MicrosDurationU32::Hz(100);
-
-pub static USB_SEND_STATUS_PENDING: AtomicBool = AtomicBool::new(false);
+/// Size of the USB serial read buffer, one full speed bulk packet
+const RX_SIZE: usize = 64;
+
+/// The USB device and the state of the host's request stream, polled on every `USBCTRL_IRQ`
+pub(crate) struct Usb {
+    device: UsbDevice<'static, UsbBus>,
+    serial: SerialPort<'static, UsbBus>,
+    session: Session,
+    board: Controller,
+    /// Received bytes not yet handed to the session, from `rx_start` up to `rx_end`
+    rx: [u8; RX_SIZE],
+    rx_start: usize,
+    rx_end: usize,
+    /// Response frame still being written out, from `tx_sent` on. Further requests wait until it is all sent, a
+    /// truncated frame would not decode on the host.
+    tx: heapless::Vec<u8, MAX_FRAME>,
+    tx_sent: usize,
+}
 
-pub(crate) fn setup(controller: &mut ControllerPeripherals, sampling_loop: control_loop::Token) {
+/// Bring up the USB device, it enumerates once `USBCTRL_IRQ` is serviced
+pub(crate) fn setup(
+    controller: &mut ControllerPeripherals,
+    boot_config: Config,
+    board: Controller,
+) -> Usb {
     let (dpram, regs, usb_clock) = controller.usb_peripherals.take().unwrap();
     // Initialize USB bus
     let usb_bus = UsbBusAllocator::new(hal::usb::UsbBus::new(
@@ -40,102 +52,100 @@ pub(crate) fn setup(controller: &mut ControllerPeripherals, sampling_loop: contr
         &mut controller.resets,
     ));
 
-    let bus_ref = unsafe {
-        USB_BUS.replace(usb_bus);
-        USB_BUS.as_ref().unwrap()
-    };
+    let bus_ref: &'static UsbBusAllocator<UsbBus> =
+        cortex_m::singleton!(: UsbBusAllocator<UsbBus> = usb_bus).unwrap();
 
     let serial = SerialPort::new(bus_ref);
     let usb_dev = UsbDeviceBuilder::new(bus_ref, UsbVidPid(0x16c0, 0x27dd))
         .strings(&[StringDescriptors::default()
             .manufacturer("DEXCORP")
             .product("Dex Fan Controller")
-            .serial_number("FOO")])
+            .serial_number(serial_number())])
         .unwrap()
         .device_class(2) // from: https://www.usb.org/defined-class-codes
         .build();
 
-    // Timer init, to schedule polls
-    let mut status_timer = controller.timer.alarm_2().unwrap();
-    status_timer.schedule(USB_PERIOD).unwrap();
-    status_timer.enable_interrupt();
-
-    unsafe {
-        ALARM2.replace(status_timer);
-        USB_SERIAL.replace(serial);
-        USB_DEVICE.replace(usb_dev);
-        ACTIVE_LOOP.replace(sampling_loop);
-    }
-
-    unsafe {
-        hal::pac::NVIC::unmask(hal::pac::interrupt::TIMER_IRQ_2);
-        hal::pac::NVIC::unmask(hal::pac::Interrupt::USBCTRL_IRQ);
+    Usb {
+        device: usb_dev,
+        serial,
+        session: Session::new(boot_config),
+        board,
+        rx: [0; RX_SIZE],
+        rx_start: 0,
+        rx_end: 0,
+        tx: heapless::Vec::new(),
+        tx_sent: 0,
     }
 }
 
-// Alarm 1 timer, used only for scheduling events for the USB IRQ right now
-#[allow(non_snake_case)]
-#[interrupt]
-unsafe fn TIMER_IRQ_2() {
-    let status_timer = ALARM2.as_mut().unwrap_unchecked();
+/// The flash chip's 64 bit unique ID in hex, so hosts can tell several controllers apart
+fn serial_number() -> &'static str {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut id = [0u8; 8];
+    cortex_m::interrupt::free(|_cs| unsafe {
+        // Nothing may run from flash while the ID is read, XIP is restored using the boot2 copy
+        rp2040_flash::flash::flash_unique_id(&mut id, true);
+    });
 
-    status_timer.clear_interrupt();
-    status_timer.schedule(USB_PERIOD).unwrap();
-    // USB_SEND_STATUS_PENDING.store(true, core::sync::atomic::Ordering::Relaxed);
-    hal::pac::NVIC::pend(hal::pac::interrupt::USBCTRL_IRQ);
+    let serial = cortex_m::singleton!(: [u8; 16] = [0; 16]).unwrap();
+    for (digits, byte) in serial.chunks_exact_mut(2).zip(id) {
+        digits[0] = HEX[usize::from(byte >> 4)];
+        digits[1] = HEX[usize::from(byte & 0xf)];
+    }
+    core::str::from_utf8(serial).unwrap()
 }
 
-#[allow(non_snake_case)]
-#[interrupt]
-unsafe fn USBCTRL_IRQ() {
-    let usb_dev = USB_DEVICE.as_mut().unwrap();
-    let serial = USB_SERIAL.as_mut().unwrap();
-
-    if usb_dev.poll(&mut [serial]) {
-        let mut buf = [0u8; 64];
-        match serial.read(&mut buf) {
-            Err(_e) => {
-                // Do nothing
-            }
-            Ok(0) => {
-                // Do nothing
+impl Usb {
+    /// Service the device, answering any complete requests against the control loop's state
+    ///
+    /// Requests are answered one at a time. Each response is written out in full before the next request is taken
+    /// on, over as many polls as the serial port's buffer needs to drain.
+    pub(crate) fn poll(&mut self, snapshot: &Snapshot, control: &mut impl Mutex<T = ControlLoop>) {
+        self.device.poll(&mut [&mut self.serial]);
+        if self.device.state() != UsbDeviceState::Configured {
+            // Whatever was in flight is of no use to the next host
+            self.tx.clear();
+            self.tx_sent = 0;
+            self.rx_start = self.rx_end;
+            return;
+        }
+
+        loop {
+            if !self.flush() {
+                return;
             }
-            Ok(count) => {
-                // scan for key command letters
-                buf.into_iter().take(count).for_each(|b| {
-                    if b == b't' {
-                        USB_SEND_STATUS_PENDING.store(true, Ordering::Relaxed);
-                    }
-                    if b == b'u' {
-                        // reset into BL mode
-                        hal::rom_data::reset_to_usb_boot(0, 0);
-                    }
-                });
+            if self.rx_start == self.rx_end {
+                match self.serial.read(&mut self.rx) {
+                    Ok(count) if count > 0 => (self.rx_start, self.rx_end) = (0, count),
+                    // Nothing received, or Err(WouldBlock)
+                    _ => return,
+                }
             }
+
+            let Self {
+                session, board, tx, ..
+            } = self;
+            let received = &self.rx[self.rx_start..self.rx_end];
+            let remaining = session.receive_one(received, snapshot, control, board, |frame| {
+                // Only sent once the previous frame is, and frames are at most `MAX_FRAME` long
+                let _ = tx.extend_from_slice(frame);
+            });
+            self.rx_start = self.rx_end - remaining.len();
         }
     }
-    let mut report_buf: heapless::String<64> = heapless::String::new();
-    let pending = USB_SEND_STATUS_PENDING.load(Ordering::Relaxed);
-    if pending {
-        USB_SEND_STATUS_PENDING.store(false, Ordering::SeqCst);
-        let current_temp = if let Some(controller) = unsafe { ACTIVE_LOOP.as_ref() } {
-            controller.current_temp()
-        } else {
-            Degrees(0)
-        };
-
-        // if command was received, overwrite buffer contents with a response instead
-        writeln!(report_buf, "{current_temp:02}").unwrap();
-
-        let mut wr_ptr = report_buf.as_bytes();
-        while !wr_ptr.is_empty() {
-            match serial.write(wr_ptr) {
-                Ok(len) => wr_ptr = &wr_ptr[len..],
-                // On error, just drop unwritten data.
-                // One possible error is Err(WouldBlock), meaning the USB
-                // write buffer is full.
-                Err(_) => break,
-            };
+
+    /// Write as much of the pending response frame as the serial port takes, true once it is all sent
+    fn flush(&mut self) -> bool {
+        while self.tx_sent < self.tx.len() {
+            match self.serial.write(&self.tx[self.tx_sent..]) {
+                Ok(len) if len > 0 => self.tx_sent += len,
+                // Err(WouldBlock) while the host has not read the port's buffer, try again on the next poll
+                _ => return false,
+            }
         }
-    };
+        self.tx.clear();
+        self.tx_sent = 0;
+        true
+    }
 }
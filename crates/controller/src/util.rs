@@ -1,60 +1,140 @@
 //! Ostensibly, BSP initialization code
 //!
 //! Realistically... too much. controller init code here should be in the controller, usb should be in usb, what's left (if anything) should remain here
-use cortex_m::delay::Delay;
-
 use crate::bsp::hal;
 
-use embedded_hal::digital::OutputPin;
+use controller_lib::{Clock, PwmOutput};
+use core::convert::Infallible;
+use embedded_hal::{
+    digital::OutputPin,
+    pwm::{ErrorType, SetDutyCycle},
+};
 use fugit::ExtU32;
 use hal::{
     gpio::{
-        bank0::{Gpio18, Gpio19, Gpio20, Gpio26},
-        FunctionSio, Pin, PullDown, PullNone, SioInput, SioOutput,
+        bank0::{Gpio18, Gpio19, Gpio20},
+        DynPinId, FunctionSio, Pin, PullDown, PullNone, PullUp, SioInput, SioOutput,
     },
     pac,
-    pwm::{Channel, FreeRunning, Pwm2, Slice, A},
-    Clock, Timer, Watchdog,
+    pwm::{Channel, FreeRunning, Pwm0, Pwm1, Pwm2, Pwm3, Slice, A},
+    Timer, Watchdog,
 };
 
 // this must be configured for 25khz
 // the input clock here is the system clock which is a big 125Mhz PLL
-const REF_CLK_HZ: u32 = 125_000_000;
+pub(crate) const REF_CLK_HZ: u32 = 125_000_000;
 const PWM_TARGET_HZ: u32 = 25_000;
 const PWM_DIV: u32 = 1;
 pub const PWM_TICKS: u32 = (REF_CLK_HZ / PWM_TARGET_HZ) / (PWM_DIV);
 
 pub(crate) type ControllerStatusPin = Pin<Gpio18, FunctionSio<SioOutput>, PullDown>;
-pub(crate) type ThermistorPin = Pin<Gpio26, FunctionSio<SioInput>, PullNone>;
-pub(crate) type FanPin = Channel<Slice<Pwm2, FreeRunning>, A>;
+pub(crate) type ThermistorPin = Pin<DynPinId, FunctionSio<SioInput>, PullNone>;
+/// Fan tach outputs are open collector, so these are pulled up
+pub(crate) type TachPin = Pin<DynPinId, FunctionSio<SioInput>, PullUp>;
+
+/// Number of fan headers, see `ControllerPeripherals::fans` for the pin mapping
+pub const FAN_HEADERS: usize = 4;
+
+/// PWM output of one of the fan headers, each on the A channel of its own slice
+pub enum FanPin {
+    Pwm0(Channel<Slice<Pwm0, FreeRunning>, A>),
+    Pwm1(Channel<Slice<Pwm1, FreeRunning>, A>),
+    Pwm2(Channel<Slice<Pwm2, FreeRunning>, A>),
+    Pwm3(Channel<Slice<Pwm3, FreeRunning>, A>),
+}
+
+impl ErrorType for FanPin {
+    type Error = Infallible;
+}
+
+impl SetDutyCycle for FanPin {
+    fn max_duty_cycle(&self) -> u16 {
+        match self {
+            Self::Pwm0(ch) => ch.max_duty_cycle(),
+            Self::Pwm1(ch) => ch.max_duty_cycle(),
+            Self::Pwm2(ch) => ch.max_duty_cycle(),
+            Self::Pwm3(ch) => ch.max_duty_cycle(),
+        }
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        match self {
+            Self::Pwm0(ch) => ch.set_duty_cycle(duty),
+            Self::Pwm1(ch) => ch.set_duty_cycle(duty),
+            Self::Pwm2(ch) => ch.set_duty_cycle(duty),
+            Self::Pwm3(ch) => ch.set_duty_cycle(duty),
+        }
+    }
+}
+
+impl PwmOutput for FanPin {
+    fn max_duty(&self) -> u16 {
+        self.max_duty_cycle()
+    }
+
+    fn set_duty(&mut self, duty: u16) {
+        self.set_duty_cycle(duty).unwrap();
+    }
+}
+
+/// Microseconds since boot from the free running timer
+#[derive(Copy, Clone)]
+pub struct SystemClock(pub Timer);
+
+impl Clock for SystemClock {
+    fn now_us(&mut self) -> u64 {
+        self.0.get_counter().ticks()
+    }
+}
+
+/// Set a slice up for 25khz fan PWM and route its A channel to the given pin
+macro_rules! fan_header {
+    ($slice:expr, $pin:expr, $variant:ident) => {{
+        let mut slice = $slice;
+        slice.enable();
+        slice.set_top((PWM_TICKS / PWM_DIV) as u16);
+        // slice.set_ph_correct();
+
+        let mut fan = slice.channel_a;
+        // fan.set_inverted();
+        let mut fan_io = $pin;
+
+        fan_io.set_drive_strength(hal::gpio::OutputDriveStrength::TwelveMilliAmps);
+        fan_io.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+
+        fan.output_to(fan_io);
+        Some(FanPin::$variant(fan))
+    }};
+}
 
 /// Global state struct, and central control point for peripheral/hardware access
 pub struct ControllerPeripherals {
     pub resets: hal::pac::RESETS,
     /// Feed this hungry boi every second or else
     pub watchdog: Watchdog,
-    pub systick_delay: Delay,
     /// Can be used to take ownership of timers and alarms
     pub timer: Timer,
     /// Hold some peripheral blocks, to allow them to be taken
     pub adc: Option<pac::ADC>,
     pub dma: Option<pac::DMA>,
-    pub thermistor_pin: Option<ThermistorPin>,
+    /// ADC capable pins, indexed by ADC channel
+    pub thermistor_pins: [Option<ThermistorPin>; 4],
     pub red: Option<ControllerStatusPin>,
     pub green: Pin<Gpio19, FunctionSio<SioOutput>, PullDown>,
     pub blue: Pin<Gpio20, FunctionSio<SioOutput>, PullDown>,
-    pub fan: Option<FanPin>,
+    /// Fan headers, in order GPIO4 (the original fan output), GPIO6, GPIO0 and GPIO2. The odd pin next to each is
+    /// left free for a tach input.
+    pub fans: [Option<FanPin>; FAN_HEADERS],
+    /// Tach inputs of each fan header, GPIO5, GPIO7, GPIO1 and GPIO3
+    pub tach_pins: [Option<TachPin>; FAN_HEADERS],
     pub(crate) usb_peripherals:
         Option<(pac::USBCTRL_DPRAM, pac::USBCTRL_REGS, hal::clocks::UsbClock)>,
 }
 
 #[allow(clippy::cast_possible_truncation)]
 impl ControllerPeripherals {
-    /// Make the instance of this singleton, return None if somehow called twice
-    pub fn take(start_watchdog: bool) -> Option<Self> {
-        let mut pac_peripherals = hal::pac::Peripherals::take()?;
-        let core = hal::pac::CorePeripherals::take()?;
-
+    /// Bring up clocks and pins on the device peripherals, None if the clocks fail to start
+    pub fn new(mut pac_peripherals: pac::Peripherals, start_watchdog: bool) -> Option<Self> {
         let mut watchdog = hal::Watchdog::new(pac_peripherals.WATCHDOG);
 
         let clocks = hal::clocks::init_clocks_and_plls(
@@ -74,9 +154,6 @@ impl ControllerPeripherals {
             watchdog.start(1.secs());
         }
 
-        let systick_delay =
-            cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
-
         let sio = hal::Sio::new(pac_peripherals.SIO);
         // now that USB is done with it take ownership of these for the BSP
         let board: crate::bsp::Pins = crate::bsp::Pins::new(
@@ -89,33 +166,30 @@ impl ControllerPeripherals {
         // Configure PWMs
         let pwm_slices = hal::pwm::Slices::new(pac_peripherals.PWM, &mut pac_peripherals.RESETS);
 
-        let mut pwm2 = pwm_slices.pwm2;
-
-        pwm2.enable();
-        pwm2.set_top((PWM_TICKS / PWM_DIV) as u16);
-        // pwm.set_ph_correct();
-
-        let mut fan = pwm2.channel_a;
-        // fan.set_inverted();
-        let mut fan_io = board.gpio4;
-
-        fan_io.set_drive_strength(hal::gpio::OutputDriveStrength::TwelveMilliAmps);
-        fan_io.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
-
-        fan.output_to(fan_io);
+        let fans = [
+            fan_header!(pwm_slices.pwm2, board.gpio4, Pwm2),
+            fan_header!(pwm_slices.pwm3, board.gpio6, Pwm3),
+            fan_header!(pwm_slices.pwm0, board.gpio0, Pwm0),
+            fan_header!(pwm_slices.pwm1, board.gpio2, Pwm1),
+        ];
 
         let timer = hal::Timer::new(pac_peripherals.TIMER, &mut pac_peripherals.RESETS, &clocks);
 
         let mut ret = Self {
             watchdog,
-            systick_delay,
             timer,
             adc: Some(pac_peripherals.ADC),
-            thermistor_pin: Some(board.gpio26.into_floating_input()),
+            thermistor_pins: [
+                Some(board.gpio26.into_floating_input().into_dyn_pin()),
+                Some(board.gpio27.into_floating_input().into_dyn_pin()),
+                Some(board.gpio28.into_floating_input().into_dyn_pin()),
+                Some(board.gpio29.into_floating_input().into_dyn_pin()),
+            ],
+            // Active low like the other LEDs, starts off until the heartbeat or a fault lights it
             red: Some(
                 board
                     .led_red
-                    .into_push_pull_output_in_state(hal::gpio::PinState::Low),
+                    .into_push_pull_output_in_state(hal::gpio::PinState::High),
             ),
             green: board
                 .led_green
@@ -123,7 +197,13 @@ impl ControllerPeripherals {
             blue: board
                 .led_blue
                 .into_push_pull_output_in_state(hal::gpio::PinState::Low),
-            fan: Some(fan),
+            fans,
+            tach_pins: [
+                Some(board.gpio5.into_pull_up_input().into_dyn_pin()),
+                Some(board.gpio7.into_pull_up_input().into_dyn_pin()),
+                Some(board.gpio1.into_pull_up_input().into_dyn_pin()),
+                Some(board.gpio3.into_pull_up_input().into_dyn_pin()),
+            ],
             resets: pac_peripherals.RESETS,
             usb_peripherals: Some((
                 pac_peripherals.USBCTRL_DPRAM,
@@ -136,12 +216,4 @@ impl ControllerPeripherals {
 
         Some(ret)
     }
-
-    /// Initializer function, called from `take()` once
-    pub fn unmask_interrupts(&mut self) {
-        self.blue.set_high().unwrap();
-        unsafe {
-            cortex_m::interrupt::enable();
-        }
-    }
 }
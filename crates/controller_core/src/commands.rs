@@ -0,0 +1,423 @@
+//! Handling of host requests, see `controller_protocol` for the wire format
+
+use crate::{
+    config,
+    control_loop::{ControlLoop, CurveError, Snapshot, OUTPUTS},
+    sensors::SENSORS,
+};
+use controller_lib::{Clock, Degrees, Fault, PwmOutput, SensorStatus, TachInput};
+use controller_protocol::{
+    Command, Config, Error, FeedResult, FrameAccumulator, OutputStatus, Reply, Request, Response,
+    SensorReading, Status, MAX_FRAME, PROTOCOL_VERSION,
+};
+use rtic_core::Mutex;
+
+/// What the commands need from the board, besides the control loop
+pub trait Board {
+    /// Store settings for the next boot with `config::save`, or hand them to whatever will. The firmware writes flash
+    /// from a task of its own, so this may return before they are written.
+    ///
+    /// # Errors
+    /// * `Error::Storage` if the settings could not be stored, or queued to be
+    fn save_config(&mut self, config: Config) -> Result<(), Error>;
+
+    /// Reset into the USB bootloader, which on real hardware does not return
+    fn reboot_to_bootloader(&mut self);
+
+    /// Settings accepted by `save_config` that then failed to be written, since boot. Boards that write before
+    /// returning report failures from `save_config` instead.
+    fn save_failures(&self) -> u32 {
+        0
+    }
+}
+
+/// Carry out a request, returning the response to send back if there is one
+///
+/// # Arguments
+/// *  `snapshot` - Latest state of the control loop
+/// *  `control` - The control loop, locked against its updates for the moment curves are read or replaced
+/// *  `boot_config` - Settings the firmware started with, stored ones only take effect on the next boot
+#[allow(clippy::cast_possible_truncation)]
+pub fn handle<P: PwmOutput, T: TachInput, K: Clock>(
+    request: Request,
+    snapshot: &Snapshot,
+    control: &mut impl Mutex<T = ControlLoop<P, T, K>>,
+    boot_config: &Config,
+    board: &mut impl Board,
+) -> Option<Response> {
+    let result = match request.command {
+        Command::Hello { version } => {
+            if version == PROTOCOL_VERSION {
+                Ok(Reply::Hello {
+                    version: PROTOCOL_VERSION,
+                    sensors: SENSORS.len() as u8,
+                    outputs: OUTPUTS.len() as u8,
+                })
+            } else {
+                Err(Error::VersionMismatch {
+                    supported: PROTOCOL_VERSION,
+                })
+            }
+        }
+        Command::ReadSensors => Ok(Reply::Sensors(
+            (0..SENSORS.len())
+                .map(|index| SensorReading {
+                    millidegrees: snapshot.status.readings[index].map(Degrees::to_millidegrees),
+                    status: match snapshot.status.sensors[index] {
+                        None | Some(SensorStatus::Ok) => controller_protocol::SensorStatus::Ok,
+                        Some(SensorStatus::Open) => controller_protocol::SensorStatus::Open,
+                        Some(SensorStatus::Short) => controller_protocol::SensorStatus::Short,
+                        Some(SensorStatus::OutOfRange) => {
+                            controller_protocol::SensorStatus::OutOfRange
+                        }
+                        Some(SensorStatus::Stuck) => controller_protocol::SensorStatus::Stuck,
+                    },
+                })
+                .collect(),
+        )),
+        Command::GetStatus => Ok(Reply::Status(Status {
+            fault: snapshot.status.fault.map(|fault| match fault {
+                Fault::Stall(output) => controller_protocol::Fault::Stall { output },
+            }),
+            outputs: (0..OUTPUTS.len())
+                .map(|index| OutputStatus {
+                    duty_permille: config::to_permille(snapshot.status.duties[index]),
+                    rpm: snapshot.status.rpms[index],
+                })
+                .collect(),
+            overruns: snapshot.overruns,
+            save_failures: board.save_failures(),
+        })),
+        Command::GetCurve { output } => control
+            .lock(|control| control.curve(usize::from(output)))
+            .map(|curve| Reply::Curve(config::curve_to_wire(&curve)))
+            .ok_or(Error::UnknownOutput),
+        Command::SetCurve { output, curve } => config::curve_from_wire(&curve)
+            .ok_or(Error::InvalidCurve)
+            .and_then(|curve| {
+                control
+                    .lock(|control| control.set_curve(usize::from(output), curve))
+                    .map_err(|e| match e {
+                        CurveError::UnknownOutput => Error::UnknownOutput,
+                        CurveError::InvalidCurve => Error::InvalidCurve,
+                    })
+            })
+            .map(|()| Reply::Ack),
+        Command::GetConfig => Ok(Reply::Config(running_config(boot_config, control))),
+        Command::SetConfig { config } => {
+            if config::is_valid(&config) {
+                board.save_config(config).map(|()| Reply::Ack)
+            } else {
+                Err(Error::InvalidConfig)
+            }
+        }
+        Command::SaveConfig => board
+            .save_config(running_config(boot_config, control))
+            .map(|()| Reply::Ack),
+        Command::RebootToBootloader => {
+            board.reboot_to_bootloader();
+            return None;
+        }
+    };
+
+    Some(Response {
+        id: request.id,
+        result,
+    })
+}
+
+/// Settings the firmware is running with, the boot settings with the curves changed since
+fn running_config<P: PwmOutput, T: TachInput, K: Clock>(
+    boot_config: &Config,
+    control: &mut impl Mutex<T = ControlLoop<P, T, K>>,
+) -> Config {
+    Config {
+        curves: control.lock(|control| {
+            control
+                .curves()
+                .map(|curve| config::curve_to_wire(&curve))
+                .collect()
+        }),
+        ..boot_config.clone()
+    }
+}
+
+/// A host connection's request stream, and the settings reported back over it
+pub struct Session {
+    /// Partially received request frame
+    frames: FrameAccumulator,
+    /// Settings the firmware started with
+    boot_config: Config,
+}
+
+impl Session {
+    #[must_use]
+    pub fn new(boot_config: Config) -> Self {
+        Self {
+            frames: FrameAccumulator::new(),
+            boot_config,
+        }
+    }
+
+    /// Answer any requests completed by `received`, passing each response frame to `send`
+    ///
+    /// Frames that cannot be decoded are answered with `Error::Malformed` under id 0.
+    pub fn receive<P: PwmOutput, T: TachInput, K: Clock>(
+        &mut self,
+        received: &[u8],
+        snapshot: &Snapshot,
+        control: &mut impl Mutex<T = ControlLoop<P, T, K>>,
+        board: &mut impl Board,
+        mut send: impl FnMut(&[u8]),
+    ) {
+        // A read can hold the end of one request and the start of another
+        let mut window = received;
+        while !window.is_empty() {
+            window = self.receive_one(window, snapshot, control, board, &mut send);
+        }
+    }
+
+    /// Answer at most one request, returning what is left of `received` after it
+    ///
+    /// For transports that must finish sending a response before taking on the next request. Each call sends at most
+    /// one frame, of at most `MAX_FRAME` bytes.
+    pub fn receive_one<'a, P: PwmOutput, T: TachInput, K: Clock>(
+        &mut self,
+        received: &'a [u8],
+        snapshot: &Snapshot,
+        control: &mut impl Mutex<T = ControlLoop<P, T, K>>,
+        board: &mut impl Board,
+        send: impl FnOnce(&[u8]),
+    ) -> &'a [u8] {
+        let (response, remaining) = match self.frames.feed::<Request>(received) {
+            FeedResult::Consumed => return &[],
+            FeedResult::OverFull(remaining) | FeedResult::DeserError(remaining) => (
+                Some(Response {
+                    id: 0,
+                    result: Err(Error::Malformed),
+                }),
+                remaining,
+            ),
+            FeedResult::Success { data, remaining } => (
+                handle(data, snapshot, control, &self.boot_config, board),
+                remaining,
+            ),
+        };
+
+        let mut frame_buf = [0u8; MAX_FRAME];
+        if let Some(frame) = response
+            .and_then(|response| controller_protocol::encode(&response, &mut frame_buf).ok())
+        {
+            send(frame);
+        }
+        remaining
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SLOT_SIZE;
+    use controller_lib::mock::{MemStorage, MockClock, MockPwm, MockSource, MockTach};
+    use controller_lib::RawReading;
+    use controller_protocol::{Command, Curve, CurvePoint};
+    use core::cell::Cell;
+    use rtic_core::Exclusive;
+
+    struct TestBoard {
+        storage: MemStorage<SLOT_SIZE>,
+        rebooted: bool,
+    }
+
+    impl Board for TestBoard {
+        fn save_config(&mut self, config: Config) -> Result<(), Error> {
+            config::save(&mut self.storage, &config).map_err(|_e| Error::Storage)
+        }
+
+        fn reboot_to_bootloader(&mut self) {
+            self.rebooted = true;
+        }
+    }
+
+    /// Send a request through a session, decoding what comes back
+    fn exchange<P: PwmOutput, T: TachInput, K: Clock>(
+        session: &mut Session,
+        control: &mut ControlLoop<P, T, K>,
+        board: &mut TestBoard,
+        received: &[u8],
+    ) -> heapless::Vec<Response, 4> {
+        let snapshot = control.snapshot();
+        let mut responses = heapless::Vec::new();
+        session.receive(
+            received,
+            &snapshot,
+            &mut Exclusive(control),
+            board,
+            |frame| {
+                let mut frame = heapless::Vec::<u8, MAX_FRAME>::from_slice(frame).unwrap();
+                responses
+                    .push(controller_protocol::decode(&mut frame).unwrap())
+                    .unwrap();
+            },
+        );
+        responses
+    }
+
+    fn request(id: u16, command: Command) -> heapless::Vec<u8, MAX_FRAME> {
+        let mut buf = [0u8; MAX_FRAME];
+        let frame = controller_protocol::encode(&Request { id, command }, &mut buf).unwrap();
+        heapless::Vec::from_slice(frame).unwrap()
+    }
+
+    fn curve(points: &[(i32, u16)]) -> Curve {
+        Curve {
+            points: points
+                .iter()
+                .map(|&(millidegrees, duty_permille)| CurvePoint {
+                    millidegrees,
+                    duty_permille,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn answers_requests_and_saves_curves() {
+        let duty = Cell::new(0);
+        let pulses = Cell::new(0);
+        let now = Cell::new(0);
+        let config = config::defaults();
+        let pwm = MockPwm {
+            duty: &duty,
+            max_duty: crate::control_loop::FULL_DUTY,
+        };
+        let mut control =
+            ControlLoop::new([(pwm, MockTach(&pulses))], MockClock(&now), &config).unwrap();
+        // Mid scale of the 14 bit readings of the defaults
+        let raw = Cell::new(RawReading {
+            sum: 8192 * 32,
+            samples: 32,
+            full_scale: 1 << 14,
+        });
+        control.update(&mut [MockSource(&raw)], 0);
+
+        let mut session = Session::new(config.clone());
+        let mut board = TestBoard {
+            storage: MemStorage::new(),
+            rebooted: false,
+        };
+
+        // Two requests in one read
+        let mut received = request(
+            1,
+            Command::Hello {
+                version: PROTOCOL_VERSION,
+            },
+        );
+        received
+            .extend_from_slice(&request(2, Command::ReadSensors))
+            .unwrap();
+        let responses = exchange(&mut session, &mut control, &mut board, &received);
+        assert_eq!(
+            responses[0].result,
+            Ok(Reply::Hello {
+                version: PROTOCOL_VERSION,
+                sensors: 1,
+                outputs: 1
+            })
+        );
+        assert_eq!(responses[1].id, 2);
+        assert!(
+            matches!(&responses[1].result, Ok(Reply::Sensors(readings)) if readings[0].millidegrees.is_some())
+        );
+
+        let new_curve = curve(&[(30_000, 300), (40_000, 1000)]);
+        let set = request(
+            3,
+            Command::SetCurve {
+                output: 0,
+                curve: new_curve.clone(),
+            },
+        );
+        let responses = exchange(&mut session, &mut control, &mut board, &set);
+        assert_eq!(responses[0].result, Ok(Reply::Ack));
+        let responses = exchange(
+            &mut session,
+            &mut control,
+            &mut board,
+            &request(4, Command::SaveConfig),
+        );
+        assert_eq!(responses[0].result, Ok(Reply::Ack));
+        assert_eq!(
+            config::load(&board.storage),
+            Config {
+                curves: [new_curve].into_iter().collect(),
+                ..config
+            }
+        );
+
+        let responses = exchange(
+            &mut session,
+            &mut control,
+            &mut board,
+            &request(5, Command::RebootToBootloader),
+        );
+        assert!(responses.is_empty());
+        assert!(board.rebooted);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let duty = Cell::new(0);
+        let pulses = Cell::new(0);
+        let now = Cell::new(0);
+        let pwm = MockPwm {
+            duty: &duty,
+            max_duty: crate::control_loop::FULL_DUTY,
+        };
+        let mut control = ControlLoop::new(
+            [(pwm, MockTach(&pulses))],
+            MockClock(&now),
+            &config::defaults(),
+        )
+        .unwrap();
+        let mut session = Session::new(config::defaults());
+        let mut board = TestBoard {
+            storage: MemStorage::new(),
+            rebooted: false,
+        };
+
+        let responses = exchange(&mut session, &mut control, &mut board, &[0x02, 0xff, 0x00]);
+        assert_eq!(responses[0].id, 0);
+        assert_eq!(responses[0].result, Err(Error::Malformed));
+
+        let backwards = request(
+            6,
+            Command::SetCurve {
+                output: 0,
+                curve: curve(&[(40_000, 1000), (30_000, 300)]),
+            },
+        );
+        let responses = exchange(&mut session, &mut control, &mut board, &backwards);
+        assert_eq!(responses[0].result, Err(Error::InvalidCurve));
+
+        let unknown = request(7, Command::GetCurve { output: 1 });
+        let responses = exchange(&mut session, &mut control, &mut board, &unknown);
+        assert_eq!(responses[0].result, Err(Error::UnknownOutput));
+
+        // One at a time, the second request is left for later
+        let mut received = request(8, Command::GetStatus);
+        received.extend_from_slice(&unknown).unwrap();
+        let snapshot = control.snapshot();
+        let mut sent = 0;
+        let remaining = session.receive_one(
+            &received,
+            &snapshot,
+            &mut Exclusive(&mut control),
+            &mut board,
+            |_frame| sent += 1,
+        );
+        assert_eq!(sent, 1);
+        assert_eq!(remaining, &unknown[..]);
+    }
+}
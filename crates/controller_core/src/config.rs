@@ -0,0 +1,279 @@
+//! Persistent settings, and turning them into what the control loop runs with
+//!
+//! The settings are a `controller_protocol::Config`, so the host reads and writes the same thing that is stored. Each
+//! storage slot holds one `nvstore` record, written alternately. If neither holds a valid record the compile-time
+//! defaults in `sensors` and `control_loop` are used.
+
+use crate::{
+    control_loop::{self, FULL_DUTY},
+    sensors::{self, ADC_BITS, MAX_OVERSAMPLE_BITS, MAX_SAMPLES_PER_SENSOR},
+};
+use controller_lib::{
+    dsp::{Decimator, Filter, LowPass, Median, MovingAverage},
+    nvstore::{self, StoreError},
+    thermistor::{ConversionError, LinearFit, Model, Placement, Response},
+    AdcConversion, Degrees, NvStorage, PiecewiseCurve, Thermistor,
+};
+use controller_protocol::{Config, Curve, CurvePoint, DutyLimits, ReadingFilter, SensorModel};
+
+/// Bumped whenever the stored encoding of `Config` changes, records of other versions fall back to defaults
+const CONFIG_VERSION: u16 = 4;
+
+/// Size of a storage slot, a flash sector on the RP2040
+pub const SLOT_SIZE: usize = 4096;
+
+/// Room for the longest window a `ReadingFilter` can ask for
+const MAX_FILTER_LEN: usize = controller_protocol::MAX_FILTER_LEN as usize;
+
+/// Settings to boot with, the newest valid stored record or the defaults
+pub fn load(storage: &impl NvStorage) -> Config {
+    nvstore::load(storage, CONFIG_VERSION)
+        .and_then(|record| controller_protocol::decode_unframed(record.payload).ok())
+        .filter(is_valid)
+        .unwrap_or_else(defaults)
+}
+
+/// Write settings to the slot not holding the newest record
+///
+/// # Errors
+/// * `TooLarge` if the settings do not fit in a slot of `SLOT_SIZE`
+/// * `Storage` if the storage fails the write
+pub fn save<S: NvStorage>(storage: &mut S, config: &Config) -> Result<(), StoreError<S::Error>> {
+    // Encoded straight into the storage's buffer behind the record header, a slot is too much to put on the stack
+    let payload = storage
+        .buffer()
+        .get_mut(nvstore::HEADER_LEN..)
+        .ok_or(StoreError::TooLarge)?;
+    let len = controller_protocol::encode_unframed(config, payload)
+        .map_err(|_e| StoreError::TooLarge)?
+        .len();
+
+    nvstore::store_in_place(storage, CONFIG_VERSION, len)
+}
+
+/// Settings built from the compile-time tables in `sensors` and `control_loop`
+pub fn defaults() -> Config {
+    Config {
+        samples_per_sensor: sensors::DEFAULT_SAMPLES_PER_SENSOR,
+        oversample_bits: sensors::DEFAULT_OVERSAMPLE_BITS,
+        sensors: sensors::SENSORS.iter().map(|sensor| sensor.model).collect(),
+        filter: sensors::DEFAULT_FILTER,
+        curves: control_loop::OUTPUTS
+            .iter()
+            .map(|output| {
+                // Default points are in the firmware, so building the curve can only fail during development
+                PiecewiseCurve::new(output.curve)
+                    .map_or_else(|_e| Curve::default(), |curve| curve_to_wire(&curve))
+            })
+            .collect(),
+        limits: control_loop::OUTPUTS
+            .iter()
+            .map(|output| DutyLimits {
+                min_permille: to_permille(output.min_duty),
+                max_permille: to_permille(output.max_duty),
+            })
+            .collect(),
+    }
+}
+
+/// Whether the controller can run with these settings
+pub fn is_valid(config: &Config) -> bool {
+    (1..=MAX_SAMPLES_PER_SENSOR).contains(&usize::from(config.samples_per_sensor))
+        && config.oversample_bits <= MAX_OVERSAMPLE_BITS
+        && usize::from(config.samples_per_sensor)
+            >= Decimator::new(config.oversample_bits).min_samples()
+        && config.sensors.len() == sensors::SENSORS.len()
+        && config
+            .sensors
+            .iter()
+            .all(|model| conversion(model, config.oversample_bits).is_some())
+        && match config.filter {
+            ReadingFilter::None => true,
+            ReadingFilter::MovingAverage { len } | ReadingFilter::Median { len } => {
+                (1..=MAX_FILTER_LEN).contains(&usize::from(len))
+            }
+            ReadingFilter::LowPass { alpha } => alpha > 0,
+        }
+        && config.curves.len() == control_loop::OUTPUTS.len()
+        && config
+            .curves
+            .iter()
+            .all(|curve| curve_from_wire(curve).is_some())
+        && config.limits.len() == control_loop::OUTPUTS.len()
+        && config
+            .limits
+            .iter()
+            .all(|limits| limits_from_wire(limits).is_some())
+}
+
+/// ADC conversion for a configured sensor
+#[derive(Copy, Clone)]
+pub enum Conversion {
+    /// Only takes 12 bit readings, oversampled ones are shifted back down
+    LinearFit {
+        extra_bits: u8,
+    },
+    Thermistor(Thermistor),
+}
+
+impl AdcConversion for Conversion {
+    fn convert(&self, counts: u32) -> Result<Degrees, ConversionError> {
+        match self {
+            Self::LinearFit { extra_bits } => LinearFit.convert(counts >> extra_bits),
+            Self::Thermistor(thermistor) => thermistor.convert(counts),
+        }
+    }
+
+    fn open_at_top_rail(&self) -> bool {
+        match self {
+            Self::LinearFit { .. } => LinearFit.open_at_top_rail(),
+            Self::Thermistor(thermistor) => thermistor.open_at_top_rail(),
+        }
+    }
+}
+
+/// The conversion for a sensor model taking readings oversampled by `oversample_bits`, None if its parameters cannot
+/// describe a real part
+pub fn conversion(model: &SensorModel, oversample_bits: u8) -> Option<Conversion> {
+    match *model {
+        SensorModel::LinearFit => Some(Conversion::LinearFit {
+            extra_bits: oversample_bits,
+        }),
+        SensorModel::Beta {
+            r0_ohms,
+            t0_millidegrees,
+            beta,
+            ptc,
+            series_ohms,
+            high_side,
+        } => {
+            if r0_ohms == 0 || beta == 0 || series_ohms == 0 {
+                return None;
+            }
+
+            let model = Model::Beta {
+                r0: r0_ohms,
+                t0: Degrees::from_millidegrees(t0_millidegrees),
+                beta,
+                response: if ptc { Response::Ptc } else { Response::Ntc },
+            };
+            Some(thermistor(model, series_ohms, high_side, oversample_bits))
+        }
+        SensorModel::SteinhartHart {
+            a,
+            b,
+            c,
+            series_ohms,
+            high_side,
+        } => {
+            // Without the B term the resistance barely moves the temperature
+            if b == 0 || series_ohms == 0 {
+                return None;
+            }
+
+            let model = Model::SteinhartHart { a, b, c };
+            Some(thermistor(model, series_ohms, high_side, oversample_bits))
+        }
+    }
+}
+
+fn thermistor(model: Model, series_ohms: u32, high_side: bool, oversample_bits: u8) -> Conversion {
+    let placement = if high_side {
+        Placement::HighSide
+    } else {
+        Placement::LowSide
+    };
+
+    Conversion::Thermistor(
+        Thermistor::new(model, series_ohms)
+            .with_placement(placement)
+            .with_adc_bits(ADC_BITS + oversample_bits),
+    )
+}
+
+/// Smoothing of one sensor's converted readings
+pub enum SensorFilter {
+    None,
+    MovingAverage(MovingAverage<Degrees, MAX_FILTER_LEN>),
+    LowPass(LowPass<Degrees>),
+    Median(Median<Degrees, MAX_FILTER_LEN>),
+}
+
+impl Filter for SensorFilter {
+    type Sample = Degrees;
+
+    fn update(&mut self, val: Degrees) -> Degrees {
+        match self {
+            Self::None => val,
+            Self::MovingAverage(filter) => filter.update(val),
+            Self::LowPass(filter) => filter.update(val),
+            Self::Median(filter) => filter.update(val),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Self::None => {}
+            Self::MovingAverage(filter) => filter.reset(),
+            Self::LowPass(filter) => filter.reset(),
+            Self::Median(filter) => filter.reset(),
+        }
+    }
+}
+
+/// A fresh filter of the configured kind
+pub fn filter(kind: ReadingFilter) -> SensorFilter {
+    match kind {
+        ReadingFilter::None => SensorFilter::None,
+        ReadingFilter::MovingAverage { len } => {
+            SensorFilter::MovingAverage(MovingAverage::new().with_len(len.into()))
+        }
+        ReadingFilter::LowPass { alpha } => SensorFilter::LowPass(LowPass::new(alpha)),
+        ReadingFilter::Median { len } => SensorFilter::Median(Median::new().with_len(len.into())),
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+pub fn to_permille(duty: u16) -> u16 {
+    ((u32::from(duty) * 1000 + u32::from(FULL_DUTY) / 2) / u32::from(FULL_DUTY)) as u16
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn from_permille(permille: u16) -> Option<u16> {
+    (permille <= 1000).then(|| (u32::from(permille) * u32::from(FULL_DUTY) / 1000) as u16)
+}
+
+/// The curve with duties in permille
+pub fn curve_to_wire(curve: &PiecewiseCurve) -> Curve {
+    Curve {
+        points: curve
+            .points()
+            .iter()
+            .map(|point| CurvePoint {
+                millidegrees: point.temp.to_millidegrees(),
+                duty_permille: to_permille(point.duty),
+            })
+            .collect(),
+    }
+}
+
+/// The curve with duties in PWM ticks, None if the points do not make a valid curve
+pub fn curve_from_wire(curve: &Curve) -> Option<PiecewiseCurve> {
+    let mut points = heapless::Vec::<_, { controller_lib::fancurve::MAX_CURVE_POINTS }>::new();
+    for point in &curve.points {
+        let point = controller_lib::CurvePoint::new(
+            Degrees::from_millidegrees(point.millidegrees),
+            from_permille(point.duty_permille)?,
+        );
+        points.push(point).ok()?;
+    }
+
+    PiecewiseCurve::new(&points).ok()
+}
+
+/// Duty limits in PWM ticks as `(min, max)`, None if out of range or crossed
+pub fn limits_from_wire(limits: &DutyLimits) -> Option<(u16, u16)> {
+    let min = from_permille(limits.min_permille)?;
+    let max = from_permille(limits.max_permille)?;
+    (min <= max).then_some((min, max))
+}
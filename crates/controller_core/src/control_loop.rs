@@ -0,0 +1,223 @@
+//! Fan control, from sensor readings through curves or PIDs to the PWM outputs
+//!
+//! Runs once per batch of sensor readings, with tach and stall detection alongside. The board provides the PWM
+//! outputs, tach inputs and clock, see `controller_lib::hal`.
+
+use crate::{
+    config::{self, Conversion, SensorFilter},
+    sensors::{SENSOR_COUNT, SENSOR_MAX, SENSOR_MIN, SENSOR_STUCK_TIMEOUT_US},
+};
+use controller_lib::pipeline::{self, OutputChain, SensorChain};
+use controller_lib::{
+    Clock, CurvePoint, Degrees, OutputStage, Pid, PiecewiseCurve, PwmOutput, SensorHealth,
+    StallDetector, TachInput, Tachometer, TemperatureSource, VirtualSensor,
+};
+
+/// PWM counter ticks at 100% duty, the 125 MHz system clock over the 25 kHz fan PWM
+pub const FULL_DUTY: u16 = 5000;
+
+/// Duty cycle in PWM counter ticks for a percentage
+#[allow(clippy::cast_possible_truncation)]
+const fn duty_percent(percent: u32) -> u16 {
+    (FULL_DUTY as u32 * percent / 100) as u16
+}
+
+/// Readings derived from `SENSORS`, e.g. `VirtualSensor::Difference(0, 1)` for water minus ambient with the water
+/// probe first. Sources index into `SENSORS`.
+pub static VIRTUAL_SENSORS: [VirtualSensor; 0] = [];
+
+/// A fan or pump header driven by a curve from one of the sensors
+pub struct OutputConfig {
+    /// Fan header, 0-3
+    pub header: usize,
+    /// Reading the curve or PID follows, an index into `SENSORS` or past its end into `VIRTUAL_SENSORS`
+    pub sensor: usize,
+    /// Default curve points, until a curve is stored in flash. Can be changed at runtime with `ControlLoop::set_curve`.
+    /// Duties are in PWM ticks, and the output runs at full duty while its sensor has no usable reading.
+    pub curve: &'static [CurvePoint],
+    /// Default duty clamps in PWM ticks, until limits are stored in flash. Applied after the curve or PID and the
+    /// output stage, whatever curve is set at runtime.
+    pub min_duty: u16,
+    pub max_duty: u16,
+    /// Tach pulses per revolution, 2 for most PC fans and pumps. 0 if the header's tach input is not connected.
+    pub pulses_per_rev: u8,
+    /// Hysteresis and ramp limits between the curve and the PWM, in PWM ticks
+    pub stage: OutputStage,
+    /// If set, the output holds its sensor at the PID's setpoint instead of following `curve`
+    pub pid: Option<Pid>,
+}
+
+/// Number of entries in `OUTPUTS`
+pub const OUTPUT_COUNT: usize = 1;
+
+/// Outputs, each with its own curve
+pub static OUTPUTS: [OutputConfig; OUTPUT_COUNT] = [OutputConfig {
+    header: 0,
+    sensor: 0,
+    curve: &[
+        // Most fans stall somewhere below 20%, never command less than that
+        CurvePoint::new(Degrees::from_int(25), duty_percent(20)),
+        CurvePoint::new(Degrees::from_int(45), FULL_DUTY),
+    ],
+    // Never stopped, whatever curve the host sets
+    min_duty: duty_percent(20),
+    max_duty: FULL_DUTY,
+    pulses_per_rev: 2,
+    // Quick to respond to heat, slow and quiet to wind down
+    stage: OutputStage::new(
+        duty_percent(1),
+        duty_percent(3),
+        duty_percent(20),
+        duty_percent(5),
+    ),
+    pid: None,
+}];
+
+/// RPM measurement window
+const TACH_WINDOW_US: u64 = 1_000_000;
+
+// Stall detection, applied to every output with a tach input
+const STALL_DUTY: u16 = duty_percent(30);
+const STALL_RPM: u32 = 200;
+const STALL_TIMEOUT_US: u64 = 5_000_000;
+
+type Pipeline<P, T, K> =
+    controller_lib::Pipeline<Conversion, SensorFilter, P, T, K, SENSOR_COUNT, OUTPUT_COUNT>;
+
+/// State of the whole loop as of its latest update
+#[derive(Copy, Clone)]
+pub struct Snapshot {
+    /// Duties in PWM ticks
+    pub status: pipeline::Status<SENSOR_COUNT, OUTPUT_COUNT>,
+    /// Times the board dropped samples, see `ControlLoop::update`
+    pub overruns: u32,
+    /// Updates so far, wraps
+    pub sequence: u32,
+}
+
+/// The control pipeline over `SENSORS` and `OUTPUTS`, updated on every batch of readings
+pub struct ControlLoop<P, T, K> {
+    pipeline: Pipeline<P, T, K>,
+    snapshot: Snapshot,
+}
+
+/// Reasons `ControlLoop::set_curve` can refuse a curve
+pub enum CurveError {
+    UnknownOutput,
+    InvalidCurve,
+}
+
+impl<P: PwmOutput, T: TachInput, K: Clock> ControlLoop<P, T, K> {
+    /// Take over the fans, running them flat out until the first batch of readings arrives
+    ///
+    /// `fans` must be the PWM output and tach input of each of `OUTPUTS` in the same order, the tach input is unused
+    /// for outputs without one. `config` must pass `config::is_valid`.
+    pub fn new(
+        fans: impl IntoIterator<Item = (P, T)>,
+        clock: K,
+        config: &controller_protocol::Config,
+    ) -> Option<Self> {
+        if !config::is_valid(config) {
+            return None;
+        }
+
+        let sensors = config
+            .sensors
+            .iter()
+            .map(|model| {
+                Some(SensorChain::new(
+                    config::conversion(model, config.oversample_bits)?,
+                    config::filter(config.filter),
+                    SensorHealth::new(SENSOR_MIN, SENSOR_MAX, SENSOR_STUCK_TIMEOUT_US),
+                ))
+            })
+            .collect::<Option<heapless::Vec<_, SENSOR_COUNT>>>()?;
+
+        let mut outputs = heapless::Vec::<_, OUTPUT_COUNT>::new();
+        for ((((fan, tach), output), curve), limits) in fans
+            .into_iter()
+            .zip(OUTPUTS.iter())
+            .zip(config.curves.iter())
+            .zip(config.limits.iter())
+        {
+            let (min_duty, max_duty) = config::limits_from_wire(limits)?;
+            let mut chain = OutputChain::new(
+                fan,
+                output.sensor,
+                config::curve_from_wire(curve)?,
+                output.stage,
+                StallDetector::new(STALL_DUTY, STALL_RPM, STALL_TIMEOUT_US),
+            )
+            .with_limits(min_duty, max_duty);
+            if output.pulses_per_rev > 0 {
+                chain =
+                    chain.with_tach(tach, Tachometer::new(output.pulses_per_rev, TACH_WINDOW_US));
+            }
+            if let Some(pid) = output.pid {
+                chain = chain.with_pid(pid);
+            }
+            outputs.push(chain).ok()?;
+        }
+
+        let pipeline = Pipeline::new(
+            sensors.into_array().ok()?,
+            &VIRTUAL_SENSORS,
+            outputs.into_array().ok()?,
+            clock,
+        )?;
+        let snapshot = Snapshot {
+            status: *pipeline.status(),
+            overruns: 0,
+            sequence: 0,
+        };
+        Some(Self { pipeline, snapshot })
+    }
+
+    /// State as of the latest update
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot
+    }
+
+    /// Drive the outputs from a new batch of readings of `SENSORS`, `overruns` being the count the board keeps of
+    /// times it had to drop samples
+    pub fn update(
+        &mut self,
+        readings: &mut [impl TemperatureSource; SENSOR_COUNT],
+        overruns: u32,
+    ) -> Snapshot {
+        self.snapshot = Snapshot {
+            status: *self.pipeline.update(readings),
+            overruns,
+            sequence: self.snapshot.sequence.wrapping_add(1),
+        };
+        self.snapshot
+    }
+
+    /// Current curve of the output at `index` into `OUTPUTS`
+    pub fn curve(&self, index: usize) -> Option<PiecewiseCurve> {
+        self.pipeline.output(index).map(|output| *output.curve())
+    }
+
+    /// Current curves of all of `OUTPUTS`
+    pub fn curves(&self) -> impl Iterator<Item = PiecewiseCurve> + '_ {
+        (0..OUTPUT_COUNT).filter_map(|index| self.curve(index))
+    }
+
+    /// Replace the curve of the output at `index` into `OUTPUTS`, taking effect from the next update
+    ///
+    /// # Errors
+    /// * `UnknownOutput` if there is no output at `index`
+    /// * `InvalidCurve` if the curve exceeds full duty
+    pub fn set_curve(&mut self, index: usize, curve: PiecewiseCurve) -> Result<(), CurveError> {
+        if curve.max_duty() > FULL_DUTY {
+            return Err(CurveError::InvalidCurve);
+        }
+
+        let output = self
+            .pipeline
+            .output_mut(index)
+            .ok_or(CurveError::UnknownOutput)?;
+        output.set_curve(curve);
+        Ok(())
+    }
+}
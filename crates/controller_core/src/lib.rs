@@ -0,0 +1,14 @@
+//! The fan controller's board independent logic, shared by the firmware and its host emulator
+//!
+//! A board samples the sensors in `sensors`, drives the outputs of `control_loop::OUTPUTS` through a `ControlLoop`,
+//! and answers the host through a `commands::Session`. Everything it has to provide for that is behind the
+//! `controller_lib::hal` traits and `commands::Board`.
+#![no_std]
+
+pub mod commands;
+pub mod config;
+pub mod control_loop;
+pub mod sensors;
+
+pub use commands::{Board, Session};
+pub use control_loop::{ControlLoop, Snapshot};
@@ -0,0 +1,38 @@
+//! The controller's temperature sensors and how they are sampled
+
+use controller_lib::Degrees;
+use controller_protocol::{ReadingFilter, SensorModel};
+
+/// Resolution of a single ADC sample
+pub const ADC_BITS: u8 = 12;
+
+/// A thermistor input on one of the ADC channels
+pub struct SensorConfig {
+    /// ADC input, 0-3 for GPIO26-29
+    pub channel: u8,
+    /// Default ADC to temperature conversion, until one is stored in flash
+    pub model: SensorModel,
+}
+
+/// Number of entries in `SENSORS`
+pub const SENSOR_COUNT: usize = 1;
+
+/// Sensors sampled round-robin, in ascending channel order as that is the order the ADC converts them in
+pub static SENSORS: [SensorConfig; SENSOR_COUNT] = [SensorConfig {
+    channel: 0,
+    model: SensorModel::LinearFit,
+}];
+
+// Samples averaged for each reading, per sensor. The ADC samples at a fixed rate, so more samples means fewer readings.
+pub const MAX_SAMPLES_PER_SENSOR: usize = 256;
+pub const DEFAULT_SAMPLES_PER_SENSOR: u16 = 32;
+// Resolution gained by averaging, see `dsp::Decimator`
+pub const MAX_OVERSAMPLE_BITS: u8 = 4;
+pub const DEFAULT_OVERSAMPLE_BITS: u8 = 2;
+/// Smoothing of converted readings, until one is stored in flash
+pub const DEFAULT_FILTER: ReadingFilter = ReadingFilter::None;
+
+// Sensor health, readings outside of this range or frozen for this long are not trusted
+pub const SENSOR_MIN: Degrees = Degrees::from_int(-10);
+pub const SENSOR_MAX: Degrees = Degrees::from_int(100);
+pub const SENSOR_STUCK_TIMEOUT_US: u64 = 10_000_000;
@@ -2,7 +2,7 @@ use core::convert::Into;
 use core::fmt::Display;
 
 /// Type for holding temperature readings with context, in a fixed point manner
-#[derive(PartialEq, Eq, PartialOrd, Copy, Clone, Default)]
+#[derive(PartialEq, Eq, PartialOrd, Copy, Clone, Default, Debug)]
 // Valid representation range: -128-128C
 // so 8 integer bits. 23 fractional bits
 pub struct Degrees(pub i32);
@@ -12,6 +12,21 @@ impl Degrees {
     pub const fn from_int(val: i32) -> Self {
         Self(val << 12)
     }
+
+    /// Temperature in thousandths of a degree, rounded to the nearest
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn to_millidegrees(self) -> i32 {
+        // The full i32 range of F12 is well within i32 millidegrees
+        ((self.0 as i64 * 1000 + (1 << 11)) >> 12) as i32
+    }
+
+    /// Create from thousandths of a degree, rounded to the nearest F12 step
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn from_millidegrees(val: i32) -> Self {
+        Self(((val as i64) * (1 << 12) + 500).div_euclid(1000) as i32)
+    }
 }
 
 // Conversion of ADC readings to degrees is specific to ADC config and circuit implementation, provide here a conversion that specifies our circuit
@@ -89,9 +104,26 @@ impl From<u32> for Degrees {
     }
 }
 
+/// Most decimal places printed, F12 resolves to about 0.00025 so further digits only pad
+const MAX_PRECISION: usize = 9;
+
 impl Display for Degrees {
+    /// Whole degrees by default, `{:.N}` for N decimal places. Rounds to nearest, halves away from zero.
+    #[allow(clippy::cast_possible_truncation)]
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{}", self.0 >> 12)
+        let precision = f.precision().unwrap_or(0).min(MAX_PRECISION);
+        let scale = 10u64.pow(precision as u32);
+
+        // Round the magnitude so negative values mirror positive ones rather than flooring towards -inf
+        let scaled = (u64::from(self.0.unsigned_abs()) * scale + (1 << 11)) >> 12;
+        let (whole, frac) = (scaled / scale, scaled % scale);
+        let sign = if self.0 < 0 && scaled != 0 { "-" } else { "" };
+
+        if precision == 0 {
+            write!(f, "{sign}{whole}")
+        } else {
+            write!(f, "{sign}{whole}.{frac:0precision$}")
+        }
     }
 }
 
@@ -100,3 +132,54 @@ impl Display for Degrees {
 //         defmt::write!(fmt, "{}", self.0);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn millidegrees_round_trip() {
+        assert_eq!(Degrees::from_int(25).to_millidegrees(), 25_000);
+        assert_eq!(Degrees::from_int(-10).to_millidegrees(), -10_000);
+        assert_eq!(
+            Degrees::from_millidegrees(32_500),
+            Degrees((32 << 12) + 2048)
+        );
+        assert_eq!(Degrees::from_millidegrees(-1).to_millidegrees(), -1);
+
+        for milli in [-40_123, -1_500, 0, 999, 37_250, 100_001] {
+            assert_eq!(Degrees::from_millidegrees(milli).to_millidegrees(), milli);
+        }
+    }
+
+    #[test]
+    fn display_precision() {
+        extern crate std;
+        use std::format;
+
+        let temp = Degrees::from_millidegrees(32_468);
+        assert_eq!(format!("{temp}"), "32");
+        assert_eq!(format!("{temp:.1}"), "32.5");
+        assert_eq!(format!("{temp:.2}"), "32.47");
+        assert_eq!(format!("{temp:.3}"), "32.468");
+        assert_eq!(format!("{:.1}", Degrees::from_int(25)), "25.0");
+    }
+
+    #[test]
+    fn display_negative_rounding() {
+        extern crate std;
+        use std::format;
+
+        // -0.25 used to print as -1
+        let temp = Degrees::from_millidegrees(-250);
+        assert_eq!(format!("{temp}"), "0");
+        assert_eq!(format!("{temp:.1}"), "-0.3");
+        assert_eq!(format!("{temp:.2}"), "-0.25");
+        assert_eq!(
+            format!("{:.2}", Degrees::from_millidegrees(-10_468)),
+            "-10.47"
+        );
+        assert_eq!(format!("{}", Degrees::from_millidegrees(-10_500)), "-11");
+        assert_eq!(format!("{:.1}", Degrees(i32::MIN)), "-524288.0");
+    }
+}
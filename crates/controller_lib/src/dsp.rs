@@ -1,46 +1,423 @@
+//! Noise rejection on sampled signals: filters sharing the `Filter` trait, and oversampling for extra resolution
+
+use super::Degrees;
 use core::default::Default;
 
-/// Implements a 32 point moving average filter to reject some high frequency noise
-pub struct MovingAverage<T> {
-    buffer: [T; 32],
+/// A filter fed one sample at a time
+pub trait Filter {
+    type Sample;
+
+    /// Feed a sample, returning the filtered value
+    fn update(&mut self, val: Self::Sample) -> Self::Sample;
+
+    /// Forget all history, the next sample starts the filter afresh
+    fn reset(&mut self);
+}
+
+/// Sample types the filters can work on, signed or not
+pub trait Sample: Copy + Default {
+    fn to_i64(self) -> i64;
+    /// Convert back from a value the filter computed, which is always within the range of samples it was fed
+    fn from_i64(val: i64) -> Self;
+}
+
+macro_rules! impl_sample {
+    ($($t:ty),*) => {
+        $(
+            impl Sample for $t {
+                fn to_i64(self) -> i64 {
+                    i64::from(self)
+                }
+
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                fn from_i64(val: i64) -> Self {
+                    val as Self
+                }
+            }
+        )*
+    };
+}
+
+impl_sample!(u16, u32, i32);
+
+impl Sample for Degrees {
+    fn to_i64(self) -> i64 {
+        i64::from(self.0)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn from_i64(val: i64) -> Self {
+        Self(val as i32)
+    }
+}
+
+/// Implements an `N` point moving average filter to reject some high frequency noise
+///
+/// Until `N` samples have arrived the average is over the samples so far
+pub struct MovingAverage<T, const N: usize = 32> {
+    buffer: [T; N],
+    /// Window in use, up to `N`
+    len: usize,
     index: usize,
-    accumulator: u32,
+    filled: usize,
+    accumulator: i64,
 }
 
-impl<T> MovingAverage<T>
-where
-    T: Copy + Into<u32> + From<u32> + Default,
-{
+impl<T: Sample, const N: usize> MovingAverage<T, N> {
     /// Create a moving average based filter to reject some high frequency noise
     #[must_use]
     pub fn new() -> Self {
         Self {
-            buffer: [Default::default(); 32],
+            buffer: [Default::default(); N],
+            len: N,
             index: 0,
+            filled: 0,
             accumulator: 0,
         }
     }
 
+    /// Average over the last `len` samples rather than `N`, clamped to 1..=N
+    #[must_use]
+    pub fn with_len(mut self, len: usize) -> Self {
+        self.len = len.clamp(1, N);
+        self
+    }
+}
+
+impl<T: Sample, const N: usize> Filter for MovingAverage<T, N> {
+    type Sample = T;
+
     /// Circular buffer with accumulator for moving average calculation
-    // the cast is OK, because this is a 32 bit platform
-    #[allow(clippy::cast_possible_truncation)]
-    pub fn update(&mut self, val: T) -> T {
-        self.accumulator -= self.buffer[self.index].into();
+    #[allow(clippy::cast_possible_wrap)]
+    fn update(&mut self, val: T) -> T {
+        if self.filled == self.len {
+            self.accumulator -= self.buffer[self.index].to_i64();
+        } else {
+            self.filled += 1;
+        }
         self.buffer[self.index] = val;
-        self.accumulator += self.buffer[self.index].into();
-        self.index += 1;
-        self.index %= self.buffer.len();
+        self.accumulator += val.to_i64();
+        self.index = (self.index + 1) % self.len;
+
+        // Return new value, floored for consistency between signed and unsigned samples
+        T::from_i64(self.accumulator.div_euclid(self.filled as i64))
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new().with_len(self.len);
+    }
+}
+
+impl<T: Sample, const N: usize> Default for MovingAverage<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// First order IIR low-pass, `y += alpha * (x - y)`
+pub struct LowPass<T> {
+    /// F16, out of 65536
+    alpha: u16,
+    /// F16 copy of the output, so small steps are not lost to rounding
+    state: Option<i64>,
+    _sample: core::marker::PhantomData<T>,
+}
+
+impl<T: Sample> LowPass<T> {
+    /// Create a low-pass with smoothing factor `alpha / 65536`, smaller is smoother
+    #[must_use]
+    pub const fn new(alpha: u16) -> Self {
+        Self {
+            alpha,
+            state: None,
+            _sample: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<T: Sample> Filter for LowPass<T> {
+    type Sample = T;
+
+    /// The first sample passes straight through
+    fn update(&mut self, val: T) -> T {
+        let x = val.to_i64() << 16;
+        let y = self
+            .state
+            .map_or(x, |y| y + (((x - y) * i64::from(self.alpha)) >> 16));
+        self.state = Some(y);
+
+        T::from_i64((y + (1 << 15)) >> 16)
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+/// Median of the last `N` samples, rejects spikes shorter than half the window outright
+pub struct Median<T, const N: usize> {
+    buffer: [T; N],
+    /// Window in use, up to `N`
+    len: usize,
+    index: usize,
+    filled: usize,
+}
+
+impl<T: Sample, const N: usize> Median<T, N> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            buffer: [Default::default(); N],
+            len: N,
+            index: 0,
+            filled: 0,
+        }
+    }
+
+    /// Take the median of the last `len` samples rather than `N`, clamped to 1..=N
+    #[must_use]
+    pub fn with_len(mut self, len: usize) -> Self {
+        self.len = len.clamp(1, N);
+        self
+    }
+}
+
+impl<T: Sample, const N: usize> Filter for Median<T, N> {
+    type Sample = T;
+
+    /// Until `N` samples have arrived the median is of the samples so far, the lower middle one for an even count
+    fn update(&mut self, val: T) -> T {
+        self.buffer[self.index] = val;
+        self.index = (self.index + 1) % self.len;
+        self.filled = (self.filled + 1).min(self.len);
+
+        // Windows are a handful of samples, sorting a copy is cheaper than maintaining order
+        let mut sorted = [0i64; N];
+        for (sorted, sample) in sorted.iter_mut().zip(&self.buffer[..self.filled]) {
+            *sorted = sample.to_i64();
+        }
+        let sorted = &mut sorted[..self.filled];
+        sorted.sort_unstable();
+
+        T::from_i64(sorted[(self.filled - 1) / 2])
+    }
 
-        // Return new value
-        (self.accumulator / (self.buffer.len() as u32)).into()
+    fn reset(&mut self) {
+        *self = Self::new().with_len(self.len);
     }
 }
 
-impl<T> Default for MovingAverage<T>
-where
-    T: Copy + Into<u32> + From<u32> + Default,
-{
+impl<T: Sample, const N: usize> Default for Median<T, N> {
     fn default() -> Self {
         Self::new()
     }
 }
+
+/// Codes the RP2040 ADC returns far more often than their neighbours, from its differential nonlinearity at these
+/// transitions (datasheet erratum RP2040-E11)
+pub const RP2040_DNL_CODES: [u16; 4] = [512, 1536, 2560, 3584];
+
+/// Oversampling and decimation, averaging many samples of a noisy ADC into a reading with more bits than it has
+///
+/// Each extra bit takes four times the samples, and only materialises if there is about an LSB of noise to dither
+/// the input across codes.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Decimator {
+    extra_bits: u8,
+    discard: &'static [u16],
+}
+
+impl Decimator {
+    /// Create a decimator adding `extra_bits` to the samples' resolution, discarding nothing
+    #[must_use]
+    pub const fn new(extra_bits: u8) -> Self {
+        Self {
+            extra_bits,
+            discard: &[],
+        }
+    }
+
+    /// Discard samples that are any of `codes`, e.g. `RP2040_DNL_CODES`
+    #[must_use]
+    pub const fn discarding(mut self, codes: &'static [u16]) -> Self {
+        self.discard = codes;
+        self
+    }
+
+    #[must_use]
+    pub const fn extra_bits(&self) -> u8 {
+        self.extra_bits
+    }
+
+    /// Fewest samples that support the extra bits, `4 ^ extra_bits`
+    #[must_use]
+    pub const fn min_samples(&self) -> usize {
+        1 << (2 * self.extra_bits)
+    }
+
+    /// Sum the samples, returning the sum scaled up by the extra bits and how many samples went into it
+    ///
+    /// If every sample is a discarded code they are all kept, an input sitting on one of them is still a reading. The
+    /// scaled sum must fit a `u32`, 65536 12 bit samples with 4 extra bits.
+    pub fn accumulate(&self, samples: impl IntoIterator<Item = u16>) -> (u32, u32) {
+        let (mut kept, mut kept_count) = (0u32, 0u32);
+        let (mut all, mut all_count) = (0u32, 0u32);
+        for sample in samples {
+            all += u32::from(sample);
+            all_count += 1;
+            if !self.discard.contains(&sample) {
+                kept += u32::from(sample);
+                kept_count += 1;
+            }
+        }
+
+        let (sum, count) = if kept_count == 0 {
+            (all, all_count)
+        } else {
+            (kept, kept_count)
+        };
+        (sum << self.extra_bits, count)
+    }
+
+    /// The rounded average of the samples with `extra_bits` more bits than them, None if there are no samples
+    pub fn decimate(&self, samples: impl IntoIterator<Item = u16>) -> Option<u32> {
+        let (sum, count) = self.accumulate(samples);
+        (count > 0).then(|| (sum + count / 2) / count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moving_average_length() {
+        let mut filter = MovingAverage::<u32, 4>::new();
+
+        assert_eq!(filter.update(100), 100);
+        assert_eq!(filter.update(200), 150);
+        filter.update(300);
+        assert_eq!(filter.update(400), 250);
+        // First sample drops out of the window
+        assert_eq!(filter.update(500), 350);
+    }
+
+    #[test]
+    fn shorter_windows() {
+        let mut average = MovingAverage::<u32, 8>::new().with_len(2);
+        let mut median = Median::<u32, 8>::new().with_len(3);
+
+        for val in [100, 900, 200, 300] {
+            average.update(val);
+            median.update(val);
+        }
+        assert_eq!(average.update(500), 400);
+        assert_eq!(median.update(250), 250);
+
+        // The window survives a reset
+        average.reset();
+        for val in [10, 20] {
+            average.update(val);
+        }
+        assert_eq!(average.update(40), 30);
+    }
+
+    #[test]
+    fn moving_average_negative_degrees() {
+        let mut filter = MovingAverage::<Degrees, 8>::new();
+
+        for temp in [-10, -12, -8, -10] {
+            filter.update(Degrees::from_int(temp));
+        }
+        assert_eq!(
+            filter.update(Degrees::from_int(-10)),
+            Degrees::from_int(-10)
+        );
+
+        filter.reset();
+        assert_eq!(filter.update(Degrees::from_int(5)), Degrees::from_int(5));
+    }
+
+    #[test]
+    fn low_pass_converges() {
+        // alpha of 1/4
+        let mut filter = LowPass::<Degrees>::new(16384);
+
+        assert_eq!(filter.update(Degrees::from_int(20)), Degrees::from_int(20));
+        assert_eq!(filter.update(Degrees::from_int(28)), Degrees::from_int(22));
+        assert_eq!(
+            filter.update(Degrees::from_int(28)),
+            Degrees((23 << 12) + 2048)
+        );
+
+        for _ in 0..100 {
+            filter.update(Degrees::from_int(-5));
+        }
+        assert_eq!(filter.update(Degrees::from_int(-5)), Degrees::from_int(-5));
+    }
+
+    #[test]
+    fn median_rejects_spikes() {
+        let mut filter = Median::<i32, 5>::new();
+
+        for val in [10, 11, 9, 10] {
+            filter.update(val);
+        }
+        assert_eq!(filter.update(4000), 10);
+        assert_eq!(filter.update(-4000), 10);
+        assert_eq!(filter.update(10), 10);
+    }
+
+    #[test]
+    fn median_while_filling() {
+        let mut filter = Median::<u16, 5>::new();
+
+        assert_eq!(filter.update(7), 7);
+        assert_eq!(filter.update(3), 3);
+        assert_eq!(filter.update(5), 5);
+    }
+
+    #[test]
+    fn filters_are_interchangeable() {
+        fn settle(filter: &mut dyn Filter<Sample = i32>) -> i32 {
+            let mut out = 0;
+            for _ in 0..64 {
+                out = filter.update(-300);
+            }
+            out
+        }
+
+        assert_eq!(settle(&mut MovingAverage::<i32, 16>::new()), -300);
+        assert_eq!(settle(&mut LowPass::new(8192)), -300);
+        assert_eq!(settle(&mut Median::<i32, 3>::new()), -300);
+    }
+
+    #[test]
+    fn decimation_gains_bits() {
+        let decimator = Decimator::new(4);
+        assert_eq!(decimator.min_samples(), 256);
+
+        // Noise dithering evenly between two codes averages to half way between them
+        let samples = (0..256).map(|i| 2047 + (i % 2));
+        assert_eq!(decimator.decimate(samples), Some((2047 << 4) + 8));
+
+        // No extra bits is a plain rounded average
+        assert_eq!(Decimator::new(0).decimate([10, 11, 11, 11]), Some(11));
+        assert_eq!(Decimator::new(0).decimate([]), None);
+    }
+
+    #[test]
+    fn decimation_discards_dnl_codes() {
+        let decimator = Decimator::new(2).discarding(&RP2040_DNL_CODES);
+
+        // The wide code around 512 swallows readings that belong either side of it
+        let samples = [510, 511, 512, 512, 512, 512, 513, 514];
+        assert_eq!(
+            decimator.accumulate(samples),
+            ((510 + 511 + 513 + 514) << 2, 4)
+        );
+
+        // But a reading that never leaves it is still a reading
+        assert_eq!(decimator.decimate([1536; 16]), Some(1536 << 2));
+    }
+}
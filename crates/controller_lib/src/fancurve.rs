@@ -1,6 +1,6 @@
 use super::Degrees;
 
-/// Contains state and APIs to implement a simple proportional controller with saturation
+/// Contains state and APIs to implement a simple proportional controller with saturation. See `Pid` to hold a setpoint.
 pub struct FanCurve<T> {
     pub max_duty: T,
     min_duty: T,
@@ -72,3 +72,181 @@ impl FanCurve<u16> {
         }
     }
 }
+
+/// Most points in a `PiecewiseCurve`
+pub const MAX_CURVE_POINTS: usize = 8;
+
+/// A breakpoint of a `PiecewiseCurve`
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct CurvePoint {
+    pub temp: Degrees,
+    pub duty: u16,
+}
+
+impl CurvePoint {
+    #[must_use]
+    pub const fn new(temp: Degrees, duty: u16) -> Self {
+        Self { temp, duty }
+    }
+}
+
+/// Reasons a set of points does not make a `PiecewiseCurve`
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum CurveError {
+    /// Fewer than 2 points
+    TooFewPoints,
+    /// More than `MAX_CURVE_POINTS` points
+    TooManyPoints,
+    /// Temperatures not strictly increasing, or duty decreasing as temperature rises
+    NotMonotonic,
+}
+
+/// Fan curve through up to `MAX_CURVE_POINTS` breakpoints, linear between them and flat beyond the first and last
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct PiecewiseCurve {
+    points: [CurvePoint; MAX_CURVE_POINTS],
+    len: usize,
+}
+
+impl PiecewiseCurve {
+    /// Create a curve through `points`, in order of increasing temperature
+    ///
+    /// # Errors
+    /// * `TooFewPoints` or `TooManyPoints` if the number of points is not supported
+    /// * `NotMonotonic` if a point is not hotter than the one before it, or asks for less duty
+    pub fn new(points: &[CurvePoint]) -> Result<Self, CurveError> {
+        if points.len() < 2 {
+            return Err(CurveError::TooFewPoints);
+        }
+        if points.len() > MAX_CURVE_POINTS {
+            return Err(CurveError::TooManyPoints);
+        }
+        if points
+            .windows(2)
+            .any(|w| w[1].temp <= w[0].temp || w[1].duty < w[0].duty)
+        {
+            return Err(CurveError::NotMonotonic);
+        }
+
+        let mut stored = [points[0]; MAX_CURVE_POINTS];
+        stored[..points.len()].copy_from_slice(points);
+
+        Ok(Self {
+            points: stored,
+            len: points.len(),
+        })
+    }
+
+    #[must_use]
+    pub fn points(&self) -> &[CurvePoint] {
+        &self.points[..self.len]
+    }
+
+    /// Duty at and above the last point, the highest the curve asks for
+    #[must_use]
+    pub fn max_duty(&self) -> u16 {
+        self.points[self.len - 1].duty
+    }
+
+    /// Duty cycle for a temperature
+    #[must_use]
+    pub fn fan_curve(&self, temp: Degrees) -> u16 {
+        let points = self.points();
+
+        // First point hotter than temp, the segment ends there
+        let idx = points.partition_point(|p| p.temp <= temp);
+        if idx == 0 {
+            return points[0].duty;
+        }
+        if idx == points.len() {
+            return self.max_duty();
+        }
+
+        let (lo, hi) = (points[idx - 1], points[idx]);
+        let span = i64::from(hi.temp.0) - i64::from(lo.temp.0);
+        let offset = i64::from(temp.0) - i64::from(lo.temp.0);
+        let rise = i64::from(hi.duty) - i64::from(lo.duty);
+
+        // Between the two point duties, so always fits
+        u16::try_from(i64::from(lo.duty) + rise * offset / span).unwrap_or(hi.duty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Silent until 35 C, then aggressive
+    fn silent_then_aggressive() -> PiecewiseCurve {
+        PiecewiseCurve::new(&[
+            CurvePoint::new(Degrees::from_int(30), 1000),
+            CurvePoint::new(Degrees::from_int(35), 1000),
+            CurvePoint::new(Degrees::from_int(40), 4000),
+            CurvePoint::new(Degrees::from_int(45), 5000),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn flat_outside_points() {
+        let curve = silent_then_aggressive();
+
+        assert_eq!(curve.fan_curve(Degrees::from_int(-20)), 1000);
+        assert_eq!(curve.fan_curve(Degrees::from_int(30)), 1000);
+        assert_eq!(curve.fan_curve(Degrees::from_int(45)), 5000);
+        assert_eq!(curve.fan_curve(Degrees::from_int(90)), 5000);
+        assert_eq!(curve.max_duty(), 5000);
+    }
+
+    #[test]
+    fn interpolates_segments() {
+        let curve = silent_then_aggressive();
+
+        assert_eq!(curve.fan_curve(Degrees::from_int(33)), 1000);
+        assert_eq!(curve.fan_curve(Degrees::from_int(35)), 1000);
+        assert_eq!(curve.fan_curve(Degrees::from_millidegrees(37_500)), 2500);
+        assert_eq!(curve.fan_curve(Degrees::from_int(40)), 4000);
+        assert_eq!(curve.fan_curve(Degrees::from_int(44)), 4800);
+    }
+
+    #[test]
+    fn matches_two_point_curve() {
+        let mut linear = FanCurve::new(5000, 1000, Degrees::from_int(45), Degrees::from_int(25));
+        let piecewise = PiecewiseCurve::new(&[
+            CurvePoint::new(Degrees::from_int(25), 1000),
+            CurvePoint::new(Degrees::from_int(45), 5000),
+        ])
+        .unwrap();
+
+        for temp in [20, 25, 30, 35, 40, 45, 50] {
+            let temp = Degrees::from_int(temp);
+            assert_eq!(piecewise.fan_curve(temp), linear.fan_curve(temp));
+        }
+    }
+
+    #[test]
+    fn validates_points() {
+        let point = |temp, duty| CurvePoint::new(Degrees::from_int(temp), duty);
+
+        assert_eq!(
+            PiecewiseCurve::new(&[point(25, 1000)]),
+            Err(CurveError::TooFewPoints)
+        );
+        assert_eq!(
+            PiecewiseCurve::new(&[point(25, 1000); MAX_CURVE_POINTS + 1]),
+            Err(CurveError::TooManyPoints)
+        );
+        assert_eq!(
+            PiecewiseCurve::new(&[point(25, 1000), point(25, 2000)]),
+            Err(CurveError::NotMonotonic)
+        );
+        assert_eq!(
+            PiecewiseCurve::new(&[point(25, 2000), point(30, 1000)]),
+            Err(CurveError::NotMonotonic)
+        );
+
+        let full: [CurvePoint; MAX_CURVE_POINTS] =
+            core::array::from_fn(|i| point(20 + 5 * i as i32, 500 * i as u16));
+        assert_eq!(PiecewiseCurve::new(&full).unwrap().points(), &full);
+    }
+}
@@ -0,0 +1,127 @@
+//! Fault detection, kept free of hardware so the logic can be tested on the host
+
+use core::fmt::Display;
+
+/// Faults that put the controller into its failsafe state, latched until reset
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Fault {
+    /// The output at this index is being driven but its tach says it is not turning
+    Stall(u8),
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Stall(output) => write!(f, "stall:{output}"),
+        }
+    }
+}
+
+/// Flags a fan or pump that stays below an RPM floor for too long while commanded above a duty threshold
+pub struct StallDetector {
+    duty_threshold: u16,
+    rpm_floor: u32,
+    timeout_us: u64,
+    /// Time the current below-floor period started, None while the output is healthy or not driven hard enough to judge
+    since: Option<u64>,
+    latched: bool,
+}
+
+impl StallDetector {
+    /// Create a detector
+    ///
+    /// # Arguments
+    /// *  `duty_threshold` - Commanded duty at or above which the output is expected to be turning
+    /// *  `rpm_floor` - Measured RPM below which the output is considered stopped
+    /// *  `timeout_us` - How long both must hold before flagging a fault
+    #[must_use]
+    pub const fn new(duty_threshold: u16, rpm_floor: u32, timeout_us: u64) -> Self {
+        Self {
+            duty_threshold,
+            rpm_floor,
+            timeout_us,
+            since: None,
+            latched: false,
+        }
+    }
+
+    /// Feed the latest commanded duty and measured RPM, returns true once a stall has been detected
+    ///
+    /// Once detected the stall stays latched, whatever the output does afterwards
+    pub fn update(&mut self, duty: u16, rpm: u32, now_us: u64) -> bool {
+        if self.latched {
+            return true;
+        }
+
+        if duty >= self.duty_threshold && rpm < self.rpm_floor {
+            let since = *self.since.get_or_insert(now_us);
+            self.latched = now_us.saturating_sub(since) >= self.timeout_us;
+        } else {
+            self.since = None;
+        }
+
+        self.latched
+    }
+
+    #[must_use]
+    pub const fn is_latched(&self) -> bool {
+        self.latched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000;
+
+    #[test]
+    fn flags_after_timeout() {
+        let mut detector = StallDetector::new(1000, 300, 5 * SECOND);
+
+        assert!(!detector.update(2000, 0, 0));
+        assert!(!detector.update(2000, 0, 4 * SECOND));
+        assert!(detector.update(2000, 0, 5 * SECOND));
+    }
+
+    #[test]
+    fn recovery_resets_timer() {
+        let mut detector = StallDetector::new(1000, 300, 5 * SECOND);
+
+        detector.update(2000, 0, 0);
+        detector.update(2000, 0, 4 * SECOND);
+        // Spun up before the timeout
+        assert!(!detector.update(2000, 1200, 4 * SECOND + 1));
+        assert!(!detector.update(2000, 0, 8 * SECOND));
+        assert!(detector.update(2000, 0, 13 * SECOND));
+    }
+
+    #[test]
+    fn low_duty_is_not_judged() {
+        let mut detector = StallDetector::new(1000, 300, 5 * SECOND);
+
+        // Some fans are allowed to stop at low duty
+        for second in 0..10 {
+            assert!(!detector.update(500, 0, second * SECOND));
+        }
+    }
+
+    #[test]
+    fn latches() {
+        let mut detector = StallDetector::new(1000, 300, SECOND);
+
+        detector.update(2000, 0, 0);
+        assert!(detector.update(2000, 0, SECOND));
+        assert!(detector.update(2000, 1200, 2 * SECOND));
+        assert!(detector.update(0, 0, 3 * SECOND));
+        assert!(detector.is_latched());
+    }
+
+    #[test]
+    fn fault_display() {
+        extern crate std;
+        use std::string::ToString;
+
+        assert_eq!(Fault::Stall(1).to_string(), "stall:1");
+    }
+}
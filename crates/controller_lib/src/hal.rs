@@ -0,0 +1,59 @@
+//! Traits the control pipeline needs from the hardware, so it runs the same against real peripherals or mocks
+//!
+//! Each board provides thin implementations over its own HAL, see `mock` for host-side ones.
+
+use super::nvstore::Slot;
+
+/// Raw ADC counts of one sensor, summed over a number of samples
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct RawReading {
+    pub sum: u32,
+    /// Samples in `sum`, 0 if there were none
+    pub samples: u32,
+    /// Full scale of a single sample in counts, 4096 for 12 bits
+    pub full_scale: u32,
+}
+
+/// A temperature sensor read through an ADC
+pub trait TemperatureSource {
+    /// Latest reading, called once per pipeline update
+    fn read(&mut self) -> RawReading;
+}
+
+/// A PWM output driving a fan or pump
+pub trait PwmOutput {
+    /// Duty at 100%, in the same ticks as the curves driving it
+    fn max_duty(&self) -> u16;
+
+    /// Set the duty, at most `max_duty`
+    fn set_duty(&mut self, duty: u16);
+}
+
+/// A fan's tach signal
+pub trait TachInput {
+    /// Pulses counted so far, free running and allowed to wrap
+    fn pulses(&mut self) -> u32;
+}
+
+/// Monotonic time
+pub trait Clock {
+    /// Microseconds since some fixed point, never going backwards
+    fn now_us(&mut self) -> u64;
+}
+
+/// Two equally sized slots of non-volatile storage, see `nvstore` for what goes in them
+pub trait NvStorage {
+    type Error;
+
+    /// Current contents of a slot, a whole slot long
+    fn read(&self, slot: Slot) -> &[u8];
+
+    /// Scratch space a whole slot long that records are put together in, so they need not be built on the stack
+    fn buffer(&mut self) -> &mut [u8];
+
+    /// Replace the contents of a slot with the first `len` bytes of `buffer`. The rest of the slot reads as erased.
+    ///
+    /// # Errors
+    /// * If `len` is longer than a slot, or the storage fails the write
+    fn write(&mut self, slot: Slot, len: usize) -> Result<(), Self::Error>;
+}
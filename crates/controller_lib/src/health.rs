@@ -0,0 +1,193 @@
+//! Sensor health classification, so a broken probe is reported as such rather than as an odd temperature
+
+use super::thermistor::{AdcConversion, ConversionError};
+use super::Degrees;
+
+/// Readings within this fraction of full scale of either rail count as pinned to it, 1/256
+const RAIL_SHIFT: u32 = 8;
+
+/// State of a sensor as of its latest reading
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum SensorStatus {
+    Ok,
+    /// Reading pinned at the rail a disconnected thermistor gives
+    Open,
+    /// Reading pinned at the rail a shorted thermistor gives
+    Short,
+    /// Converts, but to a temperature outside of the plausible range, or not at all
+    OutOfRange,
+    /// Raw reading has not changed at all for too long, real readings always carry some noise
+    Stuck,
+}
+
+/// Classifies each reading of one sensor
+pub struct SensorHealth {
+    min: Degrees,
+    max: Degrees,
+    stuck_timeout_us: u64,
+    /// Last raw sum, and when it was first seen
+    unchanged_since: Option<(u32, u64)>,
+}
+
+impl SensorHealth {
+    /// Create a classifier
+    ///
+    /// # Arguments
+    /// *  `min` - Lowest plausible temperature
+    /// *  `max` - Highest plausible temperature
+    /// *  `stuck_timeout_us` - How long the raw reading may stay exactly the same before the sensor counts as stuck
+    #[must_use]
+    pub const fn new(min: Degrees, max: Degrees, stuck_timeout_us: u64) -> Self {
+        Self {
+            min,
+            max,
+            stuck_timeout_us,
+            unchanged_since: None,
+        }
+    }
+
+    /// Classify a reading, returning its status and the temperature if the status is `Ok`
+    ///
+    /// # Arguments
+    /// *  `sum` - Sum of `samples` raw ADC readings, compared whole for stuck detection as averaging hides noise
+    /// *  `full_scale` - ADC full scale in counts, 4096 for 12 bits
+    /// *  `conversion` - The sensor's conversion, which also tells which rail is open for its wiring
+    pub fn check(
+        &mut self,
+        sum: u32,
+        samples: u32,
+        full_scale: u32,
+        conversion: &impl AdcConversion,
+        now_us: u64,
+    ) -> (SensorStatus, Option<Degrees>) {
+        let counts = sum / samples.max(1);
+        let margin = full_scale >> RAIL_SHIFT;
+
+        let status = if counts <= margin || counts >= full_scale - margin {
+            if (counts > margin) == conversion.open_at_top_rail() {
+                SensorStatus::Open
+            } else {
+                SensorStatus::Short
+            }
+        } else if self.is_stuck(sum, now_us) {
+            SensorStatus::Stuck
+        } else {
+            match conversion.convert(counts) {
+                Ok(temp) if temp >= self.min && temp <= self.max => {
+                    return (SensorStatus::Ok, Some(temp));
+                }
+                Err(ConversionError::OpenCircuit) => SensorStatus::Open,
+                Err(ConversionError::ShortCircuit) => SensorStatus::Short,
+                _ => SensorStatus::OutOfRange,
+            }
+        };
+
+        (status, None)
+    }
+
+    fn is_stuck(&mut self, sum: u32, now_us: u64) -> bool {
+        match self.unchanged_since {
+            Some((last, since)) if last == sum => {
+                now_us.saturating_sub(since) >= self.stuck_timeout_us
+            }
+            _ => {
+                self.unchanged_since = Some((sum, now_us));
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thermistor::{LinearFit, Model, Placement, Thermistor};
+
+    const SECOND: u64 = 1_000_000;
+    const FULL_SCALE: u32 = 4096;
+
+    fn health() -> SensorHealth {
+        SensorHealth::new(Degrees::from_int(-20), Degrees::from_int(100), 10 * SECOND)
+    }
+
+    fn ntc(placement: Placement) -> Thermistor {
+        Thermistor::new(Model::ntc_beta(10_000, Degrees::from_int(25), 3950), 10_000)
+            .with_placement(placement)
+    }
+
+    #[test]
+    fn healthy_reading() {
+        let (status, temp) = health().check(2048 * 32, 32, FULL_SCALE, &ntc(Placement::LowSide), 0);
+
+        assert_eq!(status, SensorStatus::Ok);
+        assert_eq!(temp, Some(Degrees::from_int(25)));
+    }
+
+    #[test]
+    fn open_and_short_follow_wiring() {
+        let low = ntc(Placement::LowSide);
+        let high = ntc(Placement::HighSide);
+
+        // Near, not only at, the rails
+        assert_eq!(
+            health().check(4090, 1, FULL_SCALE, &low, 0).0,
+            SensorStatus::Open
+        );
+        assert_eq!(
+            health().check(5, 1, FULL_SCALE, &low, 0).0,
+            SensorStatus::Short
+        );
+        assert_eq!(
+            health().check(4090, 1, FULL_SCALE, &high, 0).0,
+            SensorStatus::Short
+        );
+        assert_eq!(
+            health().check(5, 1, FULL_SCALE, &high, 0).0,
+            SensorStatus::Open
+        );
+
+        // The board's own fit never fails, the high rail is open for its wiring
+        assert_eq!(
+            health().check(4095, 1, FULL_SCALE, &LinearFit, 0).0,
+            SensorStatus::Open
+        );
+        assert_eq!(
+            health().check(0, 1, FULL_SCALE, &LinearFit, 0).0,
+            SensorStatus::Short
+        );
+    }
+
+    #[test]
+    fn implausible_temperature() {
+        // About 150 C, the divider is fine but the reading is not believable
+        let (status, temp) = health().check(190, 1, FULL_SCALE, &ntc(Placement::LowSide), 0);
+
+        assert_eq!(status, SensorStatus::OutOfRange);
+        assert_eq!(temp, None);
+    }
+
+    #[test]
+    fn stuck_reading() {
+        let mut health = health();
+        let sensor = ntc(Placement::LowSide);
+
+        assert_eq!(
+            health.check(65_536, 32, FULL_SCALE, &sensor, 0).0,
+            SensorStatus::Ok
+        );
+        assert_eq!(
+            health.check(65_536, 32, FULL_SCALE, &sensor, 9 * SECOND).0,
+            SensorStatus::Ok
+        );
+        assert_eq!(
+            health.check(65_536, 32, FULL_SCALE, &sensor, 10 * SECOND).0,
+            SensorStatus::Stuck
+        );
+
+        // Any noise at all clears it
+        assert_eq!(
+            health.check(65_537, 32, FULL_SCALE, &sensor, 11 * SECOND).0,
+            SensorStatus::Ok
+        );
+    }
+}
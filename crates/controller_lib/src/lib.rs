@@ -2,6 +2,29 @@
 mod degrees;
 pub mod dsp;
 pub mod fancurve;
+pub mod fault;
+pub mod hal;
+pub mod health;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod nvstore;
+pub mod output_stage;
+pub mod pid;
+pub mod pipeline;
+pub mod rt_table;
+pub mod tach;
+pub mod thermistor;
+pub mod virtual_sensor;
 
 pub use degrees::Degrees;
-pub use fancurve::FanCurve;
+pub use fancurve::{CurvePoint, FanCurve, PiecewiseCurve};
+pub use fault::{Fault, StallDetector};
+pub use hal::{Clock, NvStorage, PwmOutput, RawReading, TachInput, TemperatureSource};
+pub use health::{SensorHealth, SensorStatus};
+pub use output_stage::OutputStage;
+pub use pid::{Pid, PidGains};
+pub use pipeline::{OutputChain, Pipeline, SensorChain};
+pub use rt_table::RtTable;
+pub use tach::Tachometer;
+pub use thermistor::{AdcConversion, Thermistor};
+pub use virtual_sensor::VirtualSensor;
@@ -0,0 +1,106 @@
+//! Host-side implementations of the `hal` traits, for tests and simulation
+//!
+//! Each holds a reference to a `Cell` the test or simulator owns, so it can drive inputs and inspect outputs while
+//! the pipeline owns the mocks themselves.
+
+use super::hal::{Clock, NvStorage, PwmOutput, RawReading, TachInput, TemperatureSource};
+use super::nvstore::Slot;
+use core::cell::Cell;
+use core::convert::Infallible;
+
+/// Reads whatever is in the cell
+#[derive(Copy, Clone)]
+pub struct MockSource<'a>(pub &'a Cell<RawReading>);
+
+impl TemperatureSource for MockSource<'_> {
+    fn read(&mut self) -> RawReading {
+        self.0.get()
+    }
+}
+
+/// Stores the duty in the cell
+#[derive(Copy, Clone)]
+pub struct MockPwm<'a> {
+    pub duty: &'a Cell<u16>,
+    pub max_duty: u16,
+}
+
+impl PwmOutput for MockPwm<'_> {
+    fn max_duty(&self) -> u16 {
+        self.max_duty
+    }
+
+    fn set_duty(&mut self, duty: u16) {
+        self.duty.set(duty);
+    }
+}
+
+/// Reads the pulse count from the cell
+#[derive(Copy, Clone)]
+pub struct MockTach<'a>(pub &'a Cell<u32>);
+
+impl TachInput for MockTach<'_> {
+    fn pulses(&mut self) -> u32 {
+        self.0.get()
+    }
+}
+
+/// Reads the time from the cell
+#[derive(Copy, Clone)]
+pub struct MockClock<'a>(pub &'a Cell<u64>);
+
+impl Clock for MockClock<'_> {
+    fn now_us(&mut self) -> u64 {
+        self.0.get()
+    }
+}
+
+/// Storage in RAM, `N` bytes a slot, starting out erased
+pub struct MemStorage<const N: usize> {
+    slots: [[u8; N]; 2],
+    buffer: [u8; N],
+}
+
+impl<const N: usize> MemStorage<N> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            slots: [[0xff; N]; 2],
+            buffer: [0; N],
+        }
+    }
+
+    const fn index(slot: Slot) -> usize {
+        match slot {
+            Slot::A => 0,
+            Slot::B => 1,
+        }
+    }
+}
+
+impl<const N: usize> Default for MemStorage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> NvStorage for MemStorage<N> {
+    type Error = Infallible;
+
+    fn read(&self, slot: Slot) -> &[u8] {
+        &self.slots[Self::index(slot)]
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    /// # Panics
+    /// * If `len` is longer than a slot
+    fn write(&mut self, slot: Slot, len: usize) -> Result<(), Self::Error> {
+        let stored = &mut self.slots[Self::index(slot)];
+        stored.fill(0xff);
+        stored[..len].copy_from_slice(&self.buffer[..len]);
+        Ok(())
+    }
+}
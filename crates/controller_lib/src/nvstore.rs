@@ -0,0 +1,286 @@
+//! Records in non-volatile storage, kept in two alternating slots so a power cut mid-write never loses both copies
+//!
+//! Each slot holds at most one record, a header followed by an opaque payload. Writes always go to the slot not
+//! holding the newest record, which also spreads erases over both slots. A record is only trusted if its magic,
+//! format version and CRC all check out.
+
+use super::hal::NvStorage;
+
+/// Marks the start of a record, "DXCF"
+const MAGIC: u32 = 0x4643_5844;
+
+/// Encoded header size
+pub const HEADER_LEN: usize = 16;
+
+/// One of the two slots
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    #[must_use]
+    pub const fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+}
+
+/// A valid record found in a slot
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Record<'a> {
+    /// Incremented on every write, the higher of two valid records is the newer
+    pub sequence: u32,
+    pub payload: &'a [u8],
+}
+
+/// CRC-32 (IEEE 802.3), bitwise as records are small and written rarely
+#[must_use]
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0xEDB8_8320
+            };
+        }
+    }
+    !crc
+}
+
+/// Encode a record into `out`, returning the used part
+///
+/// Returns None if `out` is too small or the payload is longer than a header can describe
+pub fn encode<'a>(
+    version: u16,
+    sequence: u32,
+    payload: &[u8],
+    out: &'a mut [u8],
+) -> Option<&'a [u8]> {
+    let record = out.get_mut(..HEADER_LEN + payload.len())?;
+    record[HEADER_LEN..].copy_from_slice(payload);
+    seal(version, sequence, record)?;
+    Some(record)
+}
+
+/// Fill in the header of `record`, whose payload already follows it, None if the payload is too long to describe
+fn seal(version: u16, sequence: u32, record: &mut [u8]) -> Option<()> {
+    let (header, payload) = record.split_at_mut(HEADER_LEN);
+    let len = u16::try_from(payload.len()).ok()?;
+
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&version.to_le_bytes());
+    header[6..8].copy_from_slice(&len.to_le_bytes());
+    header[8..12].copy_from_slice(&sequence.to_le_bytes());
+    header[12..16].copy_from_slice(&crc32(payload).to_le_bytes());
+    Some(())
+}
+
+/// Decode the record held in a slot, None if the slot is erased, corrupt or from another format version
+#[must_use]
+pub fn decode(slot: &[u8], version: u16) -> Option<Record<'_>> {
+    let header = slot.get(..HEADER_LEN)?;
+    let word = |at: usize| {
+        u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+    };
+    let half = |at: usize| u16::from_le_bytes([header[at], header[at + 1]]);
+
+    if word(0) != MAGIC || half(4) != version {
+        return None;
+    }
+
+    let payload = slot.get(HEADER_LEN..HEADER_LEN + usize::from(half(6)))?;
+    (crc32(payload) == word(12)).then_some(Record {
+        sequence: word(8),
+        payload,
+    })
+}
+
+/// Find the newest valid record across both slots
+#[must_use]
+pub fn newest<'a>(a: &'a [u8], b: &'a [u8], version: u16) -> Option<(Slot, Record<'a>)> {
+    match (decode(a, version), decode(b, version)) {
+        (Some(a), Some(b)) => {
+            // Wrapping comparison, so the sequence number can roll over
+            if b.sequence.wrapping_sub(a.sequence) as i32 > 0 {
+                Some((Slot::B, b))
+            } else {
+                Some((Slot::A, a))
+            }
+        }
+        (Some(a), None) => Some((Slot::A, a)),
+        (None, Some(b)) => Some((Slot::B, b)),
+        (None, None) => None,
+    }
+}
+
+/// Slot and sequence number the next write should use, given the current newest record
+#[must_use]
+pub fn next_write(newest: Option<(Slot, Record<'_>)>) -> (Slot, u32) {
+    newest.map_or((Slot::A, 0), |(slot, record)| {
+        (slot.other(), record.sequence.wrapping_add(1))
+    })
+}
+
+/// Errors writing a record with `store`
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum StoreError<E> {
+    /// The record does not fit in the buffer given, or in a slot
+    TooLarge,
+    Storage(E),
+}
+
+/// The newest valid record in `storage`
+pub fn load<S: NvStorage>(storage: &S, version: u16) -> Option<Record<'_>> {
+    newest(storage.read(Slot::A), storage.read(Slot::B), version).map(|(_slot, record)| record)
+}
+
+/// Write a record to the slot of `storage` not holding the newest one
+///
+/// # Errors
+/// * `TooLarge` if the record does not fit in a slot
+/// * `Storage` if the write fails
+pub fn store<S: NvStorage>(
+    storage: &mut S,
+    version: u16,
+    payload: &[u8],
+) -> Result<(), StoreError<S::Error>> {
+    storage
+        .buffer()
+        .get_mut(HEADER_LEN..HEADER_LEN + payload.len())
+        .ok_or(StoreError::TooLarge)?
+        .copy_from_slice(payload);
+    store_in_place(storage, version, payload.len())
+}
+
+/// Write a record whose payload of `len` bytes has already been put in `storage`'s buffer, after `HEADER_LEN` bytes
+/// left for the header
+///
+/// # Errors
+/// * `TooLarge` if the record does not fit in a slot
+/// * `Storage` if the write fails
+pub fn store_in_place<S: NvStorage>(
+    storage: &mut S,
+    version: u16,
+    len: usize,
+) -> Result<(), StoreError<S::Error>> {
+    let (slot, sequence) = next_write(newest(
+        storage.read(Slot::A),
+        storage.read(Slot::B),
+        version,
+    ));
+    let record = storage
+        .buffer()
+        .get_mut(..HEADER_LEN + len)
+        .ok_or(StoreError::TooLarge)?;
+    seal(version, sequence, record).ok_or(StoreError::TooLarge)?;
+    storage
+        .write(slot, HEADER_LEN + len)
+        .map_err(StoreError::Storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ERASED: [u8; 64] = [0xff; 64];
+
+    fn write(slot: &mut [u8; 64], sequence: u32, payload: &[u8]) {
+        let mut buf = [0u8; 64];
+        let record = encode(1, sequence, payload, &mut buf).unwrap();
+        *slot = ERASED;
+        slot[..record.len()].copy_from_slice(record);
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut slot = ERASED;
+        write(&mut slot, 7, b"hello");
+
+        assert_eq!(
+            decode(&slot, 1),
+            Some(Record {
+                sequence: 7,
+                payload: b"hello"
+            })
+        );
+        // Another format version is as good as no record
+        assert_eq!(decode(&slot, 2), None);
+        assert_eq!(decode(&ERASED, 1), None);
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let mut slot = ERASED;
+        write(&mut slot, 7, b"hello");
+        slot[HEADER_LEN + 1] ^= 0x10;
+
+        assert_eq!(decode(&slot, 1), None);
+    }
+
+    #[test]
+    fn alternates_slots() {
+        let (mut a, mut b) = (ERASED, ERASED);
+        assert_eq!(next_write(newest(&a, &b, 1)), (Slot::A, 0));
+
+        write(&mut a, 0, b"first");
+        assert_eq!(next_write(newest(&a, &b, 1)), (Slot::B, 1));
+
+        write(&mut b, 1, b"second");
+        let (slot, record) = newest(&a, &b, 1).unwrap();
+        assert_eq!((slot, record.payload), (Slot::B, &b"second"[..]));
+        assert_eq!(next_write(newest(&a, &b, 1)), (Slot::A, 2));
+    }
+
+    #[test]
+    fn falls_back_to_older_record() {
+        let (mut a, mut b) = (ERASED, ERASED);
+        write(&mut a, 4, b"older");
+        write(&mut b, 5, b"newer");
+        // Power lost part way through rewriting B
+        b[HEADER_LEN..].fill(0xff);
+
+        let (slot, record) = newest(&a, &b, 1).unwrap();
+        assert_eq!((slot, record.payload), (Slot::A, &b"older"[..]));
+        assert_eq!(next_write(Some((slot, record))), (Slot::B, 5));
+    }
+
+    #[test]
+    fn stores_through_storage() {
+        let mut storage = crate::mock::MemStorage::<64>::new();
+
+        assert_eq!(load(&storage, 1), None);
+        store(&mut storage, 1, b"first").unwrap();
+        store(&mut storage, 1, b"second").unwrap();
+        assert_eq!(load(&storage, 1).unwrap().payload, b"second");
+        // The older record is still there to fall back on
+        assert_eq!(decode(storage.read(Slot::A), 1).unwrap().payload, b"first");
+
+        assert_eq!(store(&mut storage, 1, &[0; 64]), Err(StoreError::TooLarge));
+
+        // A payload put straight into the buffer
+        storage.buffer()[HEADER_LEN..HEADER_LEN + 5].copy_from_slice(b"third");
+        store_in_place(&mut storage, 1, 5).unwrap();
+        assert_eq!(load(&storage, 1).unwrap().payload, b"third");
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let (mut a, mut b) = (ERASED, ERASED);
+        write(&mut a, u32::MAX, b"older");
+        write(&mut b, 0, b"newer");
+
+        assert_eq!(newest(&a, &b, 1).unwrap().0, Slot::B);
+    }
+}
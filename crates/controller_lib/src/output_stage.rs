@@ -0,0 +1,168 @@
+//! Smoothing between a fan curve and the PWM, so sensor noise near a breakpoint does not make the fans hunt
+
+/// Sub-tick resolution of the ramped duty, so slow ramps still move on frequent updates
+const FRAC: u32 = 16;
+
+/// Stateful output stage applying hysteresis, then ramp-rate limits, to the duty a curve asks for
+///
+/// The curve's duty is only followed once it moves far enough from the duty last followed, with separate thresholds
+/// up and down. The output then ramps towards it no faster than the configured rate in that direction.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct OutputStage {
+    spin_up_threshold: u16,
+    spin_down_threshold: u16,
+    max_rise_per_s: u16,
+    max_fall_per_s: u16,
+    /// Duty being ramped towards, None before the first update
+    target: Option<u16>,
+    /// Current duty, F16
+    output: u32,
+    last_us: u64,
+}
+
+impl OutputStage {
+    /// Create an output stage, all in the same duty units as the curve
+    ///
+    /// # Arguments
+    /// *  `spin_up_threshold` - How far the curve must rise above the followed duty before it is followed
+    /// *  `spin_down_threshold` - How far the curve must fall below the followed duty before it is followed
+    /// *  `max_rise_per_s` - Fastest the output may rise, 0 for no limit
+    /// *  `max_fall_per_s` - Fastest the output may fall, 0 for no limit
+    #[must_use]
+    pub const fn new(
+        spin_up_threshold: u16,
+        spin_down_threshold: u16,
+        max_rise_per_s: u16,
+        max_fall_per_s: u16,
+    ) -> Self {
+        Self {
+            spin_up_threshold,
+            spin_down_threshold,
+            max_rise_per_s,
+            max_fall_per_s,
+            target: None,
+            output: 0,
+            last_us: 0,
+        }
+    }
+
+    /// Feed the duty the curve asks for, returning the duty to drive
+    ///
+    /// The first update is passed straight through, there is nothing to smooth from yet
+    pub fn update(&mut self, requested: u16, now_us: u64) -> u16 {
+        let Some(target) = self.target else {
+            self.force(requested, now_us);
+            return requested;
+        };
+
+        let rising = requested > target.saturating_add(self.spin_up_threshold);
+        let falling = requested < target.saturating_sub(self.spin_down_threshold);
+        let target = if rising || falling { requested } else { target };
+        self.target = Some(target);
+
+        let elapsed = now_us.saturating_sub(self.last_us);
+        self.last_us = now_us;
+
+        let target_fixed = u32::from(target) << FRAC;
+        self.output = if target_fixed > self.output {
+            self.output
+                .saturating_add(Self::max_step(self.max_rise_per_s, elapsed))
+                .min(target_fixed)
+        } else {
+            self.output
+                .saturating_sub(Self::max_step(self.max_fall_per_s, elapsed))
+                .max(target_fixed)
+        };
+
+        self.duty()
+    }
+
+    /// Jump straight to a duty, bypassing hysteresis and ramp limits. For failsafes that must not wait.
+    pub fn force(&mut self, duty: u16, now_us: u64) {
+        self.target = Some(duty);
+        self.output = u32::from(duty) << FRAC;
+        self.last_us = now_us;
+    }
+
+    /// Current output duty
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn duty(&self) -> u16 {
+        // Rounded, and never beyond the u16 target it ramps towards
+        ((self.output + (1 << (FRAC - 1))) >> FRAC) as u16
+    }
+
+    /// Largest change in F16 duty allowed over `elapsed_us` at `rate` per second
+    fn max_step(rate: u16, elapsed_us: u64) -> u32 {
+        if rate == 0 {
+            return u32::MAX;
+        }
+
+        let step = (u64::from(rate) << FRAC) * elapsed_us / 1_000_000;
+        u32::try_from(step).unwrap_or(u32::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1000;
+
+    #[test]
+    fn first_update_passes_through() {
+        let mut stage = OutputStage::new(100, 100, 1000, 1000);
+
+        assert_eq!(stage.update(2500, 0), 2500);
+    }
+
+    #[test]
+    fn hysteresis_ignores_noise() {
+        let mut stage = OutputStage::new(100, 200, 0, 0);
+        stage.update(2000, 0);
+
+        // Hunting around a breakpoint stays put
+        for (i, duty) in [2050, 1950, 2100, 1850, 1800].into_iter().enumerate() {
+            assert_eq!(stage.update(duty, i as u64 * MS), 2000);
+        }
+        assert_eq!(stage.update(2101, 10 * MS), 2101);
+        assert_eq!(stage.update(1900, 11 * MS), 1900);
+    }
+
+    #[test]
+    fn rises_at_limited_rate() {
+        let mut stage = OutputStage::new(0, 0, 1000, 0);
+        stage.update(1000, 0);
+
+        assert_eq!(stage.update(5000, 500 * MS), 1500);
+        assert_eq!(stage.update(5000, 1000 * MS), 2000);
+        // Small steps add up rather than rounding away
+        for ms in 1001..=1100 {
+            stage.update(5000, ms * MS);
+        }
+        assert_eq!(stage.duty(), 2100);
+        assert_eq!(stage.update(5000, 10_000 * MS), 5000);
+    }
+
+    #[test]
+    fn falls_at_its_own_rate() {
+        let mut stage = OutputStage::new(0, 0, 0, 200);
+        stage.update(1000, 0);
+
+        // Rising is unlimited
+        assert_eq!(stage.update(5000, 1), 5000);
+        assert_eq!(stage.update(1000, 1 + 1000 * MS), 4800);
+        assert_eq!(stage.update(1000, 1 + 2000 * MS), 4600);
+    }
+
+    #[test]
+    fn force_bypasses_limits() {
+        let mut stage = OutputStage::new(500, 500, 10, 10);
+        stage.update(1000, 0);
+
+        stage.force(5000, MS);
+        assert_eq!(stage.duty(), 5000);
+        // Curve dropping back is then rate limited as usual
+        assert_eq!(stage.update(1000, 1001 * MS), 4990);
+    }
+}
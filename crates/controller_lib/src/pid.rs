@@ -0,0 +1,215 @@
+//! Fixed point PID controller, for holding a temperature at a setpoint with the least duty that does it
+//!
+//! The controller is oriented for cooling: a temperature above the setpoint asks for more duty.
+
+use super::Degrees;
+
+/// Fractional bits of gains and of the controller's internal duty values
+const FRAC: u32 = 16;
+
+/// PID gains, F16 duty per degree
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct PidGains {
+    /// Duty per degree of error
+    pub kp: i32,
+    /// Duty per degree-second of accumulated error
+    pub ki: i32,
+    /// Duty per degree per second of temperature change
+    pub kd: i32,
+}
+
+impl PidGains {
+    /// Create gains from their values in duty units
+    ///
+    /// Intended to be used in const context so the float math never makes it into the firmware
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    pub const fn new(kp: f64, ki: f64, kd: f64) -> Self {
+        let scale = (1u32 << FRAC) as f64;
+        Self {
+            kp: (kp * scale) as i32,
+            ki: (ki * scale) as i32,
+            kd: (kd * scale) as i32,
+        }
+    }
+}
+
+/// PID controller with a clamped integrator and a filtered derivative on measurement
+///
+/// The derivative acts on the measured temperature rather than the error, so moving the setpoint does not kick the
+/// output. It is low-pass filtered, as differentiating ADC noise would otherwise dominate it.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Pid {
+    setpoint: Degrees,
+    gains: PidGains,
+    min_duty: u16,
+    max_duty: u16,
+    derivative_tau_us: u64,
+    /// F16 duty, clamped to the output limits
+    integral: i64,
+    /// F12 degrees per second
+    derivative: i64,
+    /// Measurement and time of the previous update, None before the first
+    last: Option<(Degrees, u64)>,
+}
+
+impl Pid {
+    /// Create a controller
+    ///
+    /// # Arguments
+    /// *  `setpoint` - Temperature to hold
+    /// *  `gains` - Proportional, integral and derivative gains
+    /// *  `min_duty` - Lowest duty the output may be driven at, also the integrator's lower clamp
+    /// *  `max_duty` - Highest duty the output may be driven at, also the integrator's upper clamp
+    #[must_use]
+    pub const fn new(setpoint: Degrees, gains: PidGains, min_duty: u16, max_duty: u16) -> Self {
+        Self {
+            setpoint,
+            gains,
+            min_duty,
+            max_duty,
+            derivative_tau_us: 0,
+            integral: (min_duty as i64) << FRAC,
+            derivative: 0,
+            last: None,
+        }
+    }
+
+    /// Set the time constant of the derivative's low-pass filter, 0 (the default) for no filtering
+    #[must_use]
+    pub const fn with_derivative_filter(mut self, tau_us: u64) -> Self {
+        self.derivative_tau_us = tau_us;
+        self
+    }
+
+    #[must_use]
+    pub const fn setpoint(&self) -> Degrees {
+        self.setpoint
+    }
+
+    /// Move the setpoint, taking effect from the next update without disturbing the integrator
+    pub fn set_setpoint(&mut self, setpoint: Degrees) {
+        self.setpoint = setpoint;
+    }
+
+    /// Feed a measurement, returning the duty to drive within the output limits
+    ///
+    /// The first update only has the proportional term and the integrator's starting value, at `min_duty`, to go on
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn update(&mut self, measurement: Degrees, now_us: u64) -> u16 {
+        let error = i64::from(measurement.0) - i64::from(self.setpoint.0);
+        let (min, max) = (
+            i64::from(self.min_duty) << FRAC,
+            i64::from(self.max_duty) << FRAC,
+        );
+
+        if let Some((last, last_us)) = self.last {
+            let dt = i128::from(now_us.saturating_sub(last_us));
+            if dt > 0 {
+                // F16 gain * F12 error * us, down to F16 duty
+                let step = (i128::from(self.gains.ki) * i128::from(error) * dt / 1_000_000) >> 12;
+                let step = step.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64;
+                self.integral = self.integral.saturating_add(step).clamp(min, max);
+
+                // F12 degrees per second, smoothed with alpha = dt / (tau + dt)
+                let change = i128::from(measurement.0) - i128::from(last.0);
+                let rate =
+                    (change * 1_000_000 / dt).clamp(i128::from(i32::MIN), i128::from(i32::MAX));
+                let delta = (rate - i128::from(self.derivative)) * dt
+                    / (i128::from(self.derivative_tau_us) + dt);
+                self.derivative += delta as i64;
+            }
+        }
+        self.last = Some((measurement, now_us));
+
+        let p = (i128::from(self.gains.kp) * i128::from(error)) >> 12;
+        let d = (i128::from(self.gains.kd) * i128::from(self.derivative)) >> 12;
+        let total = (p + i128::from(self.integral) + d).clamp(i128::from(min), i128::from(max));
+
+        // Within the u16 output limits after the clamp
+        ((total + (1 << (FRAC - 1))) >> FRAC) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000;
+
+    fn setpoint() -> Degrees {
+        Degrees::from_int(32)
+    }
+
+    #[test]
+    fn proportional() {
+        let mut pid = Pid::new(setpoint(), PidGains::new(500.0, 0.0, 0.0), 0, 5000);
+
+        assert_eq!(pid.update(Degrees::from_int(34), 0), 1000);
+        assert_eq!(pid.update(Degrees::from_millidegrees(32_500), SECOND), 250);
+        // Below the setpoint clamps to the minimum
+        assert_eq!(pid.update(Degrees::from_int(30), 2 * SECOND), 0);
+        // And far above to the maximum
+        assert_eq!(pid.update(Degrees::from_int(60), 3 * SECOND), 5000);
+    }
+
+    #[test]
+    fn integral_accumulates_and_clamps() {
+        let mut pid = Pid::new(setpoint(), PidGains::new(0.0, 100.0, 0.0), 1000, 5000);
+
+        assert_eq!(pid.update(Degrees::from_int(33), 0), 1000);
+        assert_eq!(pid.update(Degrees::from_int(33), SECOND), 1100);
+        assert_eq!(pid.update(Degrees::from_int(34), 2 * SECOND), 1300);
+
+        // A long time hot winds the integrator up only as far as the output limit
+        assert_eq!(pid.update(Degrees::from_int(40), 1000 * SECOND), 5000);
+        // So it starts coming back as soon as the error changes sign
+        assert_eq!(pid.update(Degrees::from_int(31), 1001 * SECOND), 4900);
+    }
+
+    #[test]
+    fn no_derivative_kick_on_setpoint_change() {
+        let mut pid = Pid::new(setpoint(), PidGains::new(0.0, 0.0, 1000.0), 0, 5000);
+
+        pid.update(Degrees::from_int(35), 0);
+        pid.set_setpoint(Degrees::from_int(20));
+        assert_eq!(pid.update(Degrees::from_int(35), SECOND), 0);
+
+        // Rising 1 degree per second
+        assert_eq!(pid.update(Degrees::from_int(36), 2 * SECOND), 1000);
+    }
+
+    #[test]
+    fn derivative_is_filtered() {
+        let gains = PidGains::new(0.0, 0.0, 1000.0);
+        let mut raw = Pid::new(setpoint(), gains, 0, 5000);
+        let mut filtered = Pid::new(setpoint(), gains, 0, 5000).with_derivative_filter(SECOND);
+
+        raw.update(Degrees::from_int(35), 0);
+        filtered.update(Degrees::from_int(35), 0);
+
+        // A single noisy sample 100 ms later
+        let noisy = Degrees::from_millidegrees(35_200);
+        assert_eq!(raw.update(noisy, SECOND / 10), 2000);
+        assert!(filtered.update(noisy, SECOND / 10) < 200);
+    }
+
+    #[test]
+    fn holds_setpoint() {
+        // Water heated at a constant rate, cooled in proportion to duty and the difference to ambient
+        let mut pid = Pid::new(setpoint(), PidGains::new(2000.0, 100.0, 500.0), 1000, 5000)
+            .with_derivative_filter(2 * SECOND);
+        let mut water: i64 = 40 << 12;
+        let ambient: i64 = 22 << 12;
+
+        for tick in 0..6000u64 {
+            let duty = pid.update(Degrees(water as i32), tick * SECOND / 10);
+            let heat = 80;
+            let cooling = i64::from(duty) * (water - ambient) / 5000 / 400;
+            water += heat - cooling;
+        }
+
+        let settled = Degrees(water as i32).to_millidegrees();
+        assert!((settled - 32_000).abs() < 100, "settled at {settled}");
+    }
+}
@@ -0,0 +1,538 @@
+//! The whole control path, from raw ADC readings through health checks, conversion and filtering to curves, PIDs and
+//! the PWM outputs
+//!
+//! Generic over the `hal` traits, so the same code drives the fans on the board and in host tests or simulation.
+
+use super::dsp::Filter;
+use super::hal::{Clock, PwmOutput, RawReading, TachInput, TemperatureSource};
+use super::{
+    AdcConversion, Degrees, Fault, OutputStage, Pid, PiecewiseCurve, SensorHealth, SensorStatus,
+    StallDetector, Tachometer, VirtualSensor,
+};
+
+/// Turns one sensor's raw readings into a trusted, filtered temperature
+pub struct SensorChain<C, F> {
+    conversion: C,
+    filter: F,
+    health: SensorHealth,
+}
+
+impl<C: AdcConversion, F: Filter<Sample = Degrees>> SensorChain<C, F> {
+    #[must_use]
+    pub const fn new(conversion: C, filter: F, health: SensorHealth) -> Self {
+        Self {
+            conversion,
+            filter,
+            health,
+        }
+    }
+
+    /// Classify and convert a reading, returning its status and the filtered temperature if it is healthy
+    ///
+    /// The filter starts over after a dropout, rather than blending the reading back in with stale history
+    pub fn update(&mut self, raw: RawReading, now_us: u64) -> (SensorStatus, Option<Degrees>) {
+        let (status, reading) = self.health.check(
+            raw.sum,
+            raw.samples,
+            raw.full_scale,
+            &self.conversion,
+            now_us,
+        );
+        if reading.is_none() {
+            self.filter.reset();
+        }
+        (status, reading.map(|temp| self.filter.update(temp)))
+    }
+}
+
+/// Drives one PWM output from a reading, through its curve or PID and output stage
+pub struct OutputChain<P, T> {
+    pwm: P,
+    /// Tach input and the tachometer measuring it, None if the output has no tach
+    tach: Option<(T, Tachometer)>,
+    stall: StallDetector,
+    stage: OutputStage,
+    curve: PiecewiseCurve,
+    pid: Option<Pid>,
+    /// Bounds on the duty from the curve or PID and output stage, failsafes are not held to them
+    min_duty: u16,
+    max_duty: u16,
+    /// Reading followed, an index into the pipeline's sensors or past their end into its virtual sensors
+    sensor: usize,
+    /// Last commanded duty
+    duty: u16,
+}
+
+impl<P: PwmOutput, T: TachInput> OutputChain<P, T> {
+    /// Take over an output, running it flat out until the first update
+    ///
+    /// # Arguments
+    /// *  `sensor` - Reading the output follows, see `Pipeline::new`
+    /// *  `curve` - Duty for the reading
+    /// *  `stage` - Hysteresis and ramp limits between the curve and the PWM
+    /// *  `stall` - Applied only if the output has a tach, see `with_tach`
+    #[must_use]
+    pub fn new(
+        mut pwm: P,
+        sensor: usize,
+        curve: PiecewiseCurve,
+        stage: OutputStage,
+        stall: StallDetector,
+    ) -> Self {
+        let duty = pwm.max_duty();
+        pwm.set_duty(duty);
+        Self {
+            pwm,
+            tach: None,
+            stall,
+            stage,
+            curve,
+            pid: None,
+            min_duty: 0,
+            max_duty: duty,
+            sensor,
+            duty,
+        }
+    }
+
+    /// Measure the output's speed, and watch it for stalls
+    #[must_use]
+    pub fn with_tach(mut self, input: T, tachometer: Tachometer) -> Self {
+        self.tach = Some((input, tachometer));
+        self
+    }
+
+    /// Hold the reading at the PID's setpoint instead of following the curve. The output still runs flat out while
+    /// there is no usable reading.
+    #[must_use]
+    pub fn with_pid(mut self, pid: Pid) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Keep the duty between `min_duty` and `max_duty` whatever the curve or PID asks for, so that e.g. a pump is never
+    /// stopped. Failsafes still run the output at full duty.
+    #[must_use]
+    pub const fn with_limits(mut self, min_duty: u16, max_duty: u16) -> Self {
+        self.min_duty = min_duty;
+        self.max_duty = max_duty;
+        self
+    }
+
+    /// Last commanded duty
+    #[must_use]
+    pub const fn duty(&self) -> u16 {
+        self.duty
+    }
+
+    #[must_use]
+    pub const fn curve(&self) -> &PiecewiseCurve {
+        &self.curve
+    }
+
+    /// Replace the curve, taking effect from the next update
+    pub fn set_curve(&mut self, curve: PiecewiseCurve) {
+        self.curve = curve;
+    }
+
+    /// Drive the output at `duty`, straight away
+    fn force(&mut self, duty: u16, now_us: u64) {
+        self.stage.force(duty, now_us);
+        self.set_duty(duty);
+    }
+
+    fn set_duty(&mut self, duty: u16) {
+        self.duty = duty;
+        self.pwm.set_duty(duty);
+    }
+}
+
+/// State of the pipeline as of its latest update
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Status<const SENSORS: usize, const OUTPUTS: usize> {
+    /// None while the sensor is unhealthy
+    pub readings: [Option<Degrees>; SENSORS],
+    /// None before the first update
+    pub sensors: [Option<SensorStatus>; SENSORS],
+    pub duties: [u16; OUTPUTS],
+    /// None for outputs without a tach
+    pub rpms: [Option<u32>; OUTPUTS],
+    /// First fault detected, latched until reset
+    pub fault: Option<Fault>,
+}
+
+/// Every sensor and output of a controller, updated together on each batch of readings
+pub struct Pipeline<C, F, P, T, K, const SENSORS: usize, const OUTPUTS: usize> {
+    sensors: [SensorChain<C, F>; SENSORS],
+    /// Readings derived from `sensors`, indexed by outputs after them
+    virtual_sensors: &'static [VirtualSensor],
+    outputs: [OutputChain<P, T>; OUTPUTS],
+    clock: K,
+    status: Status<SENSORS, OUTPUTS>,
+}
+
+impl<C, F, P, T, K, const SENSORS: usize, const OUTPUTS: usize>
+    Pipeline<C, F, P, T, K, SENSORS, OUTPUTS>
+where
+    C: AdcConversion,
+    F: Filter<Sample = Degrees>,
+    P: PwmOutput,
+    T: TachInput,
+    K: Clock,
+{
+    /// Assemble a pipeline, None if a virtual sensor or output refers to a reading that does not exist
+    ///
+    /// Outputs follow readings by index, the sensors' first and the virtual sensors' after them
+    #[must_use]
+    pub fn new(
+        sensors: [SensorChain<C, F>; SENSORS],
+        virtual_sensors: &'static [VirtualSensor],
+        outputs: [OutputChain<P, T>; OUTPUTS],
+        clock: K,
+    ) -> Option<Self> {
+        if !virtual_sensors
+            .iter()
+            .all(|sensor| sensor.is_valid(SENSORS))
+            || outputs
+                .iter()
+                .any(|output| output.sensor >= SENSORS + virtual_sensors.len())
+        {
+            return None;
+        }
+
+        let status = Status {
+            readings: [None; SENSORS],
+            sensors: [None; SENSORS],
+            duties: core::array::from_fn(|index| outputs[index].duty),
+            rpms: [None; OUTPUTS],
+            fault: None,
+        };
+        Some(Self {
+            sensors,
+            virtual_sensors,
+            outputs,
+            clock,
+            status,
+        })
+    }
+
+    /// State as of the latest update
+    #[must_use]
+    pub const fn status(&self) -> &Status<SENSORS, OUTPUTS> {
+        &self.status
+    }
+
+    #[must_use]
+    pub fn output(&self, index: usize) -> Option<&OutputChain<P, T>> {
+        self.outputs.get(index)
+    }
+
+    pub fn output_mut(&mut self, index: usize) -> Option<&mut OutputChain<P, T>> {
+        self.outputs.get_mut(index)
+    }
+
+    /// Read every sensor and drive every output from the readings
+    ///
+    /// Unhealthy sensors run their outputs flat out, whatever their curves top out at, as there is no telling how hot
+    /// the loop is. A stall runs everything else flat out to make up for it. Failsafes skip the output stage, nothing
+    /// should slow them down.
+    pub fn update(
+        &mut self,
+        sources: &mut [impl TemperatureSource; SENSORS],
+    ) -> &Status<SENSORS, OUTPUTS> {
+        let now = self.clock.now_us();
+        let status = &mut self.status;
+
+        for (index, (sensor, source)) in self.sensors.iter_mut().zip(sources).enumerate() {
+            let (health, reading) = sensor.update(source.read(), now);
+            status.sensors[index] = Some(health);
+            status.readings[index] = reading;
+        }
+
+        // Tach and stall detection against the duty commanded last time around
+        for (index, output) in self.outputs.iter_mut().enumerate() {
+            let Some((input, tachometer)) = output.tach.as_mut() else {
+                continue;
+            };
+            let rpm = tachometer.update(input.pulses(), now);
+            status.rpms[index] = Some(rpm);
+
+            #[allow(clippy::cast_possible_truncation)]
+            if output.stall.update(output.duty, rpm, now) {
+                status.fault = status.fault.or(Some(Fault::Stall(index as u8)));
+            }
+        }
+
+        for (index, output) in self.outputs.iter_mut().enumerate() {
+            let reading = match output.sensor.checked_sub(SENSORS) {
+                None => status.readings[output.sensor],
+                Some(virtual_index) => {
+                    self.virtual_sensors[virtual_index].evaluate(&status.readings)
+                }
+            };
+
+            match (status.fault, reading) {
+                (Some(Fault::Stall(stalled)), _) if usize::from(stalled) != index => {
+                    output.force(output.pwm.max_duty(), now);
+                }
+                (_, None) => output.force(output.pwm.max_duty(), now),
+                (_, Some(temp)) => {
+                    let requested = match output.pid.as_mut() {
+                        Some(pid) => pid.update(temp, now),
+                        None => output.curve.fan_curve(temp),
+                    };
+                    let duty = output
+                        .stage
+                        .update(requested, now)
+                        .max(output.min_duty)
+                        .min(output.max_duty);
+                    output.set_duty(duty);
+                }
+            }
+            status.duties[index] = output.duty;
+        }
+
+        &self.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::MovingAverage;
+    use crate::mock::{MockClock, MockPwm, MockSource, MockTach};
+    use crate::thermistor::{Model, Thermistor};
+    use crate::CurvePoint;
+    use crate::PidGains;
+    use core::cell::Cell;
+
+    const SECOND: u64 = 1_000_000;
+    const FULL_SCALE: u32 = 4096;
+    /// 25 C through `thermistor()`
+    const MID_SCALE: u32 = 2048;
+
+    type TestPipeline<'a, const SENSORS: usize, const OUTPUTS: usize> = Pipeline<
+        Thermistor,
+        MovingAverage<Degrees, 4>,
+        MockPwm<'a>,
+        MockTach<'a>,
+        MockClock<'a>,
+        SENSORS,
+        OUTPUTS,
+    >;
+
+    fn thermistor() -> Thermistor {
+        Thermistor::new(Model::ntc_beta(10_000, Degrees::from_int(25), 3950), 10_000)
+    }
+
+    fn sensor() -> SensorChain<Thermistor, MovingAverage<Degrees, 4>> {
+        SensorChain::new(
+            thermistor(),
+            MovingAverage::new(),
+            SensorHealth::new(Degrees::from_int(-10), Degrees::from_int(100), 10 * SECOND),
+        )
+    }
+
+    /// 1000 at 20 C up to 3000 at 30 C
+    fn curve() -> PiecewiseCurve {
+        PiecewiseCurve::new(&[
+            CurvePoint::new(Degrees::from_int(20), 1000),
+            CurvePoint::new(Degrees::from_int(30), 3000),
+        ])
+        .unwrap()
+    }
+
+    fn output<'a>(pwm: &'a Cell<u16>, sensor: usize) -> OutputChain<MockPwm<'a>, MockTach<'a>> {
+        OutputChain::new(
+            MockPwm {
+                duty: pwm,
+                max_duty: 5000,
+            },
+            sensor,
+            curve(),
+            OutputStage::new(0, 0, 0, 0),
+            StallDetector::new(1500, 200, 5 * SECOND),
+        )
+    }
+
+    fn reading(counts: u32) -> Cell<RawReading> {
+        Cell::new(RawReading {
+            sum: counts * 16,
+            samples: 16,
+            full_scale: FULL_SCALE,
+        })
+    }
+
+    #[test]
+    fn sensor_to_output() {
+        let (pwm, clock, temp) = (Cell::new(0), Cell::new(0), reading(MID_SCALE));
+        let mut pipeline: TestPipeline<1, 1> =
+            Pipeline::new([sensor()], &[], [output(&pwm, 0)], MockClock(&clock)).unwrap();
+
+        // Flat out until the first reading
+        assert_eq!(pwm.get(), 5000);
+        assert_eq!(pipeline.status().duties, [5000]);
+
+        let status = *pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(status.readings, [Some(Degrees::from_int(25))]);
+        assert_eq!(status.sensors, [Some(SensorStatus::Ok)]);
+        assert_eq!(status.duties, [2000]);
+        assert_eq!(pwm.get(), 2000);
+
+        // Curves can be replaced while running
+        let quiet = PiecewiseCurve::new(&[
+            CurvePoint::new(Degrees::from_int(20), 500),
+            CurvePoint::new(Degrees::from_int(30), 1500),
+        ])
+        .unwrap();
+        pipeline.output_mut(0).unwrap().set_curve(quiet);
+        pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(pwm.get(), 1000);
+    }
+
+    #[test]
+    fn unhealthy_sensor_runs_flat_out() {
+        let (pwm, clock, temp) = (Cell::new(0), Cell::new(0), reading(MID_SCALE));
+        let mut pipeline: TestPipeline<1, 1> =
+            Pipeline::new([sensor()], &[], [output(&pwm, 0)], MockClock(&clock)).unwrap();
+
+        pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(pwm.get(), 2000);
+
+        // Pinned to the top rail, the thermistor is unplugged
+        temp.set(reading(FULL_SCALE - 1).get());
+        let status = *pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(status.sensors, [Some(SensorStatus::Open)]);
+        assert_eq!(status.readings, [None]);
+        // Full duty, not the top of the curve at 3000, there is no knowing how hot the loop is
+        assert_eq!(pwm.get(), 5000);
+        assert_eq!(status.duties, [5000]);
+
+        // Back to the curve once the sensor recovers
+        temp.set(reading(MID_SCALE).get());
+        pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(pwm.get(), 2000);
+    }
+
+    #[test]
+    fn limits_bound_the_curve_but_not_failsafes() {
+        let (pwm, clock, temp) = (Cell::new(0), Cell::new(0), reading(MID_SCALE));
+        let mut pipeline: TestPipeline<1, 1> = Pipeline::new(
+            [sensor()],
+            &[],
+            [output(&pwm, 0).with_limits(2500, 2800)],
+            MockClock(&clock),
+        )
+        .unwrap();
+
+        // 2000 from the curve at 25 C
+        pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(pwm.get(), 2500);
+
+        // A curve that would stop the output entirely
+        let off = PiecewiseCurve::new(&[
+            CurvePoint::new(Degrees::from_int(20), 0),
+            CurvePoint::new(Degrees::from_int(30), 0),
+        ])
+        .unwrap();
+        pipeline.output_mut(0).unwrap().set_curve(off);
+        pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(pwm.get(), 2500);
+
+        // 3000 at the top of the curve, held to the upper limit
+        pipeline.output_mut(0).unwrap().set_curve(curve());
+        temp.set(reading(FULL_SCALE / 4).get());
+        pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(pwm.get(), 2800);
+
+        temp.set(reading(FULL_SCALE - 1).get());
+        pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(pwm.get(), 5000);
+    }
+
+    #[test]
+    fn unhealthy_sensor_overrides_pid_limits() {
+        let (pwm, clock, temp) = (Cell::new(0), Cell::new(0), reading(MID_SCALE));
+        let pid = Pid::new(
+            Degrees::from_int(25),
+            PidGains::new(100.0, 0.0, 0.0),
+            1000,
+            3000,
+        );
+        let mut pipeline: TestPipeline<1, 1> = Pipeline::new(
+            [sensor()],
+            &[],
+            [output(&pwm, 0).with_pid(pid)],
+            MockClock(&clock),
+        )
+        .unwrap();
+
+        temp.set(reading(0).get());
+        let status = *pipeline.update(&mut [MockSource(&temp)]);
+        assert_eq!(status.sensors, [Some(SensorStatus::Short)]);
+        assert_eq!(pwm.get(), 5000);
+    }
+
+    #[test]
+    fn stall_runs_other_outputs_flat_out() {
+        let (fan, pump, pulses) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let (clock, temp) = (Cell::new(0), reading(MID_SCALE));
+        let stalling = output(&pump, 0).with_tach(MockTach(&pulses), Tachometer::new(2, SECOND));
+        let mut pipeline: TestPipeline<1, 2> = Pipeline::new(
+            [sensor()],
+            &[],
+            [output(&fan, 0), stalling],
+            MockClock(&clock),
+        )
+        .unwrap();
+
+        // The pump never turns while driven above the stall threshold
+        for second in 0..=6 {
+            clock.set(second * SECOND);
+            pipeline.update(&mut [MockSource(&temp)]);
+        }
+
+        let status = pipeline.status();
+        assert_eq!(status.fault, Some(Fault::Stall(1)));
+        assert_eq!(status.rpms, [None, Some(0)]);
+        assert_eq!(fan.get(), 5000);
+        // The stalled output keeps following its curve
+        assert_eq!(pump.get(), 2000);
+    }
+
+    #[test]
+    fn virtual_sensor_drives_output() {
+        static DELTA: [VirtualSensor; 1] = [VirtualSensor::Difference(0, 1)];
+        let (pwm, clock) = (Cell::new(0), Cell::new(0));
+        let (water, ambient) = (reading(MID_SCALE), reading(MID_SCALE));
+        let mut pipeline: TestPipeline<2, 1> = Pipeline::new(
+            [sensor(), sensor()],
+            &DELTA,
+            [output(&pwm, 2)],
+            MockClock(&clock),
+        )
+        .unwrap();
+
+        // No difference is below the curve's first point
+        pipeline.update(&mut [MockSource(&water), MockSource(&ambient)]);
+        assert_eq!(pwm.get(), 1000);
+
+        // Either side failing takes the difference with it
+        ambient.set(reading(0).get());
+        pipeline.update(&mut [MockSource(&water), MockSource(&ambient)]);
+        assert_eq!(pwm.get(), 5000);
+    }
+
+    #[test]
+    fn rejects_missing_readings() {
+        static BAD: [VirtualSensor; 1] = [VirtualSensor::Difference(0, 1)];
+        let (pwm, clock) = (Cell::new(0), Cell::new(0));
+
+        let past_end: Option<TestPipeline<1, 1>> =
+            Pipeline::new([sensor()], &[], [output(&pwm, 1)], MockClock(&clock));
+        assert!(past_end.is_none());
+        let bad_virtual: Option<TestPipeline<1, 1>> =
+            Pipeline::new([sensor()], &BAD, [output(&pwm, 0)], MockClock(&clock));
+        assert!(bad_virtual.is_none());
+    }
+}
@@ -0,0 +1,208 @@
+//! Resistance-temperature lookup tables, for sensors whose vendor only publishes an R-T table
+//!
+//! Points are interpolated linearly in ln(R), which follows the exponential shape of a thermistor curve
+//! far better than interpolating the resistance itself for the same table density.
+
+use super::thermistor::{ln, ConversionError};
+use super::Degrees;
+
+/// One row of a resistance-temperature table
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct RtPoint {
+    pub ohms: u32,
+    pub temp: Degrees,
+}
+
+impl RtPoint {
+    #[must_use]
+    pub const fn new(ohms: u32, temp: Degrees) -> Self {
+        Self { ohms, temp }
+    }
+}
+
+/// A validated resistance-temperature table
+///
+/// Rows must be ordered by strictly increasing temperature, with the resistance strictly monotonic in either direction
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct RtTable {
+    points: &'static [RtPoint],
+}
+
+impl RtTable {
+    /// Validate and wrap a table, returning None if it has fewer than 2 rows or is not monotonic
+    #[must_use]
+    pub const fn new(points: &'static [RtPoint]) -> Option<Self> {
+        if points.len() < 2 {
+            return None;
+        }
+
+        let descending = points[0].ohms > points[1].ohms;
+        let mut i = 1;
+        while i < points.len() {
+            let (prev, next) = (points[i - 1], points[i]);
+            if next.temp.0 <= prev.temp.0 {
+                return None;
+            }
+            if descending && next.ohms >= prev.ohms || !descending && next.ohms <= prev.ohms {
+                return None;
+            }
+            i += 1;
+        }
+
+        Some(Self { points })
+    }
+
+    #[must_use]
+    pub const fn points(&self) -> &'static [RtPoint] {
+        self.points
+    }
+
+    /// Look up the temperature for a resistance, in F12 ohms
+    ///
+    /// # Errors
+    /// * `OutOfTable` if the resistance lies outside of the first and last rows
+    pub fn degrees_from_ohms(&self, ohms: u64) -> Result<Degrees, ConversionError> {
+        let points = self.points;
+        let descending = points[0].ohms > points[1].ohms;
+        let fixed = |p: &RtPoint| u64::from(p.ohms) << 12;
+
+        // First row at or past the reading, in the direction of the table
+        let idx = points.partition_point(|p| {
+            if descending {
+                fixed(p) > ohms
+            } else {
+                fixed(p) < ohms
+            }
+        });
+
+        if idx == points.len() {
+            return Err(ConversionError::OutOfTable);
+        }
+        if idx == 0 {
+            return if fixed(&points[0]) == ohms {
+                Ok(points[0].temp)
+            } else {
+                Err(ConversionError::OutOfTable)
+            };
+        }
+
+        let (lo, hi) = (points[idx - 1], points[idx]);
+        let ln_lo = ln(fixed(&lo));
+        let span = ln(fixed(&hi)) - ln_lo;
+        let offset = ln(ohms) - ln_lo;
+
+        let delta = i64::from(hi.temp.0) - i64::from(lo.temp.0);
+        let temp = i64::from(lo.temp.0) + delta * offset / span;
+
+        // Bounded by the two rows, so always fits
+        i32::try_from(temp)
+            .map(Degrees)
+            .map_err(|_e| ConversionError::OutOfRange)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thermistor::{Model, Placement, Thermistor};
+
+    /// Subset of the 10K3 (type II) table
+    static TEN_K3: [RtPoint; 6] = [
+        RtPoint::new(32_650, Degrees::from_int(0)),
+        RtPoint::new(19_900, Degrees::from_int(10)),
+        RtPoint::new(10_000, Degrees::from_int(25)),
+        RtPoint::new(3_602, Degrees::from_int(50)),
+        RtPoint::new(1_480, Degrees::from_int(75)),
+        RtPoint::new(678, Degrees::from_int(100)),
+    ];
+
+    fn assert_close(actual: Degrees, expected_centi: i32) {
+        let expected = (expected_centi << 12) / 100;
+        assert!(
+            (actual.0 - expected).abs() <= 41,
+            "got {} (F12), expected {} (F12)",
+            actual.0,
+            expected
+        );
+    }
+
+    #[test]
+    fn rows_are_exact() {
+        let table = RtTable::new(&TEN_K3).unwrap();
+        for point in TEN_K3 {
+            assert_eq!(
+                table.degrees_from_ohms(u64::from(point.ohms) << 12),
+                Ok(point.temp)
+            );
+        }
+    }
+
+    #[test]
+    fn interpolates_in_log_domain() {
+        let table = RtTable::new(&TEN_K3).unwrap();
+
+        assert_close(table.degrees_from_ohms(5_971 << 12).unwrap(), 3763);
+        assert_close(table.degrees_from_ohms(2_240 << 12).unwrap(), 6335);
+        assert_close(table.degrees_from_ohms(25_000 << 12).unwrap(), 539);
+        assert_close(table.degrees_from_ohms(800 << 12).unwrap(), 9470);
+    }
+
+    #[test]
+    fn out_of_table() {
+        let table = RtTable::new(&TEN_K3).unwrap();
+
+        assert_eq!(
+            table.degrees_from_ohms(40_000 << 12),
+            Err(ConversionError::OutOfTable)
+        );
+        assert_eq!(
+            table.degrees_from_ohms(500 << 12),
+            Err(ConversionError::OutOfTable)
+        );
+    }
+
+    #[test]
+    fn ascending_tables() {
+        static PTC: [RtPoint; 3] = [
+            RtPoint::new(1_000, Degrees::from_int(0)),
+            RtPoint::new(1_385, Degrees::from_int(100)),
+            RtPoint::new(1_758, Degrees::from_int(200)),
+        ];
+        let table = RtTable::new(&PTC).unwrap();
+
+        assert_eq!(table.degrees_from_ohms(1_385 << 12), Ok(Degrees::from_int(100)));
+        assert!(table.degrees_from_ohms(1_200 << 12).unwrap() > Degrees::from_int(50));
+        assert_eq!(
+            table.degrees_from_ohms(999 << 12),
+            Err(ConversionError::OutOfTable)
+        );
+    }
+
+    #[test]
+    fn rejects_non_monotonic() {
+        static SHORT: [RtPoint; 1] = [RtPoint::new(10_000, Degrees::from_int(25))];
+        static BACKWARDS: [RtPoint; 2] = [
+            RtPoint::new(10_000, Degrees::from_int(25)),
+            RtPoint::new(32_650, Degrees::from_int(0)),
+        ];
+        static KINKED: [RtPoint; 3] = [
+            RtPoint::new(32_650, Degrees::from_int(0)),
+            RtPoint::new(10_000, Degrees::from_int(25)),
+            RtPoint::new(19_900, Degrees::from_int(50)),
+        ];
+
+        assert!(RtTable::new(&SHORT).is_none());
+        assert!(RtTable::new(&BACKWARDS).is_none());
+        assert!(RtTable::new(&KINKED).is_none());
+    }
+
+    #[test]
+    fn through_divider() {
+        let sensor = Thermistor::new(Model::Table(RtTable::new(&TEN_K3).unwrap()), 10_000)
+            .with_placement(Placement::LowSide);
+
+        // Mid scale is 10k
+        assert_eq!(sensor.degrees(2048), Ok(Degrees::from_int(25)));
+        assert_eq!(sensor.degrees(4000), Err(ConversionError::OutOfTable));
+    }
+}
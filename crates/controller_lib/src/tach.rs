@@ -0,0 +1,88 @@
+//! Fan tachometer signal processing
+
+/// Turns a free running count of tach pulses into RPM, averaged over a measurement window
+///
+/// The tach signal of a PC fan is a handful of pulses per revolution, so short windows give very coarse readings.
+/// RPM is only recalculated once the window has elapsed.
+pub struct Tachometer {
+    pulses_per_rev: u8,
+    window_us: u64,
+    /// Pulse count and timestamp at the start of the current window, None before the first update
+    window_start: Option<(u32, u64)>,
+    rpm: u32,
+}
+
+impl Tachometer {
+    /// Create a tachometer for a fan that gives `pulses_per_rev` pulses each revolution, 2 for standard PC fans
+    #[must_use]
+    pub const fn new(pulses_per_rev: u8, window_us: u64) -> Self {
+        Self {
+            pulses_per_rev,
+            window_us,
+            window_start: None,
+            rpm: 0,
+        }
+    }
+
+    /// Update with the pulse counter as of `now_us`, returning the current RPM
+    ///
+    /// The counter is free running and allowed to wrap
+    pub fn update(&mut self, pulses: u32, now_us: u64) -> u32 {
+        let Some((start_pulses, start_us)) = self.window_start else {
+            self.window_start = Some((pulses, now_us));
+            return self.rpm;
+        };
+
+        let elapsed = now_us.saturating_sub(start_us);
+        if elapsed >= self.window_us && elapsed > 0 && self.pulses_per_rev > 0 {
+            let counted = u64::from(pulses.wrapping_sub(start_pulses));
+            let rpm = counted * 60_000_000 / (elapsed * u64::from(self.pulses_per_rev));
+
+            self.rpm = u32::try_from(rpm).unwrap_or(u32::MAX);
+            self.window_start = Some((pulses, now_us));
+        }
+
+        self.rpm
+    }
+
+    /// RPM over the last complete window
+    #[must_use]
+    pub const fn rpm(&self) -> u32 {
+        self.rpm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_over_window() {
+        let mut tach = Tachometer::new(2, 1_000_000);
+
+        assert_eq!(tach.update(0, 0), 0);
+        // 40 pulses over half a window is not a reading yet
+        assert_eq!(tach.update(20, 500_000), 0);
+        // 40 pulses a second at 2 per revolution is 1200 RPM
+        assert_eq!(tach.update(40, 1_000_000), 1200);
+        assert_eq!(tach.rpm(), 1200);
+    }
+
+    #[test]
+    fn stopped_fan_reads_zero() {
+        let mut tach = Tachometer::new(2, 1_000_000);
+
+        tach.update(100, 0);
+        assert_eq!(tach.update(140, 1_000_000), 1200);
+        assert_eq!(tach.update(140, 2_000_000), 0);
+    }
+
+    #[test]
+    fn counter_wraps() {
+        let mut tach = Tachometer::new(4, 1_000_000);
+
+        tach.update(u32::MAX - 9, 0);
+        // 80 pulses at 4 per revolution over a second
+        assert_eq!(tach.update(70, 1_000_000), 1200);
+    }
+}
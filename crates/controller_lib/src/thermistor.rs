@@ -0,0 +1,427 @@
+//! Analytic thermistor models for converting ADC readings of a voltage divider into temperatures
+//!
+//! Everything here is fixed point so that it is usable on the M0+ without pulling in soft float routines.
+//! Resistances are carried as F12 ohms, natural logs as F24 and inverse temperatures (and Steinhart-Hart
+//! coefficients) as F56 per kelvin.
+
+use super::{Degrees, RtTable};
+
+/// Fractional bits of natural log intermediates
+const LN_FRAC: u32 = 24;
+/// Fractional bits of resistances
+const OHMS_FRAC: u32 = 12;
+/// Fractional bits of inverse temperatures and the Steinhart-Hart coefficients
+pub const COEFF_FRAC: u32 = 56;
+
+/// ln(2) in F32
+const LN2_F32: i64 = 2_977_044_472;
+/// 0 degrees C in kelvin, F12
+const ZERO_C_KELVIN: i64 = 1_118_822;
+
+/// Reasons a reading could not be turned into a temperature
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum ConversionError {
+    /// Divider is pinned at the rail that an infinite thermistor resistance would give
+    OpenCircuit,
+    /// Divider is pinned at the rail that a zero thermistor resistance would give
+    ShortCircuit,
+    /// The model produced a temperature that can not be represented
+    OutOfRange,
+    /// The resistance lies outside of the lookup table, distinct from `OutOfRange` as the sensor may well be fine
+    OutOfTable,
+}
+
+/// Conversion of raw ADC readings of a sensor into temperatures
+///
+/// Implemented by each of the ways a sensor can be described, so the control loop can be configured with any of them
+pub trait AdcConversion {
+    /// Convert a reading, at whichever resolution the implementation was configured for
+    ///
+    /// # Errors
+    /// * Any `ConversionError` that applies to the implementation
+    fn convert(&self, counts: u32) -> Result<Degrees, ConversionError>;
+
+    /// Whether a reading pinned at the top rail means the sensor is open rather than shorted, as it does for the
+    /// controller board's wiring
+    fn open_at_top_rail(&self) -> bool {
+        true
+    }
+}
+
+/// The original two point fit of the controller board thermistor, see `impl From<i64> for Degrees`
+///
+/// Takes 12 bit readings and never fails
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub struct LinearFit;
+
+impl AdcConversion for LinearFit {
+    fn convert(&self, counts: u32) -> Result<Degrees, ConversionError> {
+        Ok(Degrees::from(i64::from(counts)))
+    }
+}
+
+/// Which half of the voltage divider the thermistor sits in
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Placement {
+    /// Thermistor between the ADC pin and ground, series resistor to VDD. This is how the controller board is wired.
+    LowSide,
+    /// Thermistor between VDD and the ADC pin, series resistor to ground
+    HighSide,
+}
+
+/// Direction of the resistance change with temperature, only meaningful for the Beta model as
+/// Steinhart-Hart coefficients carry their own sign
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Response {
+    /// Negative temperature coefficient, resistance falls as temperature rises
+    Ntc,
+    /// Positive temperature coefficient, resistance rises with temperature
+    Ptc,
+}
+
+/// Temperature/resistance relationship of the sensing element
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Model {
+    /// Single parameter model as printed on most datasheets, IE "10k 3950"
+    Beta {
+        /// Nominal resistance in ohms at `t0`
+        r0: u32,
+        /// Temperature at which the element measures `r0`, usually 25C
+        t0: Degrees,
+        /// B constant in kelvin
+        beta: u16,
+        response: Response,
+    },
+    /// Full three term Steinhart-Hart equation, `1/T = A + B*ln(R) + C*ln(R)^3`
+    SteinhartHart {
+        /// F56 per kelvin
+        a: i64,
+        /// F56 per kelvin
+        b: i64,
+        /// F56 per kelvin
+        c: i64,
+    },
+    /// Vendor resistance-temperature table
+    Table(RtTable),
+}
+
+impl Model {
+    /// Create a Beta model for a negative temperature coefficient part
+    #[must_use]
+    pub const fn ntc_beta(r0: u32, t0: Degrees, beta: u16) -> Self {
+        Self::Beta {
+            r0,
+            t0,
+            beta,
+            response: Response::Ntc,
+        }
+    }
+
+    /// Create a Steinhart-Hart model from the coefficients as published
+    ///
+    /// Intended to be used in const context so the float math never makes it into the firmware
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    pub const fn steinhart_hart(a: f64, b: f64, c: f64) -> Self {
+        let scale = (1u64 << COEFF_FRAC) as f64;
+        Self::SteinhartHart {
+            a: (a * scale) as i64,
+            b: (b * scale) as i64,
+            c: (c * scale) as i64,
+        }
+    }
+
+    /// Evaluate the model for a resistance, in F12 ohms
+    ///
+    /// # Errors
+    /// * `ShortCircuit` if the resistance is 0
+    /// * `OutOfRange` if the model does not produce a representable temperature
+    /// * `OutOfTable` if the resistance is not covered by a `Table` model
+    pub fn degrees_from_ohms(&self, ohms: u64) -> Result<Degrees, ConversionError> {
+        if ohms == 0 {
+            return Err(ConversionError::ShortCircuit);
+        }
+        let ln_r = ln(ohms) - ln_scale(OHMS_FRAC);
+
+        let inv_t: i128 = match *self {
+            Self::Table(ref table) => return table.degrees_from_ohms(ohms),
+            Self::Beta {
+                r0,
+                t0,
+                beta,
+                response,
+            } => {
+                let t0_kelvin = i128::from(t0.0) + i128::from(ZERO_C_KELVIN);
+                if t0_kelvin <= 0 || beta == 0 || r0 == 0 {
+                    return Err(ConversionError::OutOfRange);
+                }
+                let inv_t0 = (1i128 << (COEFF_FRAC + 12)) / t0_kelvin;
+
+                // ln(R/R0) / B, F24 shifted up to F56
+                let ln_ratio = i128::from(ln_r - ln(u64::from(r0)));
+                let delta = (ln_ratio << (COEFF_FRAC - LN_FRAC)) / i128::from(beta);
+
+                match response {
+                    Response::Ntc => inv_t0 + delta,
+                    Response::Ptc => inv_t0 - delta,
+                }
+            }
+            Self::SteinhartHart { a, b, c } => {
+                let ln_r = i128::from(ln_r);
+                let ln_r3 = ln_r * ln_r * ln_r;
+
+                let b_term = (i128::from(b) * ln_r) >> LN_FRAC;
+                let c_term = i128::from(c)
+                    .checked_mul(ln_r3)
+                    .ok_or(ConversionError::OutOfRange)?
+                    >> (3 * LN_FRAC);
+
+                i128::from(a) + b_term + c_term
+            }
+        };
+
+        if inv_t <= 0 {
+            return Err(ConversionError::OutOfRange);
+        }
+
+        // Back to F12 kelvin, then celsius
+        let kelvin = (1i128 << (COEFF_FRAC + 12)) / inv_t;
+        let celsius = kelvin - i128::from(ZERO_C_KELVIN);
+
+        i32::try_from(celsius)
+            .map(Degrees)
+            .map_err(|_e| ConversionError::OutOfRange)
+    }
+}
+
+/// A thermistor in a voltage divider read by an ADC referenced to the divider supply
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Thermistor {
+    pub model: Model,
+    /// Fixed resistor making up the other half of the divider, in ohms
+    pub series_ohms: u32,
+    pub placement: Placement,
+    /// Resolution of the readings passed to `degrees`, 12 for a single RP2040 conversion
+    pub adc_bits: u8,
+}
+
+impl Thermistor {
+    /// Create a thermistor wired the same as the controller board, on the low side of the divider and read with
+    /// the 12 bit ADC
+    #[must_use]
+    pub const fn new(model: Model, series_ohms: u32) -> Self {
+        Self {
+            model,
+            series_ohms,
+            placement: Placement::LowSide,
+            adc_bits: 12,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_placement(mut self, placement: Placement) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_adc_bits(mut self, adc_bits: u8) -> Self {
+        self.adc_bits = adc_bits;
+        self
+    }
+
+    /// Solve the divider for the thermistor resistance, in F12 ohms
+    ///
+    /// # Errors
+    /// * `OpenCircuit` or `ShortCircuit` if the reading is at either rail
+    pub fn resistance(&self, counts: u32) -> Result<u64, ConversionError> {
+        let full_scale: u64 = 1 << self.adc_bits;
+        let counts = u64::from(counts);
+        let series = u64::from(self.series_ohms) << OHMS_FRAC;
+
+        // Rt / (Rs + Rt) = counts / full_scale for the low side, and the complement for the high side
+        let (top, bottom) = match self.placement {
+            Placement::LowSide => (counts, full_scale.saturating_sub(counts)),
+            Placement::HighSide => (full_scale.saturating_sub(counts), counts),
+        };
+
+        match (top, bottom) {
+            (0, _) => Err(ConversionError::ShortCircuit),
+            (_, 0) => Err(ConversionError::OpenCircuit),
+            _ => Ok(series * top / bottom),
+        }
+    }
+
+    /// Convert an ADC reading of the divider to a temperature
+    ///
+    /// # Errors
+    /// * Any of `ConversionError`, see `resistance` and `Model::degrees_from_ohms`
+    pub fn degrees(&self, counts: u32) -> Result<Degrees, ConversionError> {
+        self.model.degrees_from_ohms(self.resistance(counts)?)
+    }
+}
+
+impl AdcConversion for Thermistor {
+    fn convert(&self, counts: u32) -> Result<Degrees, ConversionError> {
+        self.degrees(counts)
+    }
+
+    fn open_at_top_rail(&self) -> bool {
+        self.placement == Placement::LowSide
+    }
+}
+
+/// Fixed point log2 of an integer, returned as F24
+fn log2(val: u64) -> i64 {
+    let int = 63 - val.leading_zeros();
+
+    // Normalize to a mantissa in [1, 2), F31
+    let mut mantissa: u64 = if int > 31 {
+        val >> (int - 31)
+    } else {
+        val << (31 - int)
+    };
+
+    // Squaring the mantissa doubles its log, so each overflow past 2 is the next fractional bit
+    let mut frac: i64 = 0;
+    for bit in (0..LN_FRAC).rev() {
+        mantissa = (mantissa * mantissa) >> 31;
+        if mantissa >= 2 << 31 {
+            mantissa >>= 1;
+            frac |= 1 << bit;
+        }
+    }
+
+    (i64::from(int) << LN_FRAC) | frac
+}
+
+/// Fixed point natural log of an integer, returned as F24
+pub(crate) fn ln(val: u64) -> i64 {
+    (log2(val) * LN2_F32) >> 32
+}
+
+/// ln(2^`bits`) in F24, to remove the scaling of a fixed point value passed to `ln`
+fn ln_scale(bits: u32) -> i64 {
+    (i64::from(bits) * LN2_F32) >> (32 - LN_FRAC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Five hundredths of a degree, covers the table resistances being rounded to whole ohms
+    const TOLERANCE: i32 = 205;
+
+    fn assert_close(actual: Degrees, expected_centi: i32) {
+        let expected = (expected_centi << 12) / 100;
+        assert!(
+            (actual.0 - expected).abs() <= TOLERANCE,
+            "got {} (F12), expected {} (F12)",
+            actual.0,
+            expected
+        );
+    }
+
+    #[test]
+    fn ln_matches_reference() {
+        // ln(10000) = 9.21034
+        assert_eq!(ln(10_000) >> 14, (9_210_340 << 10) / 1_000_000);
+        assert_eq!(ln(1), 0);
+        assert_eq!(ln_scale(1), ln(2));
+    }
+
+    #[test]
+    fn beta_3950_curve() {
+        let model = Model::ntc_beta(10_000, Degrees::from_int(25), 3950);
+
+        // Ohms against hundredths of a degree, from R = R0 * exp(B * (1/T - 1/T0))
+        for (ohms, centi) in [
+            (105_385, -2000),
+            (33_621, 0),
+            (10_000, 2500),
+            (3_588, 5000),
+            (1_087, 8500),
+            (698, 10000),
+        ] {
+            assert_close(model.degrees_from_ohms(ohms << 12).unwrap(), centi);
+        }
+    }
+
+    #[test]
+    fn beta_ptc_mirrors_ntc() {
+        let ntc = Model::ntc_beta(10_000, Degrees::from_int(25), 3950);
+        let Model::Beta { r0, t0, beta, .. } = ntc else {
+            unreachable!()
+        };
+        let ptc = Model::Beta {
+            r0,
+            t0,
+            beta,
+            response: Response::Ptc,
+        };
+
+        // Resistance rising with temperature for the PTC by the same ratio, so R0^2 / R reads the same as the NTC
+        assert_close(ptc.degrees_from_ohms(2_974 << 12).unwrap(), 0);
+        assert_close(ptc.degrees_from_ohms(10_000 << 12).unwrap(), 2500);
+        assert_close(ptc.degrees_from_ohms(27_870 << 12).unwrap(), 5000);
+    }
+
+    #[test]
+    fn steinhart_hart_10k3_table() {
+        // Coefficients from the 0/25/100C points of the 10K3 (type II) R-T table
+        let model = Model::steinhart_hart(1.130_278_567e-3, 2.339_481_978e-4, 8.830_650_541e-8);
+
+        // Remaining table points, which the fit must also land on
+        for (ohms, centi) in [
+            (32_650, 0),
+            (19_900, 1000),
+            (10_000, 2500),
+            (3_602, 5000),
+            (1_480, 7500),
+            (678, 10000),
+        ] {
+            let t = model.degrees_from_ohms(ohms << 12).unwrap();
+            assert!(
+                (t.0 - (centi << 12) / 100).abs() <= 410,
+                "{ohms} ohms gave {} (F12)",
+                t.0
+            );
+        }
+    }
+
+    #[test]
+    fn divider_placement() {
+        let model = Model::ntc_beta(10_000, Degrees::from_int(25), 3950);
+        let low = Thermistor::new(model, 10_000);
+        let high = low.with_placement(Placement::HighSide);
+
+        // Mid scale is Rt == Rs either way
+        assert_eq!(low.resistance(2048), Ok(10_000 << 12));
+        assert_eq!(high.resistance(2048), Ok(10_000 << 12));
+        assert_close(low.degrees(2048).unwrap(), 2500);
+
+        // A quarter of full scale is Rt == Rs / 3 on the low side and Rt == 3 * Rs on the high side
+        assert_eq!(low.resistance(1024).unwrap() >> 12, 3333);
+        assert_eq!(high.resistance(1024).unwrap() >> 12, 30_000);
+    }
+
+    #[test]
+    fn oversampled_readings() {
+        let model = Model::ntc_beta(100_000, Degrees::from_int(25), 3950);
+        let thermistor = Thermistor::new(model, 100_000).with_adc_bits(16);
+
+        assert_close(thermistor.degrees(1 << 15).unwrap(), 2500);
+    }
+
+    #[test]
+    fn rails_are_faults() {
+        let model = Model::ntc_beta(10_000, Degrees::from_int(25), 3950);
+        let low = Thermistor::new(model, 10_000);
+        let high = low.with_placement(Placement::HighSide);
+
+        assert_eq!(low.degrees(0), Err(ConversionError::ShortCircuit));
+        assert_eq!(low.degrees(4096), Err(ConversionError::OpenCircuit));
+        assert_eq!(high.degrees(0), Err(ConversionError::OpenCircuit));
+        assert_eq!(high.degrees(4096), Err(ConversionError::ShortCircuit));
+    }
+}
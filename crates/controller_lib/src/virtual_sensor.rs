@@ -0,0 +1,169 @@
+//! Sensors derived from physical readings, such as water temperature minus ambient
+
+use super::Degrees;
+
+/// A reading computed from other readings, by index into the caller's array of them
+///
+/// Evaluates to None whenever any of its sources has no reading, so a failed probe falls through to the consumer's
+/// failsafe rather than quietly skewing the result.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum VirtualSensor {
+    /// First source minus second, e.g. water minus ambient
+    Difference(usize, usize),
+    /// Hottest of the sources
+    Max(&'static [usize]),
+    /// Coolest of the sources
+    Min(&'static [usize]),
+    /// Average of the sources, each with a relative weight
+    WeightedAverage(&'static [(usize, u16)]),
+}
+
+impl VirtualSensor {
+    /// Whether every source index is below `count`, and there is at least one source
+    #[must_use]
+    pub fn is_valid(&self, count: usize) -> bool {
+        match self {
+            Self::Difference(a, b) => *a < count && *b < count,
+            Self::Max(sources) | Self::Min(sources) => {
+                !sources.is_empty() && sources.iter().all(|s| *s < count)
+            }
+            Self::WeightedAverage(sources) => {
+                sources.iter().any(|(_, weight)| *weight > 0)
+                    && sources.iter().all(|(s, _)| *s < count)
+            }
+        }
+    }
+
+    /// Compute the reading from the current readings of its sources
+    #[must_use]
+    pub fn evaluate(&self, readings: &[Option<Degrees>]) -> Option<Degrees> {
+        let reading = |index: &usize| readings.get(*index).copied().flatten();
+
+        match self {
+            Self::Difference(a, b) => Some(Degrees(reading(a)?.0.saturating_sub(reading(b)?.0))),
+            Self::Max(sources) => extreme(sources.iter().map(reading), |r, best| r > best),
+            Self::Min(sources) => extreme(sources.iter().map(reading), |r, best| r < best),
+            Self::WeightedAverage(sources) => {
+                let mut sum = 0i64;
+                let mut weights = 0i64;
+                for (index, weight) in *sources {
+                    sum += i64::from(reading(index)?.0) * i64::from(*weight);
+                    weights += i64::from(*weight);
+                }
+                if weights == 0 {
+                    return None;
+                }
+                // Between the lowest and highest source, so always fits
+                i32::try_from(sum / weights).ok().map(Degrees)
+            }
+        }
+    }
+}
+
+/// The reading `better` prefers over all others, None if there are none or any is missing
+fn extreme(
+    readings: impl Iterator<Item = Option<Degrees>>,
+    better: impl Fn(Degrees, Degrees) -> bool,
+) -> Option<Degrees> {
+    let mut best = None;
+    for reading in readings {
+        let reading = reading?;
+        if best.is_none_or(|best| better(reading, best)) {
+            best = Some(reading);
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WATER: usize = 0;
+    const AMBIENT: usize = 1;
+    const GPU_OUT: usize = 2;
+
+    fn readings() -> [Option<Degrees>; 4] {
+        [
+            Some(Degrees::from_int(34)),
+            Some(Degrees::from_int(24)),
+            Some(Degrees::from_int(38)),
+            None,
+        ]
+    }
+
+    #[test]
+    fn delta_t() {
+        let delta = VirtualSensor::Difference(WATER, AMBIENT);
+
+        assert_eq!(delta.evaluate(&readings()), Some(Degrees::from_int(10)));
+        // Negative when the water is below ambient
+        assert_eq!(
+            VirtualSensor::Difference(AMBIENT, WATER).evaluate(&readings()),
+            Some(Degrees::from_int(-10))
+        );
+    }
+
+    #[test]
+    fn extremes() {
+        static PROBES: [usize; 3] = [WATER, AMBIENT, GPU_OUT];
+
+        assert_eq!(
+            VirtualSensor::Max(&PROBES).evaluate(&readings()),
+            Some(Degrees::from_int(38))
+        );
+        assert_eq!(
+            VirtualSensor::Min(&PROBES).evaluate(&readings()),
+            Some(Degrees::from_int(24))
+        );
+    }
+
+    #[test]
+    fn weighted_average() {
+        static LOOP: [(usize, u16); 2] = [(WATER, 3), (GPU_OUT, 1)];
+
+        assert_eq!(
+            VirtualSensor::WeightedAverage(&LOOP).evaluate(&readings()),
+            Some(Degrees::from_int(35))
+        );
+    }
+
+    #[test]
+    fn missing_source_is_missing_reading() {
+        static WITH_MISSING: [usize; 2] = [WATER, 3];
+        static WEIGHTED: [(usize, u16); 2] = [(WATER, 1), (3, 1)];
+
+        assert_eq!(
+            VirtualSensor::Difference(WATER, 3).evaluate(&readings()),
+            None
+        );
+        assert_eq!(
+            VirtualSensor::Max(&WITH_MISSING).evaluate(&readings()),
+            None
+        );
+        assert_eq!(
+            VirtualSensor::Min(&WITH_MISSING).evaluate(&readings()),
+            None
+        );
+        assert_eq!(
+            VirtualSensor::WeightedAverage(&WEIGHTED).evaluate(&readings()),
+            None
+        );
+        // Out of range too
+        assert_eq!(
+            VirtualSensor::Difference(WATER, 9).evaluate(&readings()),
+            None
+        );
+    }
+
+    #[test]
+    fn validates_sources() {
+        static EMPTY: [usize; 0] = [];
+        static WEIGHTLESS: [(usize, u16); 1] = [(WATER, 0)];
+
+        assert!(VirtualSensor::Difference(WATER, AMBIENT).is_valid(2));
+        assert!(!VirtualSensor::Difference(WATER, GPU_OUT).is_valid(2));
+        assert!(!VirtualSensor::Max(&EMPTY).is_valid(2));
+        assert!(!VirtualSensor::WeightedAverage(&WEIGHTLESS).is_valid(2));
+    }
+}
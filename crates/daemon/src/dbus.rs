@@ -0,0 +1,133 @@
+//! Readings as properties of a D-Bus object, with change notifications on every poll
+
+use crate::readings::Readings;
+use controller_client::text::{fault_description, status_name};
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::blocking::{connection, Connection};
+use zbus::interface;
+
+/// Well known name the daemon owns
+pub const NAME: &str = "org.dexorg.FanController";
+/// Object the readings are properties of
+pub const PATH: &str = "/org/dexorg/FanController";
+
+/// Which bus to publish on
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum BusKind {
+    System,
+    Session,
+}
+
+/// The `org.dexorg.FanController1` interface
+struct FanController {
+    readings: Option<Readings>,
+}
+
+#[interface(name = "org.dexorg.FanController1")]
+impl FanController {
+    /// Whether the controller is connected, every other property is empty while it is not
+    #[zbus(property)]
+    fn connected(&self) -> bool {
+        self.readings.is_some()
+    }
+
+    /// Degrees C by sensor, NaN if the sensor has no reading
+    #[zbus(property)]
+    fn temperatures(&self) -> Vec<f64> {
+        self.readings
+            .iter()
+            .flat_map(|readings| &readings.sensors)
+            .map(|sensor| {
+                sensor
+                    .millidegrees
+                    .map_or(f64::NAN, |millidegrees| f64::from(millidegrees) / 1000.0)
+            })
+            .collect()
+    }
+
+    /// Health by sensor: ok, open, short, out-of-range or stuck
+    #[zbus(property)]
+    fn sensor_status(&self) -> Vec<String> {
+        self.readings
+            .iter()
+            .flat_map(|readings| &readings.sensors)
+            .map(|sensor| status_name(sensor.status).to_owned())
+            .collect()
+    }
+
+    /// Duty cycle in percent by output
+    #[zbus(property)]
+    fn duties(&self) -> Vec<f64> {
+        self.readings
+            .iter()
+            .flat_map(|readings| &readings.status.outputs)
+            .map(|output| f64::from(output.duty_permille) / 10.0)
+            .collect()
+    }
+
+    /// Fan speed by output, 0 if the output has no tach
+    #[zbus(property)]
+    fn rpms(&self) -> Vec<u32> {
+        self.readings
+            .iter()
+            .flat_map(|readings| &readings.status.outputs)
+            .map(|output| output.rpm.unwrap_or(0))
+            .collect()
+    }
+
+    /// Latched fault, empty if there is none
+    #[zbus(property)]
+    fn fault(&self) -> String {
+        self.readings
+            .as_ref()
+            .and_then(|readings| readings.status.fault)
+            .map(fault_description)
+            .unwrap_or_default()
+    }
+}
+
+/// The daemon's connection to the bus, owning `NAME`
+pub struct Bus {
+    _connection: Connection,
+    interface: InterfaceRef<FanController>,
+}
+
+impl Bus {
+    /// Connect to the bus and serve the interface at `PATH`
+    ///
+    /// # Errors
+    /// * If there is no such bus, or `NAME` is owned by someone else or not ours to own
+    pub fn connect(kind: BusKind) -> zbus::Result<Self> {
+        let builder = match kind {
+            BusKind::System => connection::Builder::system()?,
+            BusKind::Session => connection::Builder::session()?,
+        };
+        let connection = builder
+            .name(NAME)?
+            .serve_at(PATH, FanController { readings: None })?
+            .build()?;
+        let interface = connection.object_server().interface(PATH)?;
+        Ok(Self {
+            _connection: connection,
+            interface,
+        })
+    }
+
+    /// Publish `readings`, `None` while the controller is not connected
+    ///
+    /// # Errors
+    /// * If the change notifications can not be sent
+    pub fn publish(&self, readings: Option<&Readings>) -> zbus::Result<()> {
+        let mut controller = self.interface.get_mut();
+        controller.readings = readings.cloned();
+        let emitter = self.interface.signal_emitter();
+        async_io::block_on(async {
+            controller.connected_changed(emitter).await?;
+            controller.temperatures_changed(emitter).await?;
+            controller.sensor_status_changed(emitter).await?;
+            controller.duties_changed(emitter).await?;
+            controller.rpms_changed(emitter).await?;
+            controller.fault_changed(emitter).await
+        })
+    }
+}
@@ -0,0 +1,171 @@
+//! Readings as a directory of hwmon-style attribute files, and as text on a Unix socket
+
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A directory holding one file per attribute, laid out like a hwmon device under `/sys/class/hwmon`
+pub struct Directory {
+    path: PathBuf,
+    /// Attributes written by the last `publish`
+    written: BTreeSet<String>,
+}
+
+impl Directory {
+    /// Create the directory if needed
+    ///
+    /// # Errors
+    /// * If it can not be created
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        Ok(Self {
+            path,
+            written: BTreeSet::new(),
+        })
+    }
+
+    /// Replace the published attributes with `attributes`
+    ///
+    /// Each file is replaced atomically, readers never see a partly written value. Files of attributes no longer
+    /// present are removed.
+    ///
+    /// # Errors
+    /// * If a file can not be written or removed
+    pub fn publish(&mut self, attributes: &[(String, String)]) -> io::Result<()> {
+        let mut written = BTreeSet::new();
+        for (name, value) in attributes {
+            let temporary = self.path.join(format!(".{name}"));
+            std::fs::write(&temporary, format!("{value}\n"))?;
+            std::fs::rename(&temporary, self.path.join(name))?;
+            written.insert(name.clone());
+        }
+        for stale in self.written.difference(&written) {
+            match std::fs::remove_file(self.path.join(stale)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        self.written = written;
+        Ok(())
+    }
+}
+
+/// A Unix socket sending the latest readings as text to every client that connects, then closing the connection
+pub struct Socket {
+    path: PathBuf,
+    text: Arc<Mutex<String>>,
+}
+
+impl Socket {
+    /// Listen at `path`, replacing a socket left behind by an earlier run. Anything else at `path` is left alone.
+    ///
+    /// # Errors
+    /// * If something other than a socket is at `path`
+    /// * If the socket can not be bound
+    pub fn bind(path: PathBuf) -> io::Result<Self> {
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        let text = Arc::new(Mutex::new(String::new()));
+
+        let served = Arc::clone(&text);
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let text = served
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone();
+                // A client that hangs up early only misses this copy
+                let _ = stream.write_all(text.as_bytes());
+            }
+        });
+        Ok(Self { path, text })
+    }
+
+    /// Path the socket listens at
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serve `text` to clients from now on
+    pub fn publish(&self, text: String) {
+        *self.text.lock().unwrap_or_else(PoisonError::into_inner) = text;
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    fn attribute(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn publishes_files_and_socket() {
+        let root = std::env::temp_dir().join(format!("fanctld-files-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let mut directory = Directory::create(root.clone()).unwrap();
+        directory
+            .publish(&[
+                attribute("temp1_input", "31250"),
+                attribute("fan1_input", "900"),
+            ])
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("temp1_input")).unwrap(),
+            "31250\n"
+        );
+        directory
+            .publish(&[attribute("temp1_input", "31500")])
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("temp1_input")).unwrap(),
+            "31500\n"
+        );
+        assert!(!root.join("fan1_input").exists());
+
+        let socket = Socket::bind(root.join("socket")).unwrap();
+        socket.publish("temp1_input 31500\n".to_owned());
+        let mut text = String::new();
+        UnixStream::connect(socket.path())
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "temp1_input 31500\n");
+
+        // A socket left behind is replaced, anything else is not touched
+        std::mem::forget(socket);
+        let socket = Socket::bind(root.join("socket")).unwrap();
+        assert!(Socket::bind(root.join("temp1_input")).is_err());
+        assert_eq!(
+            std::fs::read_to_string(root.join("temp1_input")).unwrap(),
+            "31500\n"
+        );
+
+        drop(socket);
+        assert!(!root.join("socket").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
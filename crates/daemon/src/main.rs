@@ -0,0 +1,209 @@
+//! Linux daemon polling the fan controller and publishing its readings
+//!
+//! The controller is found by its USB IDs, or opened at a given port, and polled for sensor readings and output
+//! status. Readings are published as hwmon-style attribute files for fancontrol and similar scripts, as text on a Unix
+//! socket, and as properties of a D-Bus object. When the controller goes away the readings are withdrawn until it is
+//! found again.
+
+mod dbus;
+mod files;
+mod readings;
+
+use controller_client::Client;
+use dbus::{Bus, BusKind};
+use files::{Directory, Socket};
+use readings::Readings;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "\
+usage: fanctld [options]
+
+  --serial SERIAL       only use the controller with this USB serial number
+  --port PATH           open the controller at PATH rather than looking for it, e.g. the emulator's port
+  --interval SECONDS    time between polls, default 1
+  --dir DIR             publish hwmon-style attribute files in DIR, default /run/fanctld
+  --socket PATH         serve readings on a Unix socket at PATH, default /run/fanctld.sock
+  --bus BUS             publish on the system or session D-Bus, or none, default system";
+
+struct Options {
+    serial: Option<String>,
+    port: Option<PathBuf>,
+    interval: Duration,
+    dir: PathBuf,
+    socket: PathBuf,
+    bus: Option<BusKind>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            serial: None,
+            port: None,
+            interval: Duration::from_secs(1),
+            dir: "/run/fanctld".into(),
+            socket: "/run/fanctld.sock".into(),
+            bus: Some(BusKind::System),
+        }
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        match flag.as_str() {
+            "--serial" => options.serial = Some(value),
+            "--port" => options.port = Some(value.into()),
+            "--interval" => {
+                options.interval = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .filter(|interval| !interval.is_zero())
+                    .ok_or_else(|| format!("invalid interval {value:?}"))?;
+            }
+            "--dir" => options.dir = value.into(),
+            "--socket" => options.socket = value.into(),
+            "--bus" => {
+                options.bus = match value.as_str() {
+                    "system" => Some(BusKind::System),
+                    "session" => Some(BusKind::Session),
+                    "none" => None,
+                    _ => return Err(format!("unknown bus {value:?}")),
+                };
+            }
+            _ => return Err(format!("unknown option {flag}")),
+        }
+    }
+    Ok(options)
+}
+
+fn connect(options: &Options) -> Result<Client, controller_client::Error> {
+    match &options.port {
+        Some(port) => Client::open(port),
+        None => Client::find(options.serial.as_deref()),
+    }
+}
+
+fn poll(client: &mut Client) -> Result<Readings, controller_client::Error> {
+    Ok(Readings {
+        sensors: client.read_sensors()?,
+        status: client.status()?,
+    })
+}
+
+/// Everywhere readings are published
+struct Publishers {
+    directory: Directory,
+    socket: Socket,
+    bus: Option<Bus>,
+}
+
+impl Publishers {
+    /// Publish `readings` everywhere, a failure in one place does not keep them from the others
+    fn publish(&mut self, readings: Option<&Readings>) {
+        let attributes = readings::attributes(readings);
+        if let Err(e) = self.directory.publish(&attributes) {
+            eprintln!("writing attribute files: {e}");
+        }
+        self.socket.publish(readings::to_text(&attributes));
+        if let Some(Err(e)) = self.bus.as_ref().map(|bus| bus.publish(readings)) {
+            eprintln!("publishing on D-Bus: {e}");
+        }
+    }
+}
+
+/// Poll and publish forever, reconnecting whenever the controller goes away
+fn run(options: &Options, publishers: &mut Publishers) -> ! {
+    let mut client = None;
+    // Only report a failure to connect when it changes, not on every retry
+    let mut last_error = String::new();
+    loop {
+        if client.is_none() {
+            match connect(options) {
+                Ok(connected) => {
+                    eprintln!("connected to {}", connected.path().display());
+                    client = Some(connected);
+                    last_error.clear();
+                }
+                Err(e) => {
+                    let error = e.to_string();
+                    if error != last_error {
+                        eprintln!("{error}, retrying");
+                        last_error = error;
+                    }
+                }
+            }
+        }
+
+        let readings = client.as_mut().and_then(|connected| match poll(connected) {
+            Ok(readings) => Some(readings),
+            Err(e) => {
+                eprintln!("lost the controller: {e}");
+                None
+            }
+        });
+        if readings.is_none() {
+            client = None;
+        }
+        publishers.publish(readings.as_ref());
+        std::thread::sleep(options.interval);
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let directory = match Directory::create(options.dir.clone()) {
+        Ok(directory) => directory,
+        Err(e) => {
+            eprintln!("creating {}: {e}", options.dir.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let socket = match Socket::bind(options.socket.clone()) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("binding {}: {e}", options.socket.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let bus = match options.bus.map(Bus::connect).transpose() {
+        Ok(bus) => bus,
+        Err(e) => {
+            eprintln!("connecting to D-Bus: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    eprintln!(
+        "publishing to {} and {}",
+        options.dir.display(),
+        socket.path().display()
+    );
+
+    run(
+        &options,
+        &mut Publishers {
+            directory,
+            socket,
+            bus,
+        },
+    )
+}
@@ -0,0 +1,124 @@
+//! What the daemon publishes, and its hwmon-style representation
+
+use controller_client::text::{fault_description, status_name};
+use controller_protocol::{Fault, SensorReading, SensorStatus, Status};
+
+/// One poll of the controller
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Readings {
+    /// Indexed by sensor
+    pub sensors: Vec<SensorReading>,
+    pub status: Status,
+}
+
+/// Duty in the 0-255 range of hwmon's `pwmN` attributes
+pub fn pwm(duty_permille: u16) -> u8 {
+    let pwm = (u32::from(duty_permille.min(1000)) * 255 + 500) / 1000;
+    u8::try_from(pwm).unwrap_or(u8::MAX)
+}
+
+/// `readings` as hwmon sysfs attributes, `None` while the controller is not connected
+///
+/// Attributes are numbered from 1 like hwmon's. A sensor without a reading has no `tempN_input`, and an output
+/// without a tach no `fanN_input`, so that scripts reading them fail rather than act on a stale value.
+pub fn attributes(readings: Option<&Readings>) -> Vec<(String, String)> {
+    let mut attributes = vec![
+        ("name".to_owned(), "fanctld".to_owned()),
+        (
+            "connected".to_owned(),
+            u8::from(readings.is_some()).to_string(),
+        ),
+    ];
+    let Some(readings) = readings else {
+        return attributes;
+    };
+
+    for (index, sensor) in (1..).zip(&readings.sensors) {
+        if let Some(millidegrees) = sensor.millidegrees {
+            attributes.push((format!("temp{index}_input"), millidegrees.to_string()));
+        }
+        attributes.push((
+            format!("temp{index}_fault"),
+            u8::from(sensor.status != SensorStatus::Ok).to_string(),
+        ));
+        attributes.push((
+            format!("temp{index}_status"),
+            status_name(sensor.status).to_owned(),
+        ));
+    }
+    for (index, output) in (1u8..).zip(&readings.status.outputs) {
+        if let Some(rpm) = output.rpm {
+            attributes.push((format!("fan{index}_input"), rpm.to_string()));
+        }
+        let stalled = readings.status.fault == Some(Fault::Stall { output: index - 1 });
+        attributes.push((format!("fan{index}_alarm"), u8::from(stalled).to_string()));
+        attributes.push((format!("pwm{index}"), pwm(output.duty_permille).to_string()));
+        // The controller runs its own curves, 2 is hwmon's automatic control
+        attributes.push((format!("pwm{index}_enable"), "2".to_owned()));
+    }
+    attributes.push((
+        "fault".to_owned(),
+        readings
+            .status
+            .fault
+            .map(fault_description)
+            .unwrap_or_default(),
+    ));
+    attributes.push(("overruns".to_owned(), readings.status.overruns.to_string()));
+    attributes.push((
+        "save_failures".to_owned(),
+        readings.status.save_failures.to_string(),
+    ));
+    attributes
+}
+
+/// `attributes` as text, one `name value` per line
+pub fn to_text(attributes: &[(String, String)]) -> String {
+    attributes
+        .iter()
+        .map(|(name, value)| format!("{name} {value}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_protocol::OutputStatus;
+
+    #[test]
+    fn publishes_hwmon_attributes() {
+        let readings = Readings {
+            sensors: vec![
+                SensorReading {
+                    millidegrees: Some(31_250),
+                    status: SensorStatus::Ok,
+                },
+                SensorReading {
+                    millidegrees: None,
+                    status: SensorStatus::Open,
+                },
+            ],
+            status: Status {
+                fault: Some(Fault::Stall { output: 0 }),
+                outputs: heapless::Vec::from_slice(&[OutputStatus {
+                    duty_permille: 1000,
+                    rpm: None,
+                }])
+                .unwrap(),
+                overruns: 0,
+                save_failures: 0,
+            },
+        };
+        assert_eq!(
+            to_text(&attributes(Some(&readings))),
+            "name fanctld\nconnected 1\n\
+             temp1_input 31250\ntemp1_fault 0\ntemp1_status ok\n\
+             temp2_fault 1\ntemp2_status open\n\
+             fan1_alarm 1\npwm1 255\npwm1_enable 2\n\
+             fault stall on output 1\noverruns 0\nsave_failures 0\n"
+        );
+        assert_eq!(to_text(&attributes(None)), "name fanctld\nconnected 0\n");
+        assert_eq!(pwm(0), 0);
+        assert_eq!(pwm(500), 128);
+    }
+}
@@ -0,0 +1,307 @@
+//! The controller's firmware logic on Linux, for developing and testing host tools without a board
+//!
+//! The control loop and host command handling are the firmware's own, from `controller_core`. Sensors, fan and the
+//! loop they sit in are simulated by `sim`, in real time or faster. Hosts talk to it through a pseudo-terminal
+//! exactly as they would through the controller's CDC serial port.
+
+mod port;
+mod storage;
+
+use controller_core::control_loop::{FULL_DUTY, OUTPUTS};
+use controller_core::sensors::SENSOR_COUNT;
+use controller_core::{Board, ControlLoop, Session};
+use controller_lib::mock::{MockClock, MockPwm, MockTach};
+use controller_protocol::Config;
+use port::Port;
+use rtic_core::Exclusive;
+use sim::plant::{LoopConfig, Plant, PowerProfile};
+use sim::probe::{Probe, ProbeConfig};
+use sim::scenario::ADC_SPS;
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use storage::FileStorage;
+
+const USAGE: &str = "\
+usage: emulator [options]
+
+Prints the path of the serial port to open, then runs until killed.
+
+  --link PATH           also make the serial port available at PATH, replacing any earlier link
+  --state FILE          keep stored settings in FILE, so they survive restarts
+  --power PROFILE       constant:W, steps:T=W,T=W,... or square:LOW,HIGH,PERIOD
+  --ambient C           air temperature at the radiator
+  --noise COUNTS        standard deviation of ADC noise per sample
+  --speed FACTOR        run the simulation this many times faster than real time
+  --seed N              seed of the ADC noise";
+
+/// Time step of the loop model, the control loop runs once per reading regardless
+const PLANT_STEP_S: f64 = 0.01;
+
+struct Options {
+    link: Option<PathBuf>,
+    state: Option<PathBuf>,
+    power: PowerProfile,
+    plant: LoopConfig,
+    probe: ProbeConfig,
+    speed: f64,
+    seed: u64,
+}
+
+impl Default for Options {
+    /// A load coming and going every five minutes, in real time
+    fn default() -> Self {
+        Self {
+            link: None,
+            state: None,
+            power: PowerProfile::Square {
+                low: 50.0,
+                high: 250.0,
+                period_s: 600.0,
+            },
+            plant: LoopConfig::default(),
+            probe: ProbeConfig::default(),
+            speed: 1.0,
+            seed: 1,
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number {value:?}"))
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        match flag.as_str() {
+            "--link" => options.link = Some(value.into()),
+            "--state" => options.state = Some(value.into()),
+            "--power" => {
+                options.power = value
+                    .parse()
+                    .map_err(|_| format!("invalid power profile {value:?}"))?;
+            }
+            "--ambient" => options.plant.ambient_c = parse_number(&value)?,
+            "--noise" => options.probe.noise_counts = parse_number(&value)?,
+            "--speed" => options.speed = parse_number(&value)?,
+            "--seed" => options.seed = parse_number(&value)?,
+            _ => return Err(format!("unknown option {flag}")),
+        }
+    }
+    if options.speed <= 0.0 {
+        return Err("speed must be positive".into());
+    }
+    Ok(options)
+}
+
+/// Settings in a file, and a bootloader that ends the emulator as the device would disappear from the host
+struct Emulated {
+    storage: FileStorage,
+}
+
+impl Board for Emulated {
+    fn save_config(&mut self, config: Config) -> Result<(), controller_protocol::Error> {
+        controller_core::config::save(&mut self.storage, &config)
+            .map_err(|_e| controller_protocol::Error::Storage)
+    }
+
+    fn reboot_to_bootloader(&mut self) {
+        eprintln!("rebooting to the bootloader, exiting");
+        std::process::exit(0);
+    }
+}
+
+/// Run the control loop against the simulated loop, answering the host on `port` in between, until `stop` is set
+///
+/// Every sensor reads the water, and every output drives the one fan.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+fn run(options: &Options, port: &mut Port, stop: &AtomicBool) -> io::Result<()> {
+    let mut board = Emulated {
+        storage: FileStorage::open(options.state.clone())?,
+    };
+    let config = controller_core::config::load(&board.storage);
+
+    let duty = Cell::new(0);
+    let pulses = Cell::new(0);
+    let now_us = Cell::new(0);
+    let fans = OUTPUTS.iter().map(|_| {
+        (
+            MockPwm {
+                duty: &duty,
+                max_duty: FULL_DUTY,
+            },
+            MockTach(&pulses),
+        )
+    });
+    // Stored settings are only ever loaded if valid
+    let mut control = ControlLoop::new(fans, MockClock(&now_us), &config)
+        .expect("settings are valid for the control loop");
+    let mut session = Session::new(config.clone());
+
+    let mut plant = Plant::new(options.plant);
+    let mut probes: [Probe; SENSOR_COUNT] = core::array::from_fn(|index| {
+        Probe::new(
+            options.probe,
+            plant.water_c,
+            config.samples_per_sensor,
+            config.oversample_bits,
+            options.seed + index as u64,
+        )
+    });
+
+    // The ADC's sample rate is shared between the sensors
+    let period_s = f64::from(config.samples_per_sensor) * SENSOR_COUNT as f64 / ADC_SPS;
+    let substeps = (period_s / PLANT_STEP_S).ceil() as u32;
+    let start = Instant::now();
+    let mut buf = [0u8; 64];
+    for reading in 1u64.. {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let time_s = reading as f64 * period_s;
+        let dt_s = period_s / f64::from(substeps);
+        for substep in 0..substeps {
+            let power = options
+                .power
+                .watts(time_s - period_s + f64::from(substep) * dt_s);
+            plant.step(dt_s, power, f64::from(duty.get()) / f64::from(FULL_DUTY));
+            for probe in &mut probes {
+                probe.track(plant.water_c, dt_s);
+            }
+        }
+        pulses.set(plant.pulses);
+        now_us.set((time_s * 1e6) as u64);
+        let snapshot = control.update(&mut probes, 0);
+
+        // Answer the host until the next reading is due
+        let due = start + Duration::from_secs_f64(time_s / options.speed);
+        while let Some(timeout) = due.checked_duration_since(Instant::now()) {
+            let count = port.read(&mut buf, timeout)?;
+            if count > 0 {
+                session.receive(
+                    &buf[..count],
+                    &snapshot,
+                    &mut Exclusive(&mut control),
+                    &mut board,
+                    |frame| port.write(frame),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = Port::open(options.link.as_deref()).and_then(|mut port| {
+        // Scripts wait for this line before opening the port
+        println!("{}", port.path().display());
+        io::stdout().flush()?;
+        run(&options, &mut port, &AtomicBool::new(false))
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_protocol::{Command, Reply, Request, Response, MAX_FRAME, PROTOCOL_VERSION};
+    use std::fs::OpenOptions;
+    use std::io::Read;
+
+    fn exchange(host: &mut std::fs::File, id: u16, command: Command) -> Response {
+        let mut buf = [0u8; MAX_FRAME];
+        let frame = controller_protocol::encode(&Request { id, command }, &mut buf).unwrap();
+        host.write_all(frame).unwrap();
+
+        let mut frame = Vec::new();
+        let mut byte = [0u8];
+        while frame.last() != Some(&0) {
+            host.read_exact(&mut byte).unwrap();
+            frame.push(byte[0]);
+        }
+        controller_protocol::decode(&mut frame).unwrap()
+    }
+
+    #[test]
+    fn answers_over_the_pty() {
+        let mut port = Port::open(None).unwrap();
+        let path = port.path().to_owned();
+        let stop = AtomicBool::new(false);
+        let options = Options {
+            speed: 10.0,
+            ..Options::default()
+        };
+
+        std::thread::scope(|scope| {
+            let emulator = scope.spawn(|| run(&options, &mut port, &stop));
+            let mut host = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+
+            let hello = exchange(
+                &mut host,
+                1,
+                Command::Hello {
+                    version: PROTOCOL_VERSION,
+                },
+            );
+            assert_eq!(
+                hello.result,
+                Ok(Reply::Hello {
+                    version: PROTOCOL_VERSION,
+                    sensors: 1,
+                    outputs: 1
+                })
+            );
+
+            let sensors = exchange(&mut host, 2, Command::ReadSensors);
+            assert_eq!(sensors.id, 2);
+            let Ok(Reply::Sensors(readings)) = sensors.result else {
+                panic!("{sensors:?}");
+            };
+            // Ambient, within the fit of the default sensor model
+            let millidegrees = readings[0].millidegrees.unwrap();
+            assert!((20_000..30_000).contains(&millidegrees), "{millidegrees}");
+
+            stop.store(true, Ordering::Relaxed);
+            emulator.join().unwrap().unwrap();
+        });
+    }
+}
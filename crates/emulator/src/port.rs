@@ -0,0 +1,94 @@
+//! The pseudo-terminal standing in for the controller's CDC serial port
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::pty::openpty;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The emulator's end of a pseudo-terminal, hosts open the other end as they would the controller's serial port
+pub struct Port {
+    master: File,
+    /// Held open so the master does not read as hung up while no host has the port open
+    _slave: File,
+    /// Symlink to the slave, removed on drop
+    link: Option<PathBuf>,
+    tty: PathBuf,
+}
+
+impl Port {
+    /// Open a pseudo-terminal in raw mode, frames are binary and must pass through untouched
+    ///
+    /// If `link` is given a symlink to the slave is made there, replacing any previous one, so hosts can be pointed
+    /// at a fixed path.
+    ///
+    /// # Errors
+    /// * If the pseudo-terminal can not be set up, or the link made
+    pub fn open(link: Option<&Path>) -> io::Result<Self> {
+        let pty = openpty(None, None)?;
+        let mut termios = tcgetattr(&pty.slave)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(&pty.slave, SetArg::TCSANOW, &termios)?;
+        fcntl(pty.master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        let tty = nix::unistd::ttyname(&pty.slave)?;
+
+        if let Some(link) = link {
+            if link.symlink_metadata().is_ok_and(|meta| meta.is_symlink()) {
+                std::fs::remove_file(link)?;
+            }
+            std::os::unix::fs::symlink(&tty, link)?;
+        }
+
+        Ok(Self {
+            master: pty.master.into(),
+            _slave: pty.slave.into(),
+            link: link.map(Path::to_owned),
+            tty,
+        })
+    }
+
+    /// Where hosts should open the port, the link if there is one
+    pub fn path(&self) -> &Path {
+        self.link.as_deref().unwrap_or(&self.tty)
+    }
+
+    /// Wait up to `timeout` for bytes from the host, 0 if none arrived
+    ///
+    /// # Errors
+    /// * If the pseudo-terminal fails
+    pub fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(self.master.as_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, timeout)? == 0 {
+            return Ok(0);
+        }
+        match self.master.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            result => result,
+        }
+    }
+
+    /// Write a frame. Whatever does not fit while the host is not reading is dropped, the pseudo-terminal buffers
+    /// kilobytes so that only happens to a host that has stopped reading altogether.
+    pub fn write(&mut self, mut frame: &[u8]) {
+        while !frame.is_empty() {
+            match self.master.write(frame) {
+                Ok(len) => frame = &frame[len..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = std::fs::remove_file(link);
+        }
+    }
+}
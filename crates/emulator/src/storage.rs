@@ -0,0 +1,98 @@
+//! Settings storage, optionally kept in a file so that settings survive restarts the way they do in flash
+
+use controller_core::config::SLOT_SIZE;
+use controller_lib::nvstore::Slot;
+use controller_lib::NvStorage;
+use std::io;
+use std::path::PathBuf;
+
+/// Both slots in memory, written through to a file of both slots back to back if there is one
+pub struct FileStorage {
+    slots: [Vec<u8>; 2],
+    buffer: Vec<u8>,
+    path: Option<PathBuf>,
+}
+
+impl FileStorage {
+    /// Read the slots back from `path` if it exists, otherwise start out erased
+    ///
+    /// # Errors
+    /// * If the file exists but can not be read, or is not two slots long
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let erased = || vec![0xff; SLOT_SIZE];
+        let slots = match &path {
+            Some(path) if path.exists() => {
+                let mut contents = std::fs::read(path)?;
+                if contents.len() != 2 * SLOT_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is not two {SLOT_SIZE} byte slots", path.display()),
+                    ));
+                }
+                let b = contents.split_off(SLOT_SIZE);
+                [contents, b]
+            }
+            _ => [erased(), erased()],
+        };
+        Ok(Self {
+            slots,
+            buffer: vec![0; SLOT_SIZE],
+            path,
+        })
+    }
+
+    const fn index(slot: Slot) -> usize {
+        match slot {
+            Slot::A => 0,
+            Slot::B => 1,
+        }
+    }
+}
+
+impl NvStorage for FileStorage {
+    type Error = io::Error;
+
+    fn read(&self, slot: Slot) -> &[u8] {
+        &self.slots[Self::index(slot)]
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    fn write(&mut self, slot: Slot, len: usize) -> io::Result<()> {
+        let data = self
+            .buffer
+            .get(..len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "larger than a slot"))?;
+        let stored = &mut self.slots[Self::index(slot)];
+        stored[..len].copy_from_slice(data);
+        stored[len..].fill(0xff);
+
+        if let Some(path) = &self.path {
+            std::fs::write(path, self.slots.concat())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_core::config;
+
+    #[test]
+    fn settings_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("emulator-storage-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut storage = FileStorage::open(Some(path.clone())).unwrap();
+        let mut settings = config::defaults();
+        settings.samples_per_sensor = 64;
+        config::save(&mut storage, &settings).unwrap();
+
+        let reopened = FileStorage::open(Some(path.clone())).unwrap();
+        assert_eq!(config::load(&reopened), settings);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! Text forms of curves and readings, meant to be both read and scripted against
+
+use controller_client::text::{fault_description, status_name};
+use controller_protocol::{Curve, CurvePoint, SensorReading, Status, MAX_CURVE_POINTS};
+use std::fmt::Write;
+
+/// Millidegrees as degrees C, with only the decimals needed
+fn degrees(millidegrees: i32) -> String {
+    (f64::from(millidegrees) / 1000.0).to_string()
+}
+
+/// Permille as percent, with only the decimals needed
+fn percent(permille: u16) -> String {
+    (f64::from(permille) / 10.0).to_string()
+}
+
+/// `curve` as `T=PCT,...` in degrees C and percent duty, the form `parse_curve` takes
+pub fn curve(curve: &Curve) -> String {
+    curve
+        .points
+        .iter()
+        .map(|point| {
+            format!(
+                "{}={}",
+                degrees(point.millidegrees),
+                percent(point.duty_permille)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse a curve given as `T=PCT,...`, degrees C to percent duty
+///
+/// The controller checks the shape of the curve, this only checks each point is in range.
+pub fn parse_curve(value: &str) -> Result<Curve, String> {
+    let mut points = heapless::Vec::<_, MAX_CURVE_POINTS>::new();
+    for point in value.split(',') {
+        let (temp, duty) = point
+            .split_once('=')
+            .ok_or_else(|| format!("expected T=PCT, got {point:?}"))?;
+        let temp: f64 = temp
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid temperature {temp:?}"))?;
+        let duty: f64 = duty
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid duty {duty:?}"))?;
+        if !(-100.0..=200.0).contains(&temp) || !(0.0..=100.0).contains(&duty) {
+            return Err(format!("point out of range: {point}"));
+        }
+        // In range, checked above
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let point = CurvePoint {
+            millidegrees: (temp * 1000.0).round() as i32,
+            duty_permille: (duty * 10.0).round() as u16,
+        };
+        points
+            .push(point)
+            .map_err(|_| format!("at most {MAX_CURVE_POINTS} points"))?;
+    }
+    Ok(Curve { points })
+}
+
+/// Sensors, outputs and faults as a table, numbering sensors and outputs from 1
+pub fn status(sensors: &[SensorReading], status: &Status) -> String {
+    let mut table = String::from("sensor  temp C    status\n");
+    for (index, sensor) in (1..).zip(sensors) {
+        let temp = sensor.millidegrees.map_or_else(
+            || "-".to_owned(),
+            |millidegrees| format!("{:.2}", f64::from(millidegrees) / 1000.0),
+        );
+        let _ = writeln!(table, "{index:<7} {temp:<9} {}", status_name(sensor.status));
+    }
+
+    table.push_str("\noutput  duty %    rpm\n");
+    for (index, output) in (1..).zip(&status.outputs) {
+        let rpm = output
+            .rpm
+            .map_or_else(|| "-".to_owned(), |rpm| rpm.to_string());
+        let duty = format!("{:.1}", f64::from(output.duty_permille) / 10.0);
+        let _ = writeln!(table, "{index:<7} {duty:<9} {rpm}");
+    }
+
+    let fault = status
+        .fault
+        .map_or_else(|| "none".to_owned(), fault_description);
+    let _ = write!(
+        table,
+        "\nfault: {fault}\noverruns: {}\nsave failures: {}\n",
+        status.overruns, status.save_failures
+    );
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_protocol::{Fault, OutputStatus, SensorStatus};
+
+    #[test]
+    fn curves_round_trip() {
+        let parsed = parse_curve("25=20, 32.5=45.5,45=100").unwrap();
+        assert_eq!(
+            parsed.points,
+            [
+                CurvePoint {
+                    millidegrees: 25_000,
+                    duty_permille: 200
+                },
+                CurvePoint {
+                    millidegrees: 32_500,
+                    duty_permille: 455
+                },
+                CurvePoint {
+                    millidegrees: 45_000,
+                    duty_permille: 1000
+                },
+            ]
+        );
+        assert_eq!(curve(&parsed), "25=20,32.5=45.5,45=100");
+
+        assert!(parse_curve("25").is_err());
+        assert!(parse_curve("25=120").is_err());
+        assert!(parse_curve(&["30=50"; MAX_CURVE_POINTS + 1].join(",")).is_err());
+    }
+
+    #[test]
+    fn formats_status_table() {
+        let sensors = [
+            SensorReading {
+                millidegrees: Some(31_250),
+                status: SensorStatus::Ok,
+            },
+            SensorReading {
+                millidegrees: None,
+                status: SensorStatus::Short,
+            },
+        ];
+        let status = Status {
+            fault: Some(Fault::Stall { output: 0 }),
+            outputs: heapless::Vec::from_slice(&[OutputStatus {
+                duty_permille: 1000,
+                rpm: Some(0),
+            }])
+            .unwrap(),
+            overruns: 2,
+            save_failures: 1,
+        };
+        assert_eq!(
+            super::status(&sensors, &status),
+            "sensor  temp C    status\n\
+             1       31.25     ok\n\
+             2       -         short\n\
+             \n\
+             output  duty %    rpm\n\
+             1       100.0     0\n\
+             \n\
+             fault: stall on output 1\n\
+             overruns: 2\n\
+             save failures: 1\n"
+        );
+    }
+}
@@ -0,0 +1,321 @@
+//! Command line tool for the fan controller
+//!
+//! Finds the controller by its USB IDs and serial number, or opens a given serial port, and runs one command against
+//! it. Output is plain text meant to be read as well as scripted against, see `USAGE`.
+
+mod format;
+
+use controller_client::{discovery, Client};
+use controller_protocol::Config;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "\
+usage: fanctl [--serial SERIAL | --port PATH] COMMAND
+
+  --serial SERIAL               use the controller with this USB serial number, needed if several are connected
+  --port PATH                   open the controller at PATH rather than looking for it, e.g. the emulator's port
+
+commands:
+  status                        sensor readings, output duties and speeds, and faults
+  watch [--interval SECONDS]    status, updated until interrupted
+  curve get OUTPUT              the curve OUTPUT runs on, as T=PCT,...
+  curve set OUTPUT T=PCT,...    run OUTPUT on a curve of degrees C to percent duty until reset
+        [--save]                and store the running settings so the curve survives a reset
+  config export [FILE]          write the running settings as TOML to FILE, or stdout
+  config import FILE            store settings from a TOML file, applied from the next reset
+  reboot --bootloader           reset into the USB bootloader to flash new firmware
+
+Outputs and sensors are numbered from 1.";
+
+/// How to reach the controller
+#[derive(PartialEq, Eq, Default, Debug)]
+struct Target {
+    serial: Option<String>,
+    port: Option<PathBuf>,
+}
+
+#[derive(PartialEq, Debug)]
+enum Action {
+    Status,
+    Watch {
+        interval: Duration,
+    },
+    CurveGet {
+        output: u8,
+    },
+    CurveSet {
+        output: u8,
+        curve: String,
+        save: bool,
+    },
+    ConfigExport {
+        file: Option<PathBuf>,
+    },
+    ConfigImport {
+        file: PathBuf,
+    },
+    RebootToBootloader,
+}
+
+/// An output numbered from 1, as the controller's index from 0
+fn parse_output(value: &str) -> Result<u8, String> {
+    value
+        .trim()
+        .parse::<u8>()
+        .ok()
+        .and_then(|output| output.checked_sub(1))
+        .ok_or_else(|| format!("invalid output {value:?}, outputs are numbered from 1"))
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<(Target, Action), String> {
+    let mut target = Target::default();
+    let mut args = args.into_iter().peekable();
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        match flag.as_str() {
+            "--serial" => target.serial = Some(value),
+            "--port" => target.port = Some(value.into()),
+            _ => return Err(format!("unknown option {flag}")),
+        }
+    }
+
+    let words = args.collect::<Vec<_>>();
+    let words = words.iter().map(String::as_str).collect::<Vec<_>>();
+    let action = match words[..] {
+        ["status"] => Action::Status,
+        ["watch"] => Action::Watch {
+            interval: Duration::from_secs(1),
+        },
+        ["watch", "--interval", seconds] => Action::Watch {
+            interval: seconds
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .filter(|interval| !interval.is_zero())
+                .ok_or_else(|| format!("invalid interval {seconds:?}"))?,
+        },
+        ["curve", "get", output] => Action::CurveGet {
+            output: parse_output(output)?,
+        },
+        ["curve", "set", output, curve, ref save @ ..] if matches!(save, [] | ["--save"]) => {
+            Action::CurveSet {
+                output: parse_output(output)?,
+                curve: curve.to_owned(),
+                save: !save.is_empty(),
+            }
+        }
+        ["config", "export"] => Action::ConfigExport { file: None },
+        ["config", "export", file] => Action::ConfigExport {
+            file: Some(file.into()),
+        },
+        ["config", "import", file] => Action::ConfigImport { file: file.into() },
+        ["reboot", "--bootloader"] => Action::RebootToBootloader,
+        ["reboot"] => {
+            return Err(
+                "the controller can only reboot into its bootloader, pass --bootloader".into(),
+            )
+        }
+        [] => return Err("missing command".into()),
+        _ => return Err(format!("unknown command {:?}", words.join(" "))),
+    };
+    Ok((target, action))
+}
+
+/// Open the controller `target` names, refusing to guess between several
+fn connect(target: &Target) -> Result<Client, String> {
+    if let Some(port) = &target.port {
+        return Client::open(port).map_err(|e| format!("{}: {e}", port.display()));
+    }
+
+    let devices = discovery::find(target.serial.as_deref())
+        .map_err(|e| format!("looking for controllers: {e}"))?;
+    let device = match &devices[..] {
+        [] => return Err(controller_client::Error::NotFound.to_string()),
+        [device] => device,
+        _ => {
+            let found = devices
+                .iter()
+                .map(|device| {
+                    format!(
+                        "{} ({})",
+                        device.path.display(),
+                        device.serial.as_deref().unwrap_or("no serial number")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(format!(
+                "several controllers found, pick one with --serial: {found}"
+            ));
+        }
+    };
+    Client::open(&device.path).map_err(|e| format!("{}: {e}", device.path.display()))
+}
+
+fn status(client: &mut Client) -> Result<String, controller_client::Error> {
+    let sensors = client.read_sensors()?;
+    let status = client.status()?;
+    Ok(format::status(&sensors, &status))
+}
+
+fn run(mut client: Client, action: Action) -> Result<(), String> {
+    let error = |e: controller_client::Error| e.to_string();
+    match action {
+        Action::Status => print!("{}", status(&mut client).map_err(error)?),
+        Action::Watch { interval } => loop {
+            let table = status(&mut client).map_err(error)?;
+            // Clear the terminal and redraw from the top
+            let mut stdout = std::io::stdout().lock();
+            let drawn = write!(stdout, "\x1b[H\x1b[2J{}\n{table}", client.path().display())
+                .and_then(|()| stdout.flush());
+            if drawn.is_err() {
+                // Nobody is watching any more
+                return Ok(());
+            }
+            drop(stdout);
+            std::thread::sleep(interval);
+        },
+        Action::CurveGet { output } => {
+            println!("{}", format::curve(&client.curve(output).map_err(error)?));
+        }
+        Action::CurveSet {
+            output,
+            curve,
+            save,
+        } => {
+            client
+                .set_curve(output, format::parse_curve(&curve)?)
+                .map_err(error)?;
+            if save {
+                client.save_config().map_err(error)?;
+            }
+        }
+        Action::ConfigExport { file } => {
+            let config = client.config().map_err(error)?;
+            let text = toml::to_string(&config).map_err(|e| e.to_string())?;
+            match file {
+                Some(file) => std::fs::write(&file, text)
+                    .map_err(|e| format!("writing {}: {e}", file.display()))?,
+                None => print!("{text}"),
+            }
+        }
+        Action::ConfigImport { file } => {
+            let text = std::fs::read_to_string(&file)
+                .map_err(|e| format!("reading {}: {e}", file.display()))?;
+            let config: Config =
+                toml::from_str(&text).map_err(|e| format!("{}: {e}", file.display()))?;
+            client.set_config(config).map_err(error)?;
+            eprintln!("stored, the settings apply from the next reset");
+        }
+        Action::RebootToBootloader => client.reboot_to_bootloader().map_err(error)?,
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let (target, action) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match connect(&target).and_then(|client| run(client, action)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<(Target, Action), String> {
+        parse_args(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("--serial FOO curve set 2 25=20,45=100 --save"),
+            Ok((
+                Target {
+                    serial: Some("FOO".into()),
+                    port: None
+                },
+                Action::CurveSet {
+                    output: 1,
+                    curve: "25=20,45=100".into(),
+                    save: true
+                }
+            ))
+        );
+        assert_eq!(
+            parse("watch --interval 0.5").map(|(_, action)| action),
+            Ok(Action::Watch {
+                interval: Duration::from_millis(500)
+            })
+        );
+        assert_eq!(
+            parse("config export").map(|(_, action)| action),
+            Ok(Action::ConfigExport { file: None })
+        );
+        assert!(parse("curve get 0").is_err());
+        assert!(parse("curve set 1 25=20 --force").is_err());
+        assert!(parse("reboot").is_err());
+        assert!(parse("--port").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn config_round_trips_through_toml() {
+        let config = Config {
+            samples_per_sensor: 32,
+            oversample_bits: 2,
+            sensors: heapless::Vec::from_slice(&[
+                controller_protocol::SensorModel::LinearFit,
+                controller_protocol::SensorModel::Beta {
+                    r0_ohms: 10_000,
+                    t0_millidegrees: 25_000,
+                    beta: 3950,
+                    ptc: false,
+                    series_ohms: 10_000,
+                    high_side: true,
+                },
+                controller_protocol::SensorModel::SteinhartHart {
+                    a: 79_328_205_276_355,
+                    b: 17_332_733_669_883,
+                    c: 6_269_010_681,
+                    series_ohms: 10_000,
+                    high_side: false,
+                },
+            ])
+            .unwrap(),
+            filter: controller_protocol::ReadingFilter::LowPass { alpha: 8192 },
+            curves: heapless::Vec::from_slice(&[format::parse_curve("25=20,45=100").unwrap()])
+                .unwrap(),
+            limits: heapless::Vec::from_slice(&[controller_protocol::DutyLimits {
+                min_permille: 200,
+                max_permille: 1000,
+            }])
+            .unwrap(),
+        };
+        let text = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<Config>(&text).unwrap(), config);
+    }
+}
@@ -1,3 +1,4 @@
+use controller_protocol::PROTOCOL_VERSION;
 use yew::prelude::*;
 
 #[function_component(App)]
@@ -5,6 +6,7 @@ pub fn app() -> Html {
     html! {
         <main>
             <h1>{ "Hello World!" }</h1>
+            <p>{ format!("Protocol version {PROTOCOL_VERSION}") }</p>
         </main>
     }
 }
@@ -0,0 +1,392 @@
+//! Command/response protocol between the fan controller and host tools
+//!
+//! Messages are postcard encoded and COBS framed, so every frame ends in the only 0 byte it contains. The host sends a
+//! `Request` and the controller answers each one with a `Response` carrying the same `id`. A session should open with
+//! `Command::Hello` so that both ends can check they speak the same `PROTOCOL_VERSION`.
+//!
+//! Units on the wire are independent of the firmware's fixed point types: temperatures in millidegrees C and duty
+//! cycles in permille of full scale.
+#![no_std]
+
+use serde::{Deserialize, Serialize};
+
+pub use postcard::accumulator::{CobsAccumulator, FeedResult};
+
+/// Bumped on any change to the encoding of the types in this crate
+pub const PROTOCOL_VERSION: u16 = 8;
+
+/// Largest encoded frame, including the terminating 0
+pub const MAX_FRAME: usize = 512;
+
+/// Most sensors or outputs any controller reports
+pub const MAX_CHANNELS: usize = 4;
+
+/// Most points in a fan curve
+pub const MAX_CURVE_POINTS: usize = 8;
+
+/// Longest window of `ReadingFilter::MovingAverage` or `ReadingFilter::Median`
+pub const MAX_FILTER_LEN: u8 = 16;
+
+/// Accumulates received bytes into whole frames
+pub type FrameAccumulator = CobsAccumulator<MAX_FRAME>;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Request {
+    /// Echoed back in the matching `Response`
+    pub id: u16,
+    pub command: Command,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+// Messages are decoded one at a time and handled straight away, the size of the largest does not matter
+#[allow(clippy::large_enum_variant)]
+pub enum Command {
+    /// Version handshake, answered with `Reply::Hello` or `Error::VersionMismatch`
+    Hello {
+        version: u16,
+    },
+    /// Latest reading of every sensor
+    ReadSensors,
+    /// Duty, RPM and fault state
+    GetStatus,
+    GetCurve {
+        output: u8,
+    },
+    SetCurve {
+        output: u8,
+        curve: Curve,
+    },
+    /// Reset into the RP2040 USB bootloader, no response is sent
+    RebootToBootloader,
+    /// Settings the controller is running with, including curves changed since boot
+    GetConfig,
+    /// Store settings in flash, applied from the next reset. Acknowledged once the write is queued, a write that then
+    /// fails shows in `Status::save_failures`.
+    SetConfig {
+        config: Config,
+    },
+    /// Store the running settings in flash, so curves changed with `SetCurve` survive a reset. Acknowledged like
+    /// `SetConfig`.
+    SaveConfig,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Response {
+    /// `id` of the request this answers, 0 if the request could not be decoded
+    pub id: u16,
+    pub result: Result<Reply, Error>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+// Messages are decoded one at a time and handled straight away, the size of the largest does not matter
+#[allow(clippy::large_enum_variant)]
+pub enum Reply {
+    Hello {
+        version: u16,
+        sensors: u8,
+        outputs: u8,
+    },
+    /// Indexed by sensor
+    Sensors(heapless::Vec<SensorReading, MAX_CHANNELS>),
+    Status(Status),
+    Curve(Curve),
+    Config(Config),
+    /// The command was carried out and has nothing to report
+    Ack,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub enum Error {
+    /// The controller speaks `supported` and nothing else
+    VersionMismatch {
+        supported: u16,
+    },
+    /// The frame did not decode as a `Request`
+    Malformed,
+    UnknownOutput,
+    /// Curve points out of order, or not a shape the controller supports
+    InvalidCurve,
+    /// Wrong number of sensors or outputs, or a setting out of range
+    InvalidConfig,
+    /// The settings could not be written to flash
+    Storage,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct SensorReading {
+    /// None if the sensor has no usable reading. Hosts should keep at least tenths, curves step visibly on whole degrees.
+    pub millidegrees: Option<i32>,
+    /// Why there is no reading, `Ok` with no reading only before the first one is taken
+    pub status: SensorStatus,
+}
+
+/// Health of a sensor, the controller runs the outputs it drives at their failsafe duty unless it is `Ok`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum SensorStatus {
+    Ok,
+    /// Disconnected probe or broken wire
+    Open,
+    /// Shorted probe or wiring
+    Short,
+    /// Reading outside of the plausible temperature range
+    OutOfRange,
+    /// Raw reading frozen, e.g. a probe connected through a faulty buffer
+    Stuck,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Status {
+    /// Latched fault, if any
+    pub fault: Option<Fault>,
+    /// Indexed by output
+    pub outputs: heapless::Vec<OutputStatus, MAX_CHANNELS>,
+    /// Times sampling fell behind and samples were dropped, since boot. Should stay at 0.
+    pub overruns: u32,
+    /// Settings from `SetConfig` or `SaveConfig` that were acknowledged but could not be written to flash, since boot
+    pub save_failures: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct OutputStatus {
+    pub duty_permille: u16,
+    /// None if the output has no tach input
+    pub rpm: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Fault {
+    /// The output at this index is driven but not turning
+    Stall { output: u8 },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Curve {
+    /// In ascending temperature order
+    pub points: heapless::Vec<CurvePoint, MAX_CURVE_POINTS>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct CurvePoint {
+    pub millidegrees: i32,
+    pub duty_permille: u16,
+}
+
+/// Persistent controller settings, the same encoding is stored in flash
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Config {
+    /// ADC samples averaged into each reading
+    pub samples_per_sensor: u16,
+    /// Bits of resolution the averaging adds to the 12 bit ADC, each needs four times the samples
+    pub oversample_bits: u8,
+    /// Indexed by sensor
+    pub sensors: heapless::Vec<SensorModel, MAX_CHANNELS>,
+    /// Applied to every sensor's readings
+    pub filter: ReadingFilter,
+    /// Indexed by output
+    pub curves: heapless::Vec<Curve, MAX_CHANNELS>,
+    /// Indexed by output
+    pub limits: heapless::Vec<DutyLimits, MAX_CHANNELS>,
+}
+
+/// Bounds on an output's duty, applied after its curve or PID and output stage so that no curve set over the wire can
+/// stop a pump. Failsafes still run the output at full duty.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct DutyLimits {
+    pub min_permille: u16,
+    pub max_permille: u16,
+}
+
+/// How a sensor's ADC readings are converted to temperature
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum SensorModel {
+    /// The original fit of the controller board's 10k NTC divider
+    LinearFit,
+    /// Thermistor described by its Beta coefficient, in a divider with a fixed resistor
+    Beta {
+        /// Resistance at `t0_millidegrees`, 25 C for most parts
+        r0_ohms: u32,
+        t0_millidegrees: i32,
+        beta: u16,
+        /// Resistance rises with temperature
+        ptc: bool,
+        series_ohms: u32,
+        /// Thermistor between the supply and the ADC pin rather than the pin and ground
+        high_side: bool,
+    },
+    /// Thermistor described by the Steinhart-Hart equation `1/T = A + B*ln(R) + C*ln(R)^3`, in a divider with a fixed
+    /// resistor. The coefficients are per kelvin in fixed point with 56 fraction bits, IE the published value times
+    /// 2^56.
+    SteinhartHart {
+        a: i64,
+        b: i64,
+        c: i64,
+        series_ohms: u32,
+        /// Thermistor between the supply and the ADC pin rather than the pin and ground
+        high_side: bool,
+    },
+}
+
+/// Smoothing of converted sensor readings, on top of the averaging of `samples_per_sensor` ADC samples
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum ReadingFilter {
+    None,
+    /// Average of the last `len` readings, 1 to `MAX_FILTER_LEN`
+    MovingAverage {
+        len: u8,
+    },
+    /// Exponential smoothing with factor `alpha / 65536`, smaller is smoother
+    LowPass {
+        alpha: u16,
+    },
+    /// Median of the last `len` readings, 1 to `MAX_FILTER_LEN`. Rejects spikes shorter than half the window.
+    Median {
+        len: u8,
+    },
+}
+
+/// Encode and frame a message into `buf`, returning the used part including the terminating 0
+///
+/// # Errors
+/// * If `buf` is too small for the message
+pub fn encode<'a, T: Serialize>(msg: &T, buf: &'a mut [u8]) -> postcard::Result<&'a mut [u8]> {
+    postcard::to_slice_cobs(msg, buf)
+}
+
+/// Decode a single frame, with or without its terminating 0. The frame is decoded in place.
+///
+/// # Errors
+/// * If the frame is not a valid COBS frame or does not decode as `T`
+pub fn decode<'a, T: Deserialize<'a>>(frame: &'a mut [u8]) -> postcard::Result<T> {
+    postcard::from_bytes_cobs(frame)
+}
+
+/// Encode a message without framing, for storage where the length is known
+///
+/// # Errors
+/// * If `buf` is too small for the message
+pub fn encode_unframed<'a, T: Serialize>(
+    msg: &T,
+    buf: &'a mut [u8],
+) -> postcard::Result<&'a mut [u8]> {
+    postcard::to_slice(msg, buf)
+}
+
+/// Decode a message written by `encode_unframed`
+///
+/// # Errors
+/// * If the bytes do not decode as `T`
+pub fn decode_unframed<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> postcard::Result<T> {
+    postcard::from_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_round_trip() {
+        let mut curve = Curve::default();
+        curve
+            .points
+            .push(CurvePoint {
+                millidegrees: 25_000,
+                duty_permille: 200,
+            })
+            .unwrap();
+        let request = Request {
+            id: 7,
+            command: Command::SetCurve { output: 1, curve },
+        };
+
+        let mut buf = [0u8; MAX_FRAME];
+        let frame = encode(&request, &mut buf).unwrap();
+        assert_eq!(frame.last(), Some(&0));
+        assert!(!frame[..frame.len() - 1].contains(&0));
+
+        assert_eq!(decode::<Request>(frame).unwrap(), request);
+    }
+
+    #[test]
+    fn accumulates_split_frames() {
+        let response = Response {
+            id: 3,
+            result: Err(Error::VersionMismatch { supported: 1 }),
+        };
+        let mut buf = [0u8; MAX_FRAME];
+        let frame = encode(&response, &mut buf).unwrap();
+        let (first, second) = frame.split_at(2);
+
+        let mut acc = FrameAccumulator::new();
+        assert!(matches!(acc.feed::<Response>(first), FeedResult::Consumed));
+        match acc.feed::<Response>(second) {
+            FeedResult::Success { data, remaining } => {
+                assert_eq!(data, response);
+                assert!(remaining.is_empty());
+            }
+            _ => panic!("frame did not decode"),
+        }
+    }
+
+    #[test]
+    fn largest_config_fits() {
+        let point = CurvePoint {
+            millidegrees: i32::MIN,
+            duty_permille: u16::MAX,
+        };
+        let curve = Curve {
+            points: core::iter::repeat_n(point, MAX_CURVE_POINTS).collect(),
+        };
+        let sensor = SensorModel::SteinhartHart {
+            a: i64::MIN,
+            b: i64::MIN,
+            c: i64::MIN,
+            series_ohms: u32::MAX,
+            high_side: true,
+        };
+        let response = Response {
+            id: u16::MAX,
+            result: Ok(Reply::Config(Config {
+                samples_per_sensor: u16::MAX,
+                oversample_bits: u8::MAX,
+                sensors: core::iter::repeat_n(sensor, MAX_CHANNELS).collect(),
+                filter: ReadingFilter::LowPass { alpha: u16::MAX },
+                curves: core::iter::repeat_n(curve, MAX_CHANNELS).collect(),
+                limits: core::iter::repeat_n(
+                    DutyLimits {
+                        min_permille: u16::MAX,
+                        max_permille: u16::MAX,
+                    },
+                    MAX_CHANNELS,
+                )
+                .collect(),
+            })),
+        };
+
+        let mut buf = [0u8; MAX_FRAME];
+        assert!(encode(&response, &mut buf).is_ok());
+    }
+
+    #[test]
+    fn largest_status_fits() {
+        let outputs = core::iter::repeat_n(
+            OutputStatus {
+                duty_permille: 1000,
+                rpm: Some(u32::MAX),
+            },
+            MAX_CHANNELS,
+        )
+        .collect();
+        let response = Response {
+            id: u16::MAX,
+            result: Ok(Reply::Status(Status {
+                fault: Some(Fault::Stall { output: 3 }),
+                outputs,
+                overruns: u32::MAX,
+                save_failures: u32::MAX,
+            })),
+        };
+
+        let mut buf = [0u8; MAX_FRAME];
+        assert!(encode(&response, &mut buf).is_ok());
+    }
+}
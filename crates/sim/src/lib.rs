@@ -0,0 +1,11 @@
+//! Host-side simulation of a custom loop under the controller's control pipeline
+//!
+//! The loop is modelled in floating point and simulated time, the controller side is `controller_lib`'s `Pipeline`
+//! exactly as the firmware runs it, over the `mock` HAL. Runs are deterministic for a given seed, so curve and PID
+//! changes can be compared for overshoot, oscillation and sensitivity to noise.
+
+pub mod plant;
+pub mod probe;
+pub mod scenario;
+
+pub use scenario::{run, Row, Scenario, Summary};
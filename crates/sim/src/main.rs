@@ -0,0 +1,187 @@
+//! Run a scenario and write its trace to stdout as CSV, with a summary on stderr
+//!
+//! See `USAGE` for the options, anything not given is left at `Scenario::default`.
+
+use controller_lib::dsp::{LowPass, Median, MovingAverage};
+use controller_lib::{CurvePoint, Degrees, Pid, PidGains, PiecewiseCurve};
+use sim::scenario::{duty_percent, PWM_TICKS};
+use sim::{run, Row, Scenario, Summary};
+use std::io::{BufWriter, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: sim [options] > trace.csv
+
+  --duration S          length of the run in seconds
+  --step S              time step of the loop model in seconds
+  --power PROFILE       constant:W, steps:T=W,T=W,... or square:LOW,HIGH,PERIOD
+  --ambient C           air temperature at the radiator
+  --noise COUNTS        standard deviation of ADC noise per sample
+  --samples N           samples averaged per reading
+  --oversample BITS     resolution gained by averaging, 0-4
+  --filter FILTER       none, average, median or lowpass:ALPHA (out of 65536)
+  --curve T=PCT,...     fan curve, degrees C to percent duty
+  --pid SP,KP,KI,KD     hold SP degrees C instead of following the curve, gains in percent duty
+  --seed N              seed of the ADC noise";
+
+/// Smoothing of converted readings, the same lengths as the firmware's
+enum FilterKind {
+    None,
+    MovingAverage,
+    Median,
+    LowPass(u16),
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number {value:?}"))
+}
+
+fn parse_degrees(value: &str) -> Result<Degrees, String> {
+    let celsius: f64 = parse_number(value)?;
+    #[allow(clippy::cast_possible_truncation)]
+    Ok(Degrees::from_millidegrees((celsius * 1000.0).round() as i32))
+}
+
+/// Percent of full duty as PWM ticks
+fn parse_duty(value: &str) -> Result<u16, String> {
+    let percent: u32 = parse_number(value)?;
+    if percent > 100 {
+        return Err(format!("duty over 100%: {value}"));
+    }
+    Ok(duty_percent(percent))
+}
+
+fn parse_curve(value: &str) -> Result<PiecewiseCurve, String> {
+    let points = value
+        .split(',')
+        .map(|point| {
+            let (temp, duty) = point
+                .split_once('=')
+                .ok_or_else(|| format!("expected T=PCT, got {point:?}"))?;
+            Ok(CurvePoint::new(parse_degrees(temp)?, parse_duty(duty)?))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    PiecewiseCurve::new(&points).map_err(|e| format!("invalid curve: {e:?}"))
+}
+
+/// PID gains are given in percent duty, as they are easier to reason about than PWM ticks
+fn parse_pid(value: &str, curve: &PiecewiseCurve) -> Result<Pid, String> {
+    let [setpoint, kp, ki, kd] = value.split(',').collect::<Vec<_>>()[..] else {
+        return Err(format!("expected SP,KP,KI,KD, got {value:?}"));
+    };
+    let ticks = |percent: &str| -> Result<f64, String> {
+        Ok(parse_number::<f64>(percent)? * f64::from(PWM_TICKS) / 100.0)
+    };
+    let min_duty = curve.points().first().map_or(0, |point| point.duty);
+    Ok(Pid::new(
+        parse_degrees(setpoint)?,
+        PidGains::new(ticks(kp)?, ticks(ki)?, ticks(kd)?),
+        min_duty,
+        curve.max_duty(),
+    )
+    .with_derivative_filter(2_000_000))
+}
+
+fn parse_filter(value: &str) -> Result<FilterKind, String> {
+    match value.split_once(':') {
+        None if value == "none" => Ok(FilterKind::None),
+        None if value == "average" => Ok(FilterKind::MovingAverage),
+        None if value == "median" => Ok(FilterKind::Median),
+        Some(("lowpass", alpha)) => Ok(FilterKind::LowPass(parse_number(alpha)?)),
+        _ => Err(format!("unknown filter {value:?}")),
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<(Scenario, FilterKind), String> {
+    let mut scenario = Scenario::default();
+    let mut filter = FilterKind::None;
+    let mut pid = None;
+
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        match flag.as_str() {
+            "--duration" => scenario.duration_s = parse_number(&value)?,
+            "--step" => scenario.step_s = parse_number(&value)?,
+            "--power" => {
+                scenario.power = value
+                    .parse()
+                    .map_err(|_| format!("invalid power profile {value:?}"))?;
+            }
+            "--ambient" => scenario.plant.ambient_c = parse_number(&value)?,
+            "--noise" => scenario.probe.noise_counts = parse_number(&value)?,
+            "--samples" => scenario.samples_per_reading = parse_number(&value)?,
+            "--oversample" => scenario.oversample_bits = parse_number(&value)?,
+            "--filter" => filter = parse_filter(&value)?,
+            "--curve" => scenario.curve = parse_curve(&value)?,
+            // Applied last, its duty limits come from the curve
+            "--pid" => pid = Some(value),
+            "--seed" => scenario.seed = parse_number(&value)?,
+            _ => return Err(format!("unknown option {flag}")),
+        }
+    }
+
+    if !(scenario.duration_s > 0.0 && scenario.step_s > 0.0) {
+        return Err("duration and step must be positive".into());
+    }
+    if scenario.samples_per_reading == 0 || scenario.oversample_bits > 4 {
+        return Err("need at least one sample a reading, and at most 4 oversample bits".into());
+    }
+    if let Some(pid) = pid {
+        scenario.pid = Some(parse_pid(&pid, &scenario.curve)?);
+    }
+    Ok((scenario, filter))
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let (scenario, filter) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let mut failed = writeln!(out, "{}", Row::CSV_HEADER).err();
+    let emit = |row: &Row| {
+        if failed.is_none() {
+            failed = writeln!(out, "{}", row.to_csv()).err();
+        }
+    };
+    let summary = match filter {
+        FilterKind::None => run(&scenario, MovingAverage::<Degrees, 1>::new(), emit),
+        FilterKind::MovingAverage => run(&scenario, MovingAverage::<Degrees, 8>::new(), emit),
+        FilterKind::Median => run(&scenario, Median::<Degrees, 5>::new(), emit),
+        FilterKind::LowPass(alpha) => run(&scenario, LowPass::new(alpha), emit),
+    };
+    if let Some(e) = failed.or_else(|| out.flush().err()) {
+        eprintln!("failed to write trace: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    report(&summary);
+    ExitCode::SUCCESS
+}
+
+fn report(summary: &Summary) {
+    eprintln!("peak water       {:.2} C", summary.peak_water_c);
+    eprintln!("settled water    {:.2} C", summary.settled_water_c);
+    eprintln!("overshoot        {:.2} C", summary.overshoot_c);
+    eprintln!("duty stddev      {:.2} %", summary.duty_stddev * 100.0);
+    eprintln!("duty reversals   {}", summary.duty_reversals);
+    eprintln!("reading error    {:.3} C", summary.reading_error_c);
+    if let Some(fault) = summary.fault {
+        eprintln!("fault            {fault:?}");
+    }
+}
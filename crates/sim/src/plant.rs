@@ -0,0 +1,250 @@
+//! Thermal model of the loop: a heat source dumping into the water, and a radiator cooled by a fan taking it out
+//!
+//! The water is lumped into a single thermal mass, blocks and radiator included, which is close enough at the time
+//! scales a fan controller cares about.
+
+use std::str::FromStr;
+
+/// Heat put into the loop over time
+#[derive(PartialEq, Clone, Debug)]
+pub enum PowerProfile {
+    /// Watts
+    Constant(f64),
+    /// Start time in seconds and the watts from then on, in ascending time. Zero before the first step.
+    Steps(Vec<(f64, f64)>),
+    /// Alternates between `low` and `high` watts every half period, starting low
+    Square { low: f64, high: f64, period_s: f64 },
+}
+
+impl PowerProfile {
+    /// Power at `time_s` into the run
+    #[must_use]
+    pub fn watts(&self, time_s: f64) -> f64 {
+        match self {
+            Self::Constant(watts) => *watts,
+            Self::Steps(steps) => steps
+                .iter()
+                .take_while(|(start, _)| *start <= time_s)
+                .last()
+                .map_or(0.0, |(_, watts)| *watts),
+            Self::Square {
+                low,
+                high,
+                period_s,
+            } => {
+                if (time_s % period_s) < period_s / 2.0 {
+                    *low
+                } else {
+                    *high
+                }
+            }
+        }
+    }
+}
+
+/// Reasons a power profile could not be parsed
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseProfileError;
+
+impl FromStr for PowerProfile {
+    type Err = ParseProfileError;
+
+    /// Parse one of `constant:W`, `steps:T=W,T=W,...` or `square:LOW,HIGH,PERIOD`, times in seconds
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |s: &str| s.trim().parse::<f64>().map_err(|_| ParseProfileError);
+        let (kind, args) = s.split_once(':').ok_or(ParseProfileError)?;
+        match kind {
+            "constant" => Ok(Self::Constant(number(args)?)),
+            "steps" => {
+                let steps = args
+                    .split(',')
+                    .map(|step| {
+                        let (start, watts) = step.split_once('=').ok_or(ParseProfileError)?;
+                        Ok((number(start)?, number(watts)?))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if steps.windows(2).any(|w| w[0].0 >= w[1].0) {
+                    return Err(ParseProfileError);
+                }
+                Ok(Self::Steps(steps))
+            }
+            "square" => match *args.split(',').collect::<Vec<_>>() {
+                [low, high, period] if number(period)? > 0.0 => Ok(Self::Square {
+                    low: number(low)?,
+                    high: number(high)?,
+                    period_s: number(period)?,
+                }),
+                _ => Err(ParseProfileError),
+            },
+            _ => Err(ParseProfileError),
+        }
+    }
+}
+
+/// A PWM fan, or several on the same header
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Fan {
+    /// Speed at 100% duty
+    pub max_rpm: f64,
+    /// Duty fraction below which the fan stops, where it starts up again too
+    pub stop_duty: f64,
+    /// Time constant of spinning up and down, seconds
+    pub tau_s: f64,
+    /// Tach pulses per revolution
+    pub pulses_per_rev: u8,
+}
+
+impl Fan {
+    /// Speed the fan settles at for a duty fraction
+    #[must_use]
+    pub fn target_rpm(&self, duty: f64) -> f64 {
+        if duty < self.stop_duty {
+            0.0
+        } else {
+            self.max_rpm * duty.min(1.0)
+        }
+    }
+}
+
+/// A radiator, dissipating in proportion to the water's temperature above ambient
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Radiator {
+    /// Watts per kelvin with the fans stopped
+    pub passive_w_per_k: f64,
+    /// Watts per kelvin the fans add at full speed, airflow's returns diminish so this scales sub-linearly with RPM
+    pub fan_w_per_k: f64,
+}
+
+/// Physical parameters of the loop
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct LoopConfig {
+    /// Air temperature at the radiator intake, degrees C
+    pub ambient_c: f64,
+    /// Water, blocks and radiator, joules per kelvin
+    pub heat_capacity_j_per_k: f64,
+    pub radiator: Radiator,
+    pub fan: Fan,
+}
+
+impl Default for LoopConfig {
+    /// A single 360mm radiator with a litre or so of water
+    fn default() -> Self {
+        Self {
+            ambient_c: 25.0,
+            heat_capacity_j_per_k: 5_000.0,
+            radiator: Radiator {
+                passive_w_per_k: 4.0,
+                fan_w_per_k: 26.0,
+            },
+            fan: Fan {
+                max_rpm: 2_000.0,
+                stop_duty: 0.15,
+                tau_s: 1.5,
+                pulses_per_rev: 2,
+            },
+        }
+    }
+}
+
+/// State of the loop, advanced in fixed steps
+pub struct Plant {
+    pub config: LoopConfig,
+    /// Degrees C
+    pub water_c: f64,
+    pub rpm: f64,
+    /// Free running tach pulse count, wraps like the controller's counter
+    pub pulses: u32,
+    /// Pulses not yet counted in `pulses`
+    pulse_fraction: f64,
+}
+
+impl Plant {
+    /// Start out with the loop soaked at ambient and the fan stopped
+    #[must_use]
+    pub const fn new(config: LoopConfig) -> Self {
+        Self {
+            water_c: config.ambient_c,
+            rpm: 0.0,
+            pulses: 0,
+            pulse_fraction: 0.0,
+            config,
+        }
+    }
+
+    /// Watts the radiator sheds at the current water temperature and fan speed
+    #[must_use]
+    pub fn dissipation(&self) -> f64 {
+        let radiator = self.config.radiator;
+        let airflow = (self.rpm / self.config.fan.max_rpm).clamp(0.0, 1.0);
+        let conductance = radiator.passive_w_per_k + radiator.fan_w_per_k * airflow.powf(0.8);
+        conductance * (self.water_c - self.config.ambient_c)
+    }
+
+    /// Advance by `dt_s` seconds with `power_w` going in and the fan driven at a `duty` fraction
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn step(&mut self, dt_s: f64, power_w: f64, duty: f64) {
+        let fan = self.config.fan;
+        let net = power_w - self.dissipation();
+        self.water_c += net * dt_s / self.config.heat_capacity_j_per_k;
+        self.rpm += (fan.target_rpm(duty) - self.rpm) * (dt_s / fan.tau_s).min(1.0);
+
+        self.pulse_fraction += self.rpm / 60.0 * f64::from(fan.pulses_per_rev) * dt_s;
+        let whole = self.pulse_fraction.floor();
+        self.pulses = self.pulses.wrapping_add(whole as u32);
+        self.pulse_fraction -= whole;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_profiles() {
+        assert_eq!("constant:150".parse(), Ok(PowerProfile::Constant(150.0)));
+        assert_eq!(
+            "steps:0=50, 60=300".parse(),
+            Ok(PowerProfile::Steps(vec![(0.0, 50.0), (60.0, 300.0)]))
+        );
+        assert_eq!(
+            "square:50,300,120".parse(),
+            Ok(PowerProfile::Square {
+                low: 50.0,
+                high: 300.0,
+                period_s: 120.0
+            })
+        );
+        assert!("steps:60=50,0=300".parse::<PowerProfile>().is_err());
+        assert!("square:50,300".parse::<PowerProfile>().is_err());
+        assert!("sine:50".parse::<PowerProfile>().is_err());
+
+        let steps: PowerProfile = "steps:10=50,60=300".parse().unwrap();
+        assert!(steps.watts(5.0).abs() < f64::EPSILON);
+        assert!((steps.watts(30.0) - 50.0).abs() < f64::EPSILON);
+        assert!((steps.watts(60.0) - 300.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn settles_where_dissipation_matches_power() {
+        let config = LoopConfig::default();
+        let mut plant = Plant::new(config);
+        for _ in 0..200_000 {
+            plant.step(0.01, 150.0, 1.0);
+        }
+        // 150 W over 30 W/K at full speed
+        assert!((plant.water_c - (config.ambient_c + 5.0)).abs() < 0.01);
+        assert!((plant.rpm - config.fan.max_rpm).abs() < 1.0);
+        // 2 pulses a revolution over 2000 seconds
+        let expected = config.fan.max_rpm / 60.0 * 2.0 * 2_000.0;
+        assert!((f64::from(plant.pulses) - expected).abs() / expected < 0.01);
+    }
+
+    #[test]
+    fn fan_stops_below_its_minimum_duty() {
+        let mut plant = Plant::new(LoopConfig::default());
+        for _ in 0..1_000 {
+            plant.step(0.01, 0.0, 0.1);
+        }
+        assert_eq!(plant.pulses, 0);
+    }
+}
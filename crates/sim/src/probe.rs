@@ -0,0 +1,186 @@
+//! Water temperature probe: an NTC thermistor in a divider, read through a noisy 12 bit ADC
+//!
+//! Readings are oversampled and decimated the same way the firmware's sampler does, see `dsp::Decimator`.
+
+use controller_lib::dsp::Decimator;
+use controller_lib::{RawReading, TemperatureSource};
+
+/// Resolution of a single ADC sample
+pub const ADC_BITS: u8 = 12;
+const ADC_FULL_SCALE: u32 = 1 << ADC_BITS;
+const ZERO_C_KELVIN: f64 = 273.15;
+
+/// An NTC thermistor on the low side of a divider, and the ADC reading it
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct ProbeConfig {
+    /// Resistance at `t0_c`, ohms
+    pub r0: f64,
+    pub t0_c: f64,
+    pub beta: f64,
+    /// The divider's fixed resistor, ohms
+    pub series_ohms: f64,
+    /// Time constant of the probe following the water, seconds
+    pub tau_s: f64,
+    /// Standard deviation of the noise on each ADC sample, in counts
+    pub noise_counts: f64,
+}
+
+impl Default for ProbeConfig {
+    /// The common 10k B3950 G1/4" plug, in a divider with 10k
+    fn default() -> Self {
+        Self {
+            r0: 10_000.0,
+            t0_c: 25.0,
+            beta: 3950.0,
+            series_ohms: 10_000.0,
+            tau_s: 2.0,
+            noise_counts: 2.0,
+        }
+    }
+}
+
+/// xorshift64*, seeded so runs are repeatable
+struct Noise(u64);
+
+impl Noise {
+    const fn new(seed: u64) -> Self {
+        // Zero is the one state xorshift never leaves
+        Self(if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        })
+    }
+
+    /// Uniform in (0, 1]
+    #[allow(clippy::cast_precision_loss)]
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let bits = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        (bits + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, by Box-Muller
+    fn gaussian(&mut self) -> f64 {
+        let (u1, u2) = (self.uniform(), self.uniform());
+        (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+    }
+}
+
+/// The probe as of the last call to `track`, read as a `TemperatureSource` by the pipeline
+pub struct Probe {
+    config: ProbeConfig,
+    /// Degrees C the thermistor itself is at, lagging the water
+    pub temp_c: f64,
+    samples_per_reading: u16,
+    decimator: Decimator,
+    noise: Noise,
+}
+
+impl Probe {
+    /// Create a probe already at `temp_c`, read `samples_per_reading` samples at a time
+    #[must_use]
+    pub const fn new(
+        config: ProbeConfig,
+        temp_c: f64,
+        samples_per_reading: u16,
+        oversample_bits: u8,
+        seed: u64,
+    ) -> Self {
+        Self {
+            config,
+            temp_c,
+            samples_per_reading,
+            decimator: Decimator::new(oversample_bits),
+            noise: Noise::new(seed),
+        }
+    }
+
+    /// Follow the water for `dt_s` seconds
+    pub fn track(&mut self, water_c: f64, dt_s: f64) {
+        self.temp_c += (water_c - self.temp_c) * (dt_s / self.config.tau_s).min(1.0);
+    }
+
+    /// Thermistor resistance at its current temperature, ohms
+    #[must_use]
+    pub fn resistance(&self) -> f64 {
+        let config = &self.config;
+        let inverse_t = 1.0 / (self.temp_c + ZERO_C_KELVIN) - 1.0 / (config.t0_c + ZERO_C_KELVIN);
+        config.r0 * (config.beta * inverse_t).exp()
+    }
+
+    /// One noisy ADC sample of the divider
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn sample(&mut self) -> u16 {
+        let r = self.resistance();
+        let ideal = f64::from(ADC_FULL_SCALE) * r / (r + self.config.series_ohms);
+        let noisy = ideal + self.noise.gaussian() * self.config.noise_counts;
+        noisy.round().clamp(0.0, f64::from(ADC_FULL_SCALE - 1)) as u16
+    }
+}
+
+impl TemperatureSource for Probe {
+    fn read(&mut self) -> RawReading {
+        let samples: Vec<u16> = (0..self.samples_per_reading)
+            .map(|_| self.sample())
+            .collect();
+        let (sum, samples) = self.decimator.accumulate(samples);
+        RawReading {
+            sum,
+            samples,
+            full_scale: ADC_FULL_SCALE << self.decimator.extra_bits(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_lib::thermistor::Model;
+    use controller_lib::{Degrees, Thermistor};
+
+    #[test]
+    fn reads_back_through_the_firmware_conversion() {
+        let config = ProbeConfig {
+            noise_counts: 0.0,
+            ..ProbeConfig::default()
+        };
+        let thermistor =
+            Thermistor::new(Model::ntc_beta(10_000, Degrees::from_int(25), 3950), 10_000)
+                .with_adc_bits(ADC_BITS + 2);
+
+        for temp in [20, 25, 40, 60] {
+            let mut probe = Probe::new(config, f64::from(temp), 32, 2, 1);
+            let raw = probe.read();
+            assert_eq!(raw.full_scale, 1 << 14);
+            let degrees = thermistor.degrees(raw.sum / raw.samples).unwrap();
+            // Within the 12 bit quantization, a few hundredths of a degree around here
+            assert!(
+                (degrees.to_millidegrees() - temp * 1000).abs() < 100,
+                "{temp}: {degrees:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn noise_averages_out() {
+        let mut probe = Probe::new(ProbeConfig::default(), 25.0, 1, 0, 7);
+        let samples: Vec<f64> = (0..10_000).map(|_| f64::from(probe.sample())).collect();
+        let mean = samples.iter().sum::<f64>() / 10_000.0;
+        let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / 10_000.0;
+        assert!((mean - 2048.0).abs() < 0.1);
+        assert!((variance.sqrt() - 2.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn lags_the_water() {
+        let mut probe = Probe::new(ProbeConfig::default(), 25.0, 1, 0, 1);
+        for _ in 0..200 {
+            probe.track(35.0, 0.01);
+        }
+        // One time constant
+        assert!((probe.temp_c - (35.0 - 10.0 / std::f64::consts::E)).abs() < 0.05);
+    }
+}
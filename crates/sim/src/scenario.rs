@@ -0,0 +1,377 @@
+//! A run of the simulated loop under the controller's control pipeline, one sensor driving one fan header
+
+use crate::plant::{LoopConfig, Plant, PowerProfile};
+use crate::probe::{Probe, ProbeConfig};
+use controller_core::{config, control_loop::OUTPUTS};
+use controller_lib::dsp::Filter;
+use controller_lib::mock::{MockClock, MockPwm, MockTach};
+use controller_lib::{
+    Degrees, Fault, OutputChain, OutputStage, Pid, PiecewiseCurve, Pipeline, SensorChain,
+    SensorHealth, SensorStatus, StallDetector, Tachometer,
+};
+use controller_protocol::SensorModel;
+use std::cell::Cell;
+
+/// PWM counter ticks at 100% duty, the same as the firmware's
+pub const PWM_TICKS: u16 = 5000;
+/// ADC samples per second, shared between all sensors
+pub const ADC_SPS: f64 = 1024.0;
+
+// The firmware's health and stall limits
+const SENSOR_MIN: Degrees = Degrees::from_int(-10);
+const SENSOR_MAX: Degrees = Degrees::from_int(100);
+const SENSOR_STUCK_TIMEOUT_US: u64 = 10_000_000;
+const TACH_WINDOW_US: u64 = 1_000_000;
+const STALL_DUTY: u16 = duty_percent(30);
+const STALL_RPM: u32 = 200;
+const STALL_TIMEOUT_US: u64 = 5_000_000;
+
+/// Duty in PWM ticks for a percentage
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub const fn duty_percent(percent: u32) -> u16 {
+    (PWM_TICKS as u32 * percent / 100) as u16
+}
+
+/// Everything about a run, the loop and how the controller is set up to cool it
+pub struct Scenario {
+    pub duration_s: f64,
+    /// Time step of the loop model, the controller updates once per reading regardless
+    pub step_s: f64,
+    pub power: PowerProfile,
+    pub plant: LoopConfig,
+    pub probe: ProbeConfig,
+    /// Seeds the probe's noise, the same seed gives the same run
+    pub seed: u64,
+    /// How the controller converts readings, which need not match `probe` exactly. Must be a model the firmware
+    /// accepts, see `controller_core::config::conversion`.
+    pub sensor: SensorModel,
+    pub samples_per_reading: u16,
+    pub oversample_bits: u8,
+    /// Duties in PWM ticks
+    pub curve: PiecewiseCurve,
+    pub stage: OutputStage,
+    pub pid: Option<Pid>,
+}
+
+impl Default for Scenario {
+    /// Ten minutes of a load coming and going on the default loop, under the firmware's default sensor, sampling,
+    /// curve and output stage
+    fn default() -> Self {
+        let firmware = config::defaults();
+        Self {
+            duration_s: 600.0,
+            step_s: 0.01,
+            power: PowerProfile::Steps(vec![(0.0, 50.0), (60.0, 300.0), (360.0, 50.0)]),
+            plant: LoopConfig::default(),
+            probe: ProbeConfig::default(),
+            seed: 1,
+            sensor: firmware.sensors[0],
+            samples_per_reading: firmware.samples_per_sensor,
+            oversample_bits: firmware.oversample_bits,
+            curve: config::curve_from_wire(&firmware.curves[0]).expect("valid curve"),
+            stage: OUTPUTS[0].stage,
+            pid: None,
+        }
+    }
+}
+
+/// State of the loop and controller after one controller update
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Row {
+    pub time_s: f64,
+    pub power_w: f64,
+    pub water_c: f64,
+    /// What the thermistor is actually at
+    pub probe_c: f64,
+    /// What the controller made of it, None while the sensor is unhealthy
+    pub reading_c: Option<f64>,
+    pub sensor: Option<SensorStatus>,
+    /// Fraction of full duty
+    pub duty: f64,
+    pub rpm: f64,
+    /// As measured by the controller
+    pub tach_rpm: Option<u32>,
+    pub dissipation_w: f64,
+}
+
+impl Row {
+    pub const CSV_HEADER: &'static str =
+        "time_s,power_w,water_c,probe_c,reading_c,sensor,duty,rpm,tach_rpm,dissipation_w";
+
+    /// The row as a line of CSV, without the line ending. Missing values are left empty.
+    #[must_use]
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{:.3},{:.1},{:.3},{:.3},{},{},{:.4},{:.0},{},{:.1}",
+            self.time_s,
+            self.power_w,
+            self.water_c,
+            self.probe_c,
+            self.reading_c.map_or(String::new(), |c| format!("{c:.3}")),
+            self.sensor.map_or(String::new(), |s| format!("{s:?}")),
+            self.duty,
+            self.rpm,
+            self.tach_rpm.map_or(String::new(), |rpm| rpm.to_string()),
+            self.dissipation_w,
+        )
+    }
+}
+
+/// Figures of merit of a run
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Summary {
+    pub peak_water_c: f64,
+    /// Mean water temperature over the last quarter of the run
+    pub settled_water_c: f64,
+    /// Peak water temperature above the PID's setpoint, or above `settled_water_c` when following a curve
+    pub overshoot_c: f64,
+    /// Standard deviation of the duty fraction over the last quarter of the run
+    pub duty_stddev: f64,
+    /// Times the duty changed direction over the last quarter of the run
+    pub duty_reversals: usize,
+    /// Standard deviation of readings from the probe's actual temperature, noise and filter lag together
+    pub reading_error_c: f64,
+    pub fault: Option<Fault>,
+}
+
+impl Summary {
+    fn new(rows: &[Row], setpoint_c: Option<f64>, fault: Option<Fault>) -> Self {
+        let last_quarter = &rows[rows.len() * 3 / 4..];
+        let peak_water_c = rows.iter().map(|row| row.water_c).fold(f64::MIN, f64::max);
+        let settled_water_c = mean(last_quarter.iter().map(|row| row.water_c));
+
+        let duties = last_quarter.iter().map(|row| row.duty);
+        let duty_mean = mean(duties.clone());
+        let duty_stddev = mean(duties.map(|duty| (duty - duty_mean).powi(2))).sqrt();
+
+        let steps = last_quarter
+            .windows(2)
+            .map(|w| w[1].duty - w[0].duty)
+            .filter(|step| *step != 0.0)
+            .collect::<Vec<_>>();
+        let duty_reversals = steps.windows(2).filter(|w| w[0] * w[1] < 0.0).count();
+
+        let errors = rows
+            .iter()
+            .filter_map(|row| Some(row.reading_c? - row.probe_c))
+            .collect::<Vec<_>>();
+        let error_mean = mean(errors.iter().copied());
+        let reading_error_c = mean(errors.iter().map(|e| (e - error_mean).powi(2))).sqrt();
+
+        Self {
+            peak_water_c,
+            settled_water_c,
+            overshoot_c: peak_water_c - setpoint_c.unwrap_or(settled_water_c),
+            duty_stddev,
+            duty_reversals,
+            reading_error_c,
+            fault,
+        }
+    }
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        0.0
+    } else {
+        sum / f64::from(count)
+    }
+}
+
+fn celsius(degrees: Degrees) -> f64 {
+    f64::from(degrees.to_millidegrees()) / 1000.0
+}
+
+/// Run a scenario with `filter` smoothing the converted readings, passing each row to `emit` as it is produced
+///
+/// # Panics
+/// * If `step_s` or `duration_s` are not positive
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+pub fn run<F: Filter<Sample = Degrees>>(
+    scenario: &Scenario,
+    filter: F,
+    mut emit: impl FnMut(&Row),
+) -> Summary {
+    assert!(scenario.step_s > 0.0 && scenario.duration_s > 0.0);
+
+    let duty = Cell::new(0);
+    let pulses = Cell::new(0);
+    let now_us = Cell::new(0);
+
+    let sensor = SensorChain::new(
+        config::conversion(&scenario.sensor, scenario.oversample_bits)
+            .expect("a sensor model the firmware accepts"),
+        filter,
+        SensorHealth::new(SENSOR_MIN, SENSOR_MAX, SENSOR_STUCK_TIMEOUT_US),
+    );
+    let mut output = OutputChain::new(
+        MockPwm {
+            duty: &duty,
+            max_duty: PWM_TICKS,
+        },
+        0,
+        scenario.curve,
+        scenario.stage,
+        StallDetector::new(STALL_DUTY, STALL_RPM, STALL_TIMEOUT_US),
+    )
+    .with_tach(
+        MockTach(&pulses),
+        Tachometer::new(scenario.plant.fan.pulses_per_rev, TACH_WINDOW_US),
+    );
+    if let Some(pid) = scenario.pid {
+        output = output.with_pid(pid);
+    }
+    let mut pipeline = Pipeline::new([sensor], &[], [output], MockClock(&now_us))
+        .expect("the output follows the only sensor");
+
+    let mut plant = Plant::new(scenario.plant);
+    let mut probe = [Probe::new(
+        scenario.probe,
+        plant.water_c,
+        scenario.samples_per_reading,
+        scenario.oversample_bits,
+        scenario.seed,
+    )];
+
+    let reading_period_s = f64::from(scenario.samples_per_reading) / ADC_SPS;
+    let mut next_update_s = 0.0;
+    let mut rows = Vec::new();
+    for step in 0..(scenario.duration_s / scenario.step_s).ceil() as u64 {
+        let time_s = step as f64 * scenario.step_s;
+        let power_w = scenario.power.watts(time_s);
+
+        if time_s >= next_update_s {
+            next_update_s += reading_period_s;
+            now_us.set((time_s * 1e6) as u64);
+            pulses.set(plant.pulses);
+            let status = pipeline.update(&mut probe);
+
+            let row = Row {
+                time_s,
+                power_w,
+                water_c: plant.water_c,
+                probe_c: probe[0].temp_c,
+                reading_c: status.readings[0].map(celsius),
+                sensor: status.sensors[0],
+                duty: f64::from(duty.get()) / f64::from(PWM_TICKS),
+                rpm: plant.rpm,
+                tach_rpm: status.rpms[0],
+                dissipation_w: plant.dissipation(),
+            };
+            emit(&row);
+            rows.push(row);
+        }
+
+        plant.step(
+            scenario.step_s,
+            power_w,
+            f64::from(duty.get()) / f64::from(PWM_TICKS),
+        );
+        probe[0].track(plant.water_c, scenario.step_s);
+    }
+
+    Summary::new(
+        &rows,
+        scenario.pid.map(|pid| celsius(pid.setpoint())),
+        pipeline.status().fault,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_lib::dsp::{LowPass, MovingAverage};
+    use controller_lib::PidGains;
+
+    /// The Beta model of the default probe, so readings only differ from it by noise
+    fn matching_probe() -> SensorModel {
+        SensorModel::Beta {
+            r0_ohms: 10_000,
+            t0_millidegrees: 25_000,
+            beta: 3950,
+            ptc: false,
+            series_ohms: 10_000,
+            high_side: false,
+        }
+    }
+
+    #[test]
+    fn default_curve_holds_the_load() {
+        let summary = run(
+            &Scenario::default(),
+            MovingAverage::<Degrees, 1>::new(),
+            |_| {},
+        );
+        // 300 W needs around 24 K over ambient with the fans flat out, the curve's last point
+        assert!(summary.peak_water_c < 45.0, "{summary:?}");
+        // Back at idle by the end
+        assert!(summary.settled_water_c < 35.0, "{summary:?}");
+        assert_eq!(summary.fault, None);
+    }
+
+    #[test]
+    fn pid_settles_at_setpoint() {
+        let scenario = Scenario {
+            power: PowerProfile::Steps(vec![(0.0, 50.0), (60.0, 200.0)]),
+            pid: Some(
+                Pid::new(
+                    Degrees::from_int(32),
+                    PidGains::new(2_000.0, 40.0, 0.0),
+                    duty_percent(20),
+                    PWM_TICKS,
+                )
+                .with_derivative_filter(2_000_000),
+            ),
+            sensor: matching_probe(),
+            ..Scenario::default()
+        };
+        let summary = run(&scenario, LowPass::new(8_192), |_| {});
+        assert!((summary.settled_water_c - 32.0).abs() < 0.3, "{summary:?}");
+        assert!(summary.overshoot_c < 2.0, "{summary:?}");
+        assert!(summary.duty_stddev < 0.05, "{summary:?}");
+    }
+
+    #[test]
+    fn filtering_reduces_reading_noise() {
+        let scenario = Scenario {
+            power: PowerProfile::Constant(100.0),
+            duration_s: 120.0,
+            probe: ProbeConfig {
+                noise_counts: 8.0,
+                ..ProbeConfig::default()
+            },
+            sensor: matching_probe(),
+            ..Scenario::default()
+        };
+        let raw = run(&scenario, MovingAverage::<Degrees, 1>::new(), |_| {});
+        let smoothed = run(&scenario, MovingAverage::<Degrees, 32>::new(), |_| {});
+        assert!(
+            smoothed.reading_error_c < raw.reading_error_c / 2.0,
+            "{raw:?} {smoothed:?}"
+        );
+    }
+
+    #[test]
+    fn emits_a_row_per_reading() {
+        let scenario = Scenario {
+            duration_s: 10.0,
+            ..Scenario::default()
+        };
+        let mut rows = 0;
+        run(&scenario, MovingAverage::<Degrees, 1>::new(), |row| {
+            assert_eq!(
+                row.to_csv().split(',').count(),
+                Row::CSV_HEADER.split(',').count()
+            );
+            rows += 1;
+        });
+        // 32 samples at 1024 sps
+        assert_eq!(rows, 320);
+    }
+}
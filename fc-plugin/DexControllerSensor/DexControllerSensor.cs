@@ -12,6 +12,7 @@ namespace FanControl.DexControllerSensor
         WaterSensor? sensor_temp;
         private readonly IPluginLogger logger;
         private readonly IPluginDialog dialog;
+        ushort request_id;
 
 
         public void Close()
@@ -31,7 +32,7 @@ namespace FanControl.DexControllerSensor
             try
             {
                 port = new SerialPort("COM14");
-                port.Open();
+                OpenPort();
             }
             catch (IOException ex)
             {
@@ -40,6 +41,33 @@ namespace FanControl.DexControllerSensor
 
         }
 
+        // Open the port and check the controller speaks the same protocol version, closing it again if not so that
+        // the next update retries
+        void OpenPort()
+        {
+            if (port == null)
+            {
+                return;
+            }
+            port.Open();
+            try
+            {
+                Protocol.Hello(port, NextRequestId());
+            }
+            catch (InvalidDataException ex)
+            {
+                logger.Log($"Handshake with the controller failed: {ex.Message}");
+                port.Close();
+            }
+        }
+
+        // Request ids are echoed back by the controller, skip 0 as that marks responses to malformed frames
+        ushort NextRequestId()
+        {
+            request_id = (ushort)(request_id % 127 + 1);
+            return request_id;
+        }
+
         public void Load(IPluginSensorsContainer container)
         {
             sensor_temp = new() { };
@@ -50,15 +78,20 @@ namespace FanControl.DexControllerSensor
 
         public void Update()
         {
-            string? msg = null;
+            float? msg = null;
             try
             {
-                // "t" requests a new temperature reading. May eventually protobuf or json this if more sensors materialize
-                port?.WriteLine("t");
-                msg = port?.ReadLine();
+                if (port != null)
+                {
+                    msg = Protocol.ReadFirstSensor(port, NextRequestId());
+                }
             }
-            // If the serial port doesn't work, attempt to recover it
             // Fail safe behavior lower down is triggered by the message here not being changed from null
+            catch (InvalidDataException ex)
+            {
+                logger.Log("Unexpected response from the controller: " + ex.Message);
+            }
+            // If the serial port doesn't work, attempt to recover it
             catch (SystemException ex)
             {
                 logger.Log("Exception while reading serial port: " + ex.Message);
@@ -67,7 +100,7 @@ namespace FanControl.DexControllerSensor
 
                     try
                     {
-                        port?.Open();
+                        OpenPort();
                     }
                     catch (IOException eex)
                     {
@@ -76,32 +109,199 @@ namespace FanControl.DexControllerSensor
                 }
             }
 
-            try
+            if (sensor_temp != null && msg != null)
+            {
+                // The first sensor is the loop sensor
+                sensor_temp.Value = msg;
+            }
+            else if (sensor_temp != null)
+            {
+                // Fail safe in case of null message, a response that did not parse, or a sensor the controller
+                // reports as unhealthy
+                sensor_temp.Value = 50;
+            }
+        }
+    }
+
+    /// Just enough of the controller_protocol crate's wire format (postcard messages in COBS frames) to read sensors
+    public static class Protocol
+    {
+        /// PROTOCOL_VERSION of the controller_protocol crate this was written against
+        const ushort PROTOCOL_VERSION = 8;
+
+        const byte COMMAND_HELLO = 0;
+        const byte COMMAND_READ_SENSORS = 1;
+        const byte RESULT_OK = 0;
+        const byte RESULT_ERR = 1;
+        const byte REPLY_HELLO = 0;
+        const byte REPLY_SENSORS = 1;
+        const byte ERROR_VERSION_MISMATCH = 0;
+        const byte SENSOR_STATUS_OK = 0;
+
+        /// Version handshake, throws InvalidDataException if the controller speaks another version
+        public static void Hello(SerialPort port, ushort id)
+        {
+            List<byte> request = new();
+            WriteVarint(request, id);
+            request.Add(COMMAND_HELLO);
+            WriteVarint(request, PROTOCOL_VERSION);
+
+            byte[] response = Exchange(port, request, id, out int pos);
+            byte result = Next(response, ref pos);
+            if (result == RESULT_ERR && Next(response, ref pos) == ERROR_VERSION_MISMATCH)
+            {
+                ulong supported = ReadVarint(response, ref pos);
+                throw new InvalidDataException(
+                    $"Controller speaks protocol version {supported}, not {PROTOCOL_VERSION}");
+            }
+            if (result != RESULT_OK || Next(response, ref pos) != REPLY_HELLO)
+            {
+                throw new InvalidDataException("Controller did not answer the handshake");
+            }
+        }
+
+        /// Read the first sensor in degrees C, null if the controller has no healthy reading for it
+        public static float? ReadFirstSensor(SerialPort port, ushort id)
+        {
+            List<byte> request = new();
+            WriteVarint(request, id);
+            request.Add(COMMAND_READ_SENSORS);
+
+            byte[] response = Exchange(port, request, id, out int pos);
+            if (Next(response, ref pos) != RESULT_OK || Next(response, ref pos) != REPLY_SENSORS)
+            {
+                throw new InvalidDataException("Controller did not return sensor readings");
+            }
+            if (ReadVarint(response, ref pos) == 0 || Next(response, ref pos) == 0)
+            {
+                return null;
+            }
+            ulong zigzag = ReadVarint(response, ref pos);
+            long millidegrees = (long)(zigzag >> 1) ^ -(long)(zigzag & 1);
+            if (Next(response, ref pos) != SENSOR_STATUS_OK)
             {
-                if (sensor_temp != null && msg != null)
+                return null;
+            }
+            return millidegrees / 1000.0f;
+        }
+
+        /// Send a request and wait for the response with the same id, `pos` is left just past the id
+        static byte[] Exchange(SerialPort port, List<byte> request, ushort id, out int pos)
+        {
+            byte[] frame = CobsEncode(request);
+            port.Write(frame, 0, frame.Length);
+
+            // Skip responses to earlier requests that timed out
+            while (true)
+            {
+                byte[] response = CobsDecode(ReadFrame(port));
+                pos = 0;
+                if (ReadVarint(response, ref pos) == id)
                 {
-                    sensor_temp.Value = Int32.Parse(msg);
-                    // fail safe in case of loose wire (results in negative reading)
-                    if (sensor_temp.Value < 0)
-                    {
-                        sensor_temp.Value = 50;
-                    }
+                    return response;
+                }
+            }
+        }
+
+        static List<byte> ReadFrame(SerialPort port)
+        {
+            List<byte> frame = new();
+            while (true)
+            {
+                byte b = (byte)port.ReadByte();
+                if (b == 0)
+                {
+                    return frame;
+                }
+                frame.Add(b);
+            }
+        }
+
+        static byte Next(byte[] buf, ref int pos)
+        {
+            if (pos >= buf.Length)
+            {
+                throw new InvalidDataException("Truncated response");
+            }
+            return buf[pos++];
+        }
+
+        static void WriteVarint(List<byte> buf, ulong value)
+        {
+            while (value >= 0x80)
+            {
+                buf.Add((byte)(value | 0x80));
+                value >>= 7;
+            }
+            buf.Add((byte)value);
+        }
+
+        static ulong ReadVarint(byte[] buf, ref int pos)
+        {
+            ulong value = 0;
+            for (int shift = 0; shift < 64; shift += 7)
+            {
+                byte b = Next(buf, ref pos);
+                value |= (ulong)(b & 0x7f) << shift;
+                if ((b & 0x80) == 0)
+                {
+                    return value;
+                }
+            }
+            throw new InvalidDataException("Varint too long");
+        }
+
+        /// Encode including the terminating 0
+        static byte[] CobsEncode(List<byte> data)
+        {
+            List<byte> output = new() { 0 };
+            int code_pos = 0;
+            byte code = 1;
+            foreach (byte b in data)
+            {
+                if (b == 0)
+                {
+                    output[code_pos] = code;
+                    code_pos = output.Count;
+                    output.Add(0);
+                    code = 1;
+                    continue;
                 }
-                else if (sensor_temp != null)
+                output.Add(b);
+                code++;
+                if (code == 0xff)
                 {
-                    // Fail safe in case of null message
-                    sensor_temp.Value = 50;
+                    output[code_pos] = code;
+                    code_pos = output.Count;
+                    output.Add(0);
+                    code = 1;
                 }
             }
-            catch (FormatException e)
+            output[code_pos] = code;
+            output.Add(0);
+            return output.ToArray();
+        }
+
+        /// Decode a frame without its terminating 0
+        static byte[] CobsDecode(List<byte> frame)
+        {
+            List<byte> output = new();
+            int pos = 0;
+            while (pos < frame.Count)
             {
-                logger.Log(e.Message);
-                if (sensor_temp != null)
+                byte code = frame[pos++];
+                if (code == 0 || pos + code - 1 > frame.Count)
+                {
+                    throw new InvalidDataException("Bad COBS frame");
+                }
+                output.AddRange(frame.GetRange(pos, code - 1));
+                pos += code - 1;
+                if (code < 0xff && pos < frame.Count)
                 {
-                    // Fail safe in case of parse exception
-                    sensor_temp.Value = 50;
+                    output.Add(0);
                 }
             }
+            return output.ToArray();
         }
     }
 